@@ -5,6 +5,7 @@
 
 fn main() -> shadow_rs::SdResult<()> {
     tonic_build::compile_protos("./protos/getresource.proto")?;
+    tonic_build::compile_protos("./protos/keyprovider.proto")?;
 
     shadow_rs::new()
 }
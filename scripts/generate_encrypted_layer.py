@@ -0,0 +1,147 @@
+#!/usr/bin/env python3
+#
+# Copyright (c) 2022 Intel Corporation
+#
+# SPDX-License-Identifier: Apache-2.0
+#
+# Generate the encrypted layer fixtures, a gzip layer holding "bin/busybox"
+# encrypted like ocicrypt does. The layer is AES-256-CTR encrypted and
+# HMAC-SHA256 authenticated, and its private options are wrapped:
+#   - test_data/encrypted_layer.json: in a JWE with RSA-OAEP and A256GCM, for
+#     the test public key test_data/public_key_for_tests.pem.
+#   - test_data/encrypted_layer_kbs.json: for the attestation-agent key
+#     provider, with A256GCM and a key the KBS holds as the "kbsResources" of
+#     the fixture, by key ID.
+#
+# Requires the python "cryptography" package.
+
+import base64
+import gzip
+import hashlib
+import hmac
+import io
+import json
+import os
+import tarfile
+
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+
+TEST_DATA = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "test_data")
+MEDIA_TYPE = "application/vnd.oci.image.layer.v1.tar+gzip+encrypted"
+KBS_KEY_ID = "kbs:///default/image_key/busybox"
+
+
+def b64(data):
+    return base64.b64encode(data).decode()
+
+
+def b64url(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def digest(data):
+    return "sha256:" + hashlib.sha256(data).hexdigest()
+
+
+def plain_layer():
+    out = io.BytesIO()
+    with tarfile.open(fileobj=out, mode="w", format=tarfile.GNU_FORMAT) as tar:
+        data = b"busybox"
+        info = tarfile.TarInfo("bin/busybox")
+        info.size = len(data)
+        info.mode = 0o644
+        tar.addfile(info, io.BytesIO(data))
+    layer_tar = out.getvalue()
+    return layer_tar, gzip.compress(layer_tar, mtime=0)
+
+
+def jwe(payload, public_key):
+    cek = os.urandom(32)
+    iv = os.urandom(12)
+    protected = b64url(json.dumps({"enc": "A256GCM"}).encode())
+    sealed = AESGCM(cek).encrypt(iv, payload, protected.encode())
+    encrypted_key = public_key.encrypt(
+        cek,
+        padding.OAEP(mgf=padding.MGF1(hashes.SHA1()), algorithm=hashes.SHA1(), label=None),
+    )
+    return json.dumps(
+        {
+            "protected": protected,
+            "header": {"alg": "RSA-OAEP"},
+            "encrypted_key": b64url(encrypted_key),
+            "iv": b64url(iv),
+            "ciphertext": b64url(sealed[:-16]),
+            "tag": b64url(sealed[-16:]),
+        }
+    ).encode()
+
+
+def attestation_agent(payload, key):
+    iv = os.urandom(12)
+    packet = {
+        "kid": KBS_KEY_ID,
+        "wrapped_data": b64(AESGCM(key).encrypt(iv, payload, None)),
+        "iv": b64(iv),
+        "wrap_type": "A256GCM",
+    }
+    return json.dumps(packet).encode()
+
+
+def encrypted_layer():
+    layer_tar, layer_gzip = plain_layer()
+    symkey = os.urandom(32)
+    nonce = os.urandom(16)
+    encryptor = Cipher(algorithms.AES(symkey), modes.CTR(nonce)).encryptor()
+    blob = encryptor.update(layer_gzip) + encryptor.finalize()
+
+    private_opts = {
+        "symkey": b64(symkey),
+        "digest": digest(layer_gzip),
+        "cipheroptions": {"nonce": b64(nonce)},
+    }
+    public_opts = {
+        "cipher": "AES_256_CTR_HMAC_SHA256",
+        "hmac": b64(hmac.new(symkey, blob, hashlib.sha256).digest()),
+        "cipheroptions": {},
+    }
+    fixture = {
+        "mediaType": MEDIA_TYPE,
+        "diffId": digest(layer_tar),
+        "annotations": {
+            "org.opencontainers.image.enc.pubopts": b64(json.dumps(public_opts).encode()),
+        },
+        "blob": b64(blob),
+    }
+    return fixture, json.dumps(private_opts).encode()
+
+
+def write_fixture(name, fixture):
+    with open(os.path.join(TEST_DATA, name), "w") as f:
+        json.dump(fixture, f, indent=4)
+        f.write("\n")
+
+
+def main():
+    with open(os.path.join(TEST_DATA, "public_key_for_tests.pem"), "rb") as f:
+        public_key = serialization.load_pem_public_key(f.read())
+
+    fixture, private_opts = encrypted_layer()
+    fixture["annotations"]["org.opencontainers.image.enc.keys.jwe"] = b64(
+        jwe(private_opts, public_key)
+    )
+    write_fixture("encrypted_layer.json", fixture)
+
+    kbs_key = os.urandom(32)
+    fixture, private_opts = encrypted_layer()
+    fixture["annotations"]["org.opencontainers.image.enc.keys.provider.attestation-agent"] = b64(
+        attestation_agent(private_opts, kbs_key)
+    )
+    fixture["kbsResources"] = {KBS_KEY_ID: b64(kbs_key)}
+    write_fixture("encrypted_layer_kbs.json", fixture)
+
+
+if __name__ == "__main__":
+    main()
@@ -13,35 +13,77 @@ pub mod digest;
 
 use digest::Digest;
 
-#[derive(EnumString, Display, Debug, PartialEq)]
+#[derive(EnumString, Display, Debug, Clone, Copy, PartialEq)]
 pub enum TransportName {
     #[strum(to_string = "docker")]
     Docker,
     #[strum(to_string = "dir")]
     Dir,
+    #[strum(to_string = "docker-archive")]
+    DockerArchive,
+    #[strum(to_string = "oci")]
+    Oci,
+    #[strum(to_string = "oci-archive")]
+    OciArchive,
 }
 
 // Image contains information about the image which may be used in signature verification.
 pub struct Image {
-    pub reference: Reference,
+    // The docker reference of the image, which local images may not have.
+    pub reference: Option<Reference>,
+    // The transport the image is read with, "docker" unless set.
+    pub transport: TransportName,
     // digest format: "digest-algorithm:digest-value"
     pub manifest_digest: Digest,
+    // The policy scope of a local image: the absolute path of the image,
+    // with the image it names in the path as a suffix like ":tag".
+    pub local_scope: Option<(String, String)>,
 }
 
 impl Image {
     pub fn default_with_reference(image_ref: Reference) -> Self {
         Image {
-            reference: image_ref,
+            reference: Some(image_ref),
+            transport: TransportName::Docker,
             manifest_digest: Digest::default(),
+            local_scope: None,
         }
     }
 
+    // A local image read with `transport`, which names no docker reference.
+    pub fn default_with_transport(transport: TransportName) -> Self {
+        Image {
+            reference: None,
+            transport,
+            manifest_digest: Digest::default(),
+            local_scope: None,
+        }
+    }
+
+    // The docker reference of the image, which the signatures of the image
+    // must be matched against.
+    pub fn docker_reference(&self) -> Result<&Reference> {
+        self.reference.as_ref().ok_or_else(|| {
+            anyhow!(
+                "The {} image has no docker reference to match its signatures against",
+                self.transport
+            )
+        })
+    }
+
     pub fn transport_name(&self) -> String {
-        // FIXME: Now only support "docker" transport (and it is hardcoded).
-        // TODO: support "dir" transport.
-        //
-        // issue: https://github.com/confidential-containers/image-rs/issues/11
-        TransportName::Docker.to_string()
+        self.transport.to_string()
+    }
+
+    // Local images are scoped by their path, as the operator names it. The
+    // docker reference of a local image comes from the image itself, so it
+    // must not select the policy applying to the image.
+    pub fn set_local_scope(&mut self, path: &str, suffix: &str) {
+        self.local_scope = Some((path.to_string(), suffix.to_string()));
+    }
+
+    pub fn set_transport(&mut self, transport: TransportName) {
+        self.transport = transport;
     }
 
     pub fn set_manifest_digest(&mut self, digest: &str) -> Result<()> {
@@ -120,6 +162,27 @@ pub fn get_image_namespaces(image_ref: &Reference) -> Vec<String> {
     res
 }
 
+// Returns the policy configuration namespaces of a local image at `path`,
+// with the image of `suffix`: the path itself when the suffix names an image,
+// then the parent directories of the path, but not the root directory.
+pub fn get_path_namespaces(path: &str, suffix: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    if !suffix.is_empty() {
+        res.push(path.to_string());
+    }
+
+    let mut name = path;
+    while let Some((parent, _)) = name.rsplit_once('/') {
+        if parent.is_empty() {
+            break;
+        }
+        res.push(parent.to_string());
+        name = parent;
+    }
+
+    res
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -222,4 +285,17 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_get_path_namespaces() {
+        assert_eq!(
+            get_path_namespaces("/images/busybox.tar", ":busybox:latest"),
+            vec!["/images/busybox.tar", "/images"]
+        );
+        assert_eq!(
+            get_path_namespaces("/var/lib/images/busybox", ""),
+            vec!["/var/lib/images", "/var/lib", "/var"]
+        );
+        assert!(get_path_namespaces("/busybox", "").is_empty());
+    }
 }
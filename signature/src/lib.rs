@@ -10,7 +10,7 @@ mod image;
 mod mechanism;
 mod policy;
 
-pub use image::Image;
+pub use image::{Image, TransportName};
 pub use policy::Policy;
 
 #[derive(EnumString, Display, Debug, PartialEq)]
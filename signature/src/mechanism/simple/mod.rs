@@ -96,7 +96,7 @@ pub fn judge_single_signature(
     // is consistent with the real information of the image.
     //
     // The match policy of image-reference is the "signedIdentity" field.
-    sig_payload.validate_signed_docker_reference(&image.reference, signed_identity)?;
+    sig_payload.validate_signed_docker_reference(image.docker_reference()?, signed_identity)?;
     sig_payload.validate_signed_docker_manifest_digest(&image.manifest_digest.to_string())?;
 
     Ok(())
@@ -104,17 +104,19 @@ pub fn judge_single_signature(
 
 #[allow(unused_assignments)]
 pub fn get_signatures(image: &mut image::Image) -> Result<Vec<Vec<u8>>> {
+    let reference = image.docker_reference()?;
+
     // Get image digest (manifest digest)
     let image_digest = if !image.manifest_digest.is_empty() {
         image.manifest_digest.clone()
-    } else if let Some(d) = image.reference.digest() {
+    } else if let Some(d) = reference.digest() {
         image::digest::Digest::try_from(d)?
     } else {
         return Err(anyhow!("Missing image digest"));
     };
 
     // Format the sigstore name: `image-repository@digest-algorithm=digest-value`.
-    let sigstore_name = sigstore::format_sigstore_name(&image.reference, image_digest);
+    let sigstore_name = sigstore::format_sigstore_name(reference, image_digest);
 
     // If the registry support `X-Registry-Supports-Signatures` API extension,
     // try to get signatures from the registry first.
@@ -128,7 +130,7 @@ pub fn get_signatures(image: &mut image::Image) -> Result<Vec<Vec<u8>>> {
         sigstore::SigstoreConfig::new_from_configs(sigstore::SIGSTORE_CONFIG_DIR)?;
 
     let sigstore_base_url = sigstore_config
-        .base_url(&image.reference)?
+        .base_url(reference)?
         .ok_or_else(|| anyhow!("The sigstore base url is none"))?;
 
     let sigstore = format!("{}/{}", &sigstore_base_url, &sigstore_name);
@@ -9,7 +9,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::vec::Vec;
 
-use crate::image;
+use crate::image::{self, TransportName};
 
 mod policy_requirement;
 mod ref_match;
@@ -93,17 +93,32 @@ impl Policy {
         let transport_name = image.transport_name();
 
         if let Some(transport_scopes) = self.transports.get(&transport_name) {
-            // Look for a full match.
-            let identity = image.reference.whole();
-            if let Some(reqs) = transport_scopes.get(&identity) {
-                return reqs;
-            }
-
-            // Look for a match of the possible parent namespaces.
-            for name in image::get_image_namespaces(&image.reference).iter() {
-                if let Some(reqs) = transport_scopes.get(name) {
+            // Registry images are scoped by their docker reference, local
+            // images by their path. A local image without a path only gets
+            // the default requirements of its transport.
+            let scopes = match (&image.transport, &image.local_scope) {
+                (TransportName::Docker, _) => image.reference.as_ref().map(|reference| {
+                    (reference.whole(), image::get_image_namespaces(reference))
+                }),
+                (_, Some((path, suffix))) => Some((
+                    format!("{}{}", path, suffix),
+                    image::get_path_namespaces(path, suffix),
+                )),
+                (_, None) => None,
+            };
+
+            if let Some((identity, namespaces)) = scopes {
+                // Look for a full match.
+                if let Some(reqs) = transport_scopes.get(&identity) {
                     return reqs;
                 }
+
+                // Look for a match of the possible parent namespaces.
+                for name in namespaces.iter() {
+                    if let Some(reqs) = transport_scopes.get(name) {
+                        return reqs;
+                    }
+                }
             }
 
             // Look for a default match for the transport.
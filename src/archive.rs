@@ -0,0 +1,648 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::manifest::{self, OciDescriptor, OciImageManifest};
+use oci_distribution::Reference;
+use oci_spec::image::ImageConfiguration;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::LimitsConfig;
+use crate::digest::digest_bytes;
+use crate::errors::{Limit, PullError};
+
+/// The prefix of the image references pointing into a `docker save` archive.
+pub const DOCKER_ARCHIVE_PREFIX: &str = "docker-archive:";
+
+/// The prefix of the image references pointing into a tar archive of an
+/// OCI image layout.
+pub const OCI_ARCHIVE_PREFIX: &str = "oci-archive:";
+
+/// The file listing the images of a docker archive.
+const DOCKER_ARCHIVE_MANIFEST: &str = "manifest.json";
+
+/// The media type of the image config of a docker archive image.
+const DOCKER_ARCHIVE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+
+/// Symbolic links are followed up to this depth in an archive.
+const MAX_LINK_DEPTH: usize = 8;
+
+/// TarArchive reads the files of an uncompressed tar archive in place. The
+/// archive is indexed once when opened, skipping over the file data, and
+/// files are then read from their offset in the archive, so that nothing
+/// is extracted.
+pub struct TarArchive {
+    path: PathBuf,
+
+    // files holds map of file name with its data offset and size.
+    files: HashMap<String, (u64, u64)>,
+}
+
+impl TarArchive {
+    /// Index the archive at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).map_err(|e| anyhow!("failed to open archive {:?}: {}", path, e))?;
+        let mut archive = tar::Archive::new(file);
+
+        let mut files = HashMap::new();
+        let mut links = HashMap::new();
+        for entry in archive
+            .entries_with_seek()
+            .map_err(|e| anyhow!("failed to read archive {:?}: {}", path, e))?
+        {
+            let entry = entry.map_err(|e| anyhow!("failed to read archive {:?}: {}", path, e))?;
+            let name = match normalize(&entry.path()?) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_file() {
+                files.insert(name, (entry.raw_file_position(), entry.size()));
+            } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+                // Symbolic links are relative to the link, hard links to the
+                // archive root. `docker save` links the layers shared by
+                // several images.
+                if let Some(target) = entry.link_name()? {
+                    let target = if entry_type.is_symlink() {
+                        Path::new(&name)
+                            .parent()
+                            .unwrap_or_else(|| Path::new(""))
+                            .join(target)
+                    } else {
+                        target.to_path_buf()
+                    };
+                    if let Some(target) = normalize(&target) {
+                        links.insert(name, target);
+                    }
+                }
+            }
+        }
+
+        for (name, target) in links.iter() {
+            let mut target = target;
+            for _ in 0..MAX_LINK_DEPTH {
+                match links.get(target) {
+                    Some(next) => target = next,
+                    None => break,
+                }
+            }
+            if let Some(file) = files.get(target).cloned() {
+                files.insert(name.clone(), file);
+            }
+        }
+
+        Ok(TarArchive {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Whether the archive holds the file `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    /// The size of the file `name`.
+    pub fn size(&self, name: &str) -> Result<u64> {
+        self.file(name).map(|(_, size)| size)
+    }
+
+    /// Read the whole file `name`, failing with the `limit` of `max` bytes
+    /// if it is larger. The size in the archive is not trusted, the buffer
+    /// only grows with the data read.
+    pub fn read(&self, name: &str, limit: Limit, max: u64) -> Result<Vec<u8>> {
+        let (position, size) = self.file(name)?;
+        if size > max {
+            return Err(PullError::LimitExceeded { limit, max }.into());
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(position))?;
+        let data = read_limited(file.take(size), limit, max)?;
+        if data.len() as u64 != size {
+            return Err(anyhow!("archive {:?} is truncated", self.path));
+        }
+
+        Ok(data)
+    }
+
+    /// Write the file `name` from `offset` into `out`.
+    pub async fn copy<T: AsyncWrite + Unpin>(
+        &self,
+        name: &str,
+        offset: u64,
+        mut out: T,
+    ) -> Result<()> {
+        let (position, size) = self.file(name)?;
+        let mut file = tokio::fs::File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(position + offset.min(size)))
+            .await?;
+        tokio::io::copy(&mut file.take(size.saturating_sub(offset)), &mut out).await?;
+        out.flush().await?;
+
+        Ok(())
+    }
+
+    fn file(&self, name: &str) -> Result<(u64, u64)> {
+        self.files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("file {} not found in archive {:?}", name, self.path))
+    }
+}
+
+/// Read the whole of `input`, failing with the `limit` of `max` bytes once
+/// it is larger. The buffer grows with the data read, so that a size
+/// declared by the input never drives an allocation.
+pub fn read_limited<R: Read>(input: R, limit: Limit, max: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    input.take(max.saturating_add(1)).read_to_end(&mut data)?;
+    if data.len() as u64 > max {
+        return Err(PullError::LimitExceeded { limit, max }.into());
+    }
+
+    Ok(data)
+}
+
+// Normalize an archive path into a relative one without "." components,
+// and reject the paths escaping the archive root.
+fn normalize(path: &Path) -> Option<String> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => components.push(c.to_str()?),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir => {
+                components.pop()?;
+            }
+            Component::Prefix(_) => return None,
+        }
+    }
+
+    if components.is_empty() {
+        return None;
+    }
+
+    Some(components.join("/"))
+}
+
+/// A reference to an image of a docker archive, in the
+/// `docker-archive:<path>[:<name>:<tag>|:@<index>]` format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DockerArchiveReference {
+    /// The archive path.
+    pub path: PathBuf,
+
+    /// The image, as one of its repository tags or as `@<index>` in the
+    /// archive manifest.
+    pub image: Option<String>,
+}
+
+impl FromStr for DockerArchiveReference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s
+            .strip_prefix(DOCKER_ARCHIVE_PREFIX)
+            .ok_or_else(|| anyhow!("invalid docker archive reference {}", s))?;
+
+        let (path, image) = match rest.split_once(':') {
+            Some((path, image)) => (path, Some(image.to_string())),
+            None => (rest, None),
+        };
+
+        if path.is_empty() || image.as_deref() == Some("") {
+            return Err(anyhow!("invalid docker archive reference {}", s));
+        }
+
+        Ok(DockerArchiveReference {
+            path: PathBuf::from(path),
+            image,
+        })
+    }
+}
+
+impl fmt::Display for DockerArchiveReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", DOCKER_ARCHIVE_PREFIX, self.path.display())?;
+        if let Some(image) = &self.image {
+            write!(f, ":{}", image)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One image of the manifest of a docker archive.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveImage {
+    config: String,
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+}
+
+/// DockerArchive reads an image from a `docker save` archive.
+///
+/// A docker archive has no image manifest, an OCI image manifest is made up
+/// from the archive manifest and the image config, with the uncompressed
+/// layers identified by their diff ids. The layers are verified against
+/// their digest as they are pulled, like the layers from a registry.
+pub struct DockerArchive {
+    reference: DockerArchiveReference,
+    archive: TarArchive,
+
+    // The docker reference of the image, one of its repository tags.
+    docker_reference: Option<String>,
+
+    manifest: Vec<u8>,
+    manifest_digest: String,
+    max_manifest_bytes: u64,
+
+    // blobs holds map of blob digest with its file name in the archive.
+    blobs: HashMap<String, String>,
+}
+
+impl DockerArchive {
+    /// Open the archive of `reference` and select its image. The archive
+    /// manifest and the image manifests are read within the manifest size
+    /// limit of `limits`, the image config within its config size limit.
+    pub fn open(reference: DockerArchiveReference, limits: &LimitsConfig) -> Result<Self> {
+        let archive = TarArchive::open(&reference.path)?;
+        let images: Vec<DockerArchiveImage> = serde_json::from_slice(&archive.read(
+            DOCKER_ARCHIVE_MANIFEST,
+            Limit::ManifestBytes,
+            limits.max_manifest_bytes,
+        )?)
+        .map_err(|e| {
+            anyhow!(
+                "failed to parse {} of {:?}: {}",
+                DOCKER_ARCHIVE_MANIFEST,
+                reference.path,
+                e
+            )
+        })?;
+        let (image, docker_reference) = select_image(&images, &reference)?;
+
+        let config = archive.read(&image.config, Limit::ConfigBytes, limits.max_config_bytes)?;
+        let config_digest = digest_bytes(&config, None)?;
+        let image_config = ImageConfiguration::from_reader(config.as_slice())?;
+        let diff_ids = image_config.rootfs().diff_ids();
+        if diff_ids.len() != image.layers.len() {
+            return Err(anyhow!(
+                "image {} has {} layers and {} diff ids",
+                reference,
+                image.layers.len(),
+                diff_ids.len()
+            ));
+        }
+
+        let mut blobs = HashMap::new();
+        blobs.insert(config_digest.clone(), image.config.clone());
+
+        let mut layers = Vec::new();
+        for (name, diff_id) in image.layers.iter().zip(diff_ids.iter()) {
+            blobs.insert(diff_id.clone(), name.clone());
+            layers.push(OciDescriptor {
+                media_type: manifest::IMAGE_LAYER_MEDIA_TYPE.to_string(),
+                digest: diff_id.clone(),
+                size: archive.size(name)? as i64,
+                ..Default::default()
+            });
+        }
+
+        let manifest = serde_json::to_vec(&OciImageManifest {
+            schema_version: 2,
+            media_type: Some(manifest::OCI_IMAGE_MEDIA_TYPE.to_string()),
+            config: OciDescriptor {
+                media_type: DOCKER_ARCHIVE_CONFIG_MEDIA_TYPE.to_string(),
+                digest: config_digest,
+                size: config.len() as i64,
+                ..Default::default()
+            },
+            layers,
+            annotations: None,
+        })?;
+        let manifest_digest = digest_bytes(&manifest, None)?;
+
+        Ok(DockerArchive {
+            reference,
+            archive,
+            docker_reference,
+            manifest,
+            manifest_digest,
+            max_manifest_bytes: limits.max_manifest_bytes,
+            blobs,
+        })
+    }
+
+    /// The reference the archive was opened with.
+    pub fn reference(&self) -> &DockerArchiveReference {
+        &self.reference
+    }
+
+    /// The docker reference of the image, if the archive names it.
+    pub fn docker_reference(&self) -> Option<&str> {
+        self.docker_reference.as_deref()
+    }
+
+    /// The digest of the image manifest made up for the image.
+    pub fn manifest_digest(&self) -> &str {
+        &self.manifest_digest
+    }
+
+    /// Read the whole manifest blob `digest`, the image manifest made up
+    /// for the image included, within the manifest size limit.
+    pub fn read_manifest(&self, digest: &str) -> Result<Vec<u8>> {
+        if digest == self.manifest_digest {
+            return Ok(self.manifest.clone());
+        }
+
+        self.archive.read(
+            self.blob_name(digest)?,
+            Limit::ManifestBytes,
+            self.max_manifest_bytes,
+        )
+    }
+
+    /// Write the blob `digest` from `offset` into `out`.
+    pub async fn copy_blob<T: AsyncWrite + Unpin>(
+        &self,
+        digest: &str,
+        offset: u64,
+        out: T,
+    ) -> Result<()> {
+        self.archive
+            .copy(self.blob_name(digest)?, offset, out)
+            .await
+    }
+
+    fn blob_name(&self, digest: &str) -> Result<&str> {
+        self.blobs
+            .get(digest)
+            .map(|name| name.as_str())
+            .ok_or_else(|| anyhow!("blob {} not found in {}", digest, self.reference))
+    }
+}
+
+// Select the image of `reference` in the archive manifest, with its docker
+// reference. Without an image in the reference, the archive must hold one.
+fn select_image<'a>(
+    images: &'a [DockerArchiveImage],
+    reference: &DockerArchiveReference,
+) -> Result<(&'a DockerArchiveImage, Option<String>)> {
+    let image = match reference.image.as_deref() {
+        None if images.len() == 1 => &images[0],
+        None => {
+            return Err(anyhow!(
+                "{} images in {}, an image name or index is required",
+                images.len(),
+                reference
+            ))
+        }
+        Some(name) => match name.strip_prefix('@') {
+            Some(index) => {
+                let index = index
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid image index in {}", reference))?;
+                images
+                    .get(index)
+                    .ok_or_else(|| anyhow!("no image {} in {}", index, reference))?
+            }
+            None => {
+                let name = Reference::try_from(name)?;
+                let image = images
+                    .iter()
+                    .find(|image| {
+                        image
+                            .repo_tags
+                            .iter()
+                            .filter_map(|tag| Reference::try_from(tag.as_str()).ok())
+                            .any(|tag| tag.whole() == name.whole())
+                    })
+                    .ok_or_else(|| anyhow!("no image {} in {}", name.whole(), reference))?;
+                return Ok((image, Some(name.whole())));
+            }
+        },
+    };
+
+    let docker_reference = image
+        .repo_tags
+        .first()
+        .and_then(|tag| Reference::try_from(tag.as_str()).ok())
+        .map(|tag| tag.whole());
+
+    Ok((image, docker_reference))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, name: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data).unwrap();
+    }
+
+    fn append_link(builder: &mut tar::Builder<Vec<u8>>, name: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, name, target).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tar_archive() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "./a/file", b"file data");
+        append(&mut builder, "b", &vec![1u8; 1024]);
+        append_link(&mut builder, "c/link", "../a/file");
+        append_link(&mut builder, "escape", "../../etc/passwd");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+
+        let archive = TarArchive::open(&path).unwrap();
+        let read = |name: &str| archive.read(name, Limit::ManifestBytes, 1024);
+        assert_eq!(read("a/file").unwrap(), b"file data");
+        assert_eq!(read("c/link").unwrap(), b"file data");
+        assert_eq!(archive.size("b").unwrap(), 1024);
+        assert!(read("b").is_ok());
+        assert_eq!(
+            archive
+                .read("b", Limit::ManifestBytes, 1023)
+                .unwrap_err()
+                .downcast_ref::<PullError>(),
+            Some(&PullError::LimitExceeded {
+                limit: Limit::ManifestBytes,
+                max: 1023
+            })
+        );
+        assert!(!archive.contains("escape"));
+        assert!(read("d").is_err());
+
+        let mut out = Vec::new();
+        archive.copy("a/file", 5, &mut out).await.unwrap();
+        assert_eq!(out, b"data");
+    }
+
+    #[test]
+    fn test_tar_archive_size() {
+        // A header declaring a huge file, with a few bytes of data: its
+        // size is never allocated.
+        let mut header = tar::Header::new_gnu();
+        header.set_path("manifest.json").unwrap();
+        header.set_size(1 << 40);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut data = header.as_bytes().to_vec();
+        data.extend_from_slice(&[b'['; 512]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        std::fs::write(&path, data).unwrap();
+
+        let archive = TarArchive::open(&path).unwrap();
+        assert!(archive
+            .read("manifest.json", Limit::ManifestBytes, 4096)
+            .is_err());
+        assert!(archive
+            .read("manifest.json", Limit::ManifestBytes, u64::MAX)
+            .is_err());
+
+        let reference =
+            DockerArchiveReference::from_str(&format!("docker-archive:{}", path.display()))
+                .unwrap();
+        assert!(DockerArchive::open(reference, &LimitsConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_docker_archive_reference() {
+        let tests = &[
+            ("docker-archive:/images.tar", "/images.tar", None),
+            (
+                "docker-archive:/images.tar:busybox:latest",
+                "/images.tar",
+                Some("busybox:latest"),
+            ),
+            ("docker-archive:images.tar:@1", "images.tar", Some("@1")),
+        ];
+
+        for (s, path, image) in tests.iter() {
+            let reference = DockerArchiveReference::from_str(s).unwrap();
+            assert_eq!(reference.path, PathBuf::from(path));
+            assert_eq!(reference.image.as_deref(), *image);
+            assert_eq!(&reference.to_string(), s);
+        }
+
+        for s in [
+            "/images.tar",
+            "docker-archive:",
+            "docker-archive:/images.tar:",
+        ]
+        .iter()
+        {
+            assert!(DockerArchiveReference::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_docker_archive() {
+        let layer = b"layer data".to_vec();
+        let diff_id = digest_bytes(&layer, None).unwrap();
+        let config = format!(
+            r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": ["{}"]}}, "history": []}}"#,
+            diff_id
+        );
+        let images = r#"[
+            {"Config": "config.json", "RepoTags": ["busybox:latest"], "Layers": ["1/layer.tar"]},
+            {"Config": "config.json", "RepoTags": ["quay.io/busybox:v1"], "Layers": ["2/layer.tar"]}
+        ]"#;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "manifest.json", images.as_bytes());
+        append(&mut builder, "config.json", config.as_bytes());
+        append(&mut builder, "1/layer.tar", &layer);
+        append_link(&mut builder, "2/layer.tar", "../1/layer.tar");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        let open = |image: &str| {
+            DockerArchive::open(
+                DockerArchiveReference::from_str(&format!(
+                    "docker-archive:{}{}",
+                    path.display(),
+                    image
+                ))
+                .unwrap(),
+                &LimitsConfig::default(),
+            )
+        };
+
+        assert!(open("").is_err());
+        assert!(open(":@2").is_err());
+        assert!(open(":alpine:latest").is_err());
+
+        let archive = open(":@1").unwrap();
+        assert_eq!(
+            archive.docker_reference(),
+            Some(
+                Reference::try_from("quay.io/busybox:v1")
+                    .unwrap()
+                    .whole()
+                    .as_str()
+            )
+        );
+
+        let archive = open(":busybox:latest").unwrap();
+        let manifest: OciImageManifest =
+            serde_json::from_slice(&archive.read_manifest(archive.manifest_digest()).unwrap())
+                .unwrap();
+        let mut out = Vec::new();
+        archive
+            .copy_blob(&manifest.config.digest, 0, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, config.as_bytes());
+        assert_eq!(manifest.layers[0].digest, diff_id);
+        assert_eq!(manifest.layers[0].size, layer.len() as i64);
+
+        let mut out = Vec::new();
+        archive.copy_blob(&diff_id, 0, &mut out).await.unwrap();
+        assert_eq!(out, layer);
+        assert!(archive.read_manifest("sha256:1234").is_err());
+
+        // The archive manifest and the config are read within the limits.
+        let reference =
+            DockerArchiveReference::from_str(&format!("docker-archive:{}", path.display()))
+                .unwrap();
+        let limits = LimitsConfig {
+            max_manifest_bytes: 64,
+            ..Default::default()
+        };
+        assert!(DockerArchive::open(reference.clone(), &limits).is_err());
+        let limits = LimitsConfig {
+            max_config_bytes: 64,
+            ..Default::default()
+        };
+        assert!(DockerArchive::open(reference, &limits).is_err());
+    }
+}
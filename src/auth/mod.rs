@@ -0,0 +1,420 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::Reference;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use crate::validate::get_resource_from_kbs;
+
+/// The canonical name of the Docker Hub registry.
+const DOCKER_HUB: &str = "docker.io";
+
+/// The names Docker Hub is known by in credential files.
+const DOCKER_HUB_ALIASES: &[&str] = &["index.docker.io", "registry-1.docker.io"];
+
+/// The key of docker config data in a Kubernetes `kubernetes.io/dockerconfigjson` secret.
+const K8S_DOCKER_CONFIG_JSON: &str = ".dockerconfigjson";
+
+/// The key of docker config data in a Kubernetes `kubernetes.io/dockercfg` secret.
+const K8S_DOCKER_CFG: &str = ".dockercfg";
+
+/// The kind of the Kubernetes secret objects.
+const K8S_SECRET_KIND: &str = "Secret";
+
+/// The types of the Kubernetes docker config secrets.
+const K8S_DOCKER_CONFIG_JSON_TYPE: &str = "kubernetes.io/dockerconfigjson";
+const K8S_DOCKER_CFG_TYPE: &str = "kubernetes.io/dockercfg";
+
+/// The top level keys of a docker config.json holding credentials, a legacy
+/// .dockercfg has none of them.
+const DOCKER_CONFIG_KEYS: &[&str] = &["auths", "credsStore", "credHelpers"];
+
+/// The name of the KBS resource holding registry credentials.
+const KBS_CREDENTIAL_RESOURCE: &str = "Credential";
+
+/// The credential to authenticate to an OCI registry with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Credential {
+    /// Access the registry anonymously.
+    #[default]
+    Anonymous,
+
+    /// Username and password, for basic auth or to request bearer tokens.
+    Basic(String, String),
+
+    /// An identity token (OAuth2 refresh token), exchanged for bearer tokens.
+    IdentityToken(String),
+
+    /// A bearer token to send to the registry as is.
+    RegistryToken(String),
+}
+
+impl TryFrom<&str> for Credential {
+    type Error = anyhow::Error;
+
+    /// Parse auth info in "username:password" format.
+    fn try_from(auth_info: &str) -> Result<Self> {
+        match auth_info.split_once(':') {
+            Some((username, password)) => Ok(Credential::Basic(
+                username.to_string(),
+                password.to_string(),
+            )),
+            None => Err(anyhow!("Invalid authentication info ({:?})", auth_info)),
+        }
+    }
+}
+
+/// One registry entry of a docker config file.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthEntry {
+    /// base64 encoded "username:password".
+    #[serde(default)]
+    pub auth: Option<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<String>,
+
+    #[serde(default)]
+    pub identitytoken: Option<String>,
+
+    #[serde(default)]
+    pub registrytoken: Option<String>,
+}
+
+impl TryFrom<&AuthEntry> for Credential {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: &AuthEntry) -> Result<Self> {
+        if let Some(token) = entry.registrytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Ok(Credential::RegistryToken(token.clone()));
+        }
+
+        if let Some(token) = entry.identitytoken.as_ref().filter(|t| !t.is_empty()) {
+            return Ok(Credential::IdentityToken(token.clone()));
+        }
+
+        if let Some(auth) = entry.auth.as_ref().filter(|a| !a.is_empty()) {
+            let decoded = base64::decode(auth.trim())
+                .map_err(|e| anyhow!("invalid base64 auth entry: {}", e))?;
+            return Credential::try_from(String::from_utf8(decoded)?.as_str());
+        }
+
+        match (&entry.username, &entry.password) {
+            (Some(username), Some(password)) => {
+                Ok(Credential::Basic(username.clone(), password.clone()))
+            }
+            _ => Ok(Credential::Anonymous),
+        }
+    }
+}
+
+/// Registry credentials in the formats of docker `config.json`, containers
+/// `auth.json`, the legacy `.dockercfg`, or a Kubernetes docker config secret.
+#[derive(Clone, Debug, Default)]
+pub struct DockerConfig {
+    // auths holds map of normalized registry (and optional repository
+    // namespace) with its auth entry.
+    auths: HashMap<String, AuthEntry>,
+}
+
+// A docker config.json or containers auth.json. The credential helpers of
+// "credsStore" and "credHelpers" are not run, and the other keys are
+// settings of the docker CLI, they are all ignored.
+#[derive(Deserialize)]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+}
+
+#[derive(Deserialize)]
+struct K8sSecret {
+    #[serde(default, rename = "type")]
+    secret_type: Option<String>,
+    #[serde(default)]
+    data: HashMap<String, String>,
+}
+
+impl K8sSecret {
+    // The docker config data of the secret, the key of its type, or else
+    // the first key found.
+    fn docker_config(&self) -> Result<Vec<u8>> {
+        let keys: &[&str] = match self.secret_type.as_deref() {
+            Some(K8S_DOCKER_CONFIG_JSON_TYPE) => &[K8S_DOCKER_CONFIG_JSON],
+            Some(K8S_DOCKER_CFG_TYPE) => &[K8S_DOCKER_CFG],
+            _ => &[K8S_DOCKER_CONFIG_JSON, K8S_DOCKER_CFG],
+        };
+
+        for key in keys.iter() {
+            if let Some(encoded) = self.data.get(*key) {
+                return base64::decode(encoded.trim())
+                    .map_err(|e| anyhow!("invalid base64 secret data {}: {}", key, e));
+            }
+        }
+
+        Err(anyhow!("no docker config found in secret data"))
+    }
+}
+
+impl DockerConfig {
+    /// Load credentials from a file, see `DockerConfig::from_slice`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data =
+            fs::read(path).map_err(|e| anyhow!("failed to read auth file {:?}: {}", path, e))?;
+        Self::from_slice(&data)
+    }
+
+    /// Get credentials from the KBS as the "Credential" resource, which
+    /// is in one of the formats supported by `DockerConfig::from_slice`.
+    pub async fn from_kbs(aa_kbc_params: &str) -> Result<Self> {
+        let data = get_resource_from_kbs(KBS_CREDENTIAL_RESOURCE, aa_kbc_params).await?;
+        Self::from_slice(&data)
+    }
+
+    /// Parse credentials from one of the supported formats:
+    ///    - docker config.json or containers auth.json: {"auths": {...}},
+    ///      with optional "auths", and the other keys ignored
+    ///    - legacy .dockercfg: {"registry": {"auth": "..."}}
+    ///    - Kubernetes secret: {"kind": "Secret", "type": "kubernetes.io/dockerconfigjson",
+    ///      "data": {".dockerconfigjson": "<base64>"}}
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let config: Value = serde_json::from_slice(data)
+            .map_err(|e| anyhow!("failed to parse docker config: {}", e))?;
+        let object = config
+            .as_object()
+            .ok_or_else(|| anyhow!("failed to parse docker config: not a JSON object"))?;
+
+        if is_k8s_secret(object) {
+            let secret: K8sSecret = serde_json::from_value(config)
+                .map_err(|e| anyhow!("failed to parse docker config secret: {}", e))?;
+            return Self::from_slice(&secret.docker_config()?);
+        }
+
+        let auths = if is_legacy_docker_cfg(object) {
+            serde_json::from_value::<HashMap<String, AuthEntry>>(config)
+        } else {
+            serde_json::from_value::<DockerConfigFile>(config).map(|config| config.auths)
+        }
+        .map_err(|e| anyhow!("failed to parse docker config: {}", e))?;
+
+        Ok(DockerConfig {
+            auths: auths
+                .into_iter()
+                .map(|(key, entry)| (normalize_auth_key(&key), entry))
+                .collect(),
+        })
+    }
+
+    /// Look up the credential for an image reference. The most specific
+    /// `registry/namespace/repository` entry wins over the registry entry.
+    pub fn credential(&self, reference: &Reference) -> Result<Option<Credential>> {
+        let mut name = format!(
+            "{}/{}",
+            normalize_registry(reference.registry()),
+            reference.repository()
+        );
+
+        loop {
+            if let Some(entry) = self.auths.get(&name) {
+                return Credential::try_from(entry).map(Some);
+            }
+
+            match name.rsplit_once('/') {
+                Some((parent, _)) => name = parent.to_string(),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+// Whether `config` is a Kubernetes secret, by its kind or its type.
+fn is_k8s_secret(config: &Map<String, Value>) -> bool {
+    let field = |key: &str| config.get(key).and_then(Value::as_str);
+
+    field("kind") == Some(K8S_SECRET_KIND)
+        || matches!(
+            field("type"),
+            Some(K8S_DOCKER_CONFIG_JSON_TYPE) | Some(K8S_DOCKER_CFG_TYPE)
+        )
+}
+
+// Whether `config` is a legacy .dockercfg, a map of registry with its auth
+// entry, rather than a docker config.json.
+fn is_legacy_docker_cfg(config: &Map<String, Value>) -> bool {
+    !config.is_empty()
+        && !DOCKER_CONFIG_KEYS
+            .iter()
+            .any(|key| config.contains_key(*key))
+        && config.values().all(Value::is_object)
+}
+
+// Normalize a credential file key like "https://index.docker.io/v1/"
+// into "docker.io".
+fn normalize_auth_key(key: &str) -> String {
+    let key = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let key = key
+        .strip_suffix("/v1/")
+        .or_else(|| key.strip_suffix("/v2/"))
+        .unwrap_or(key)
+        .trim_end_matches('/');
+
+    match key.split_once('/') {
+        Some((registry, path)) => format!("{}/{}", normalize_registry(registry), path),
+        None => normalize_registry(key).to_string(),
+    }
+}
+
+// Map the Docker Hub aliases to "docker.io".
+fn normalize_registry(registry: &str) -> &str {
+    if DOCKER_HUB_ALIASES.contains(&registry) {
+        DOCKER_HUB
+    } else {
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credential_from_auth_info() {
+        assert_eq!(
+            Credential::try_from("user:pass:word").unwrap(),
+            Credential::Basic("user".to_string(), "pass:word".to_string())
+        );
+        assert!(Credential::try_from("user").is_err());
+    }
+
+    #[test]
+    fn test_docker_config() {
+        let data = r#"{
+            "auths": {
+                "https://index.docker.io/v1/": {
+                    "auth": "dXNlcjpwYXNzd29yZA=="
+                },
+                "quay.io": {
+                    "username": "quay_user",
+                    "password": "quay_password"
+                },
+                "quay.io/kata-containers": {
+                    "identitytoken": "refresh_token"
+                },
+                "registry.example.com:5000": {
+                    "registrytoken": "bearer_token"
+                }
+            }
+        }"#;
+        let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+
+        let tests = &[
+            (
+                "busybox",
+                Some(Credential::Basic(
+                    "user".to_string(),
+                    "password".to_string(),
+                )),
+            ),
+            (
+                "index.docker.io/library/busybox:latest",
+                Some(Credential::Basic(
+                    "user".to_string(),
+                    "password".to_string(),
+                )),
+            ),
+            (
+                "quay.io/prometheus/busybox:latest",
+                Some(Credential::Basic(
+                    "quay_user".to_string(),
+                    "quay_password".to_string(),
+                )),
+            ),
+            (
+                "quay.io/kata-containers/confidential-containers:signed",
+                Some(Credential::IdentityToken("refresh_token".to_string())),
+            ),
+            (
+                "registry.example.com:5000/busybox",
+                Some(Credential::RegistryToken("bearer_token".to_string())),
+            ),
+            ("gcr.io/google-containers/busybox:1.27.2", None),
+        ];
+
+        for (image, credential) in tests.iter() {
+            let reference = Reference::try_from(*image).unwrap();
+            assert_eq!(&config.credential(&reference).unwrap(), credential);
+        }
+    }
+
+    #[test]
+    fn test_docker_config_formats() {
+        let expected = Some(Credential::Basic(
+            "user".to_string(),
+            "password".to_string(),
+        ));
+        let reference = Reference::try_from("docker.io/library/busybox").unwrap();
+
+        // legacy .dockercfg
+        let data = r#"{"registry-1.docker.io": {"auth": "dXNlcjpwYXNzd29yZA=="}}"#;
+        let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(config.credential(&reference).unwrap(), expected);
+
+        // Kubernetes dockerconfigjson secret
+        let docker_config = r#"{"auths": {"docker.io": {"auth": "dXNlcjpwYXNzd29yZA=="}}}"#;
+        let data = format!(
+            r#"{{"type": "kubernetes.io/dockerconfigjson", "data": {{".dockerconfigjson": "{}"}}}}"#,
+            base64::encode(docker_config)
+        );
+        let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(config.credential(&reference).unwrap(), expected);
+
+        // Kubernetes dockercfg secret
+        let data = format!(
+            r#"{{"kind": "Secret", "type": "kubernetes.io/dockercfg", "data": {{".dockercfg": "{}"}}}}"#,
+            base64::encode(r#"{"docker.io": {"auth": "dXNlcjpwYXNzd29yZA=="}}"#)
+        );
+        let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(config.credential(&reference).unwrap(), expected);
+
+        // docker config.json with the docker CLI settings, and a legacy
+        // .dockercfg entry named "data", are not secrets.
+        let data = r#"{
+            "auths": {"docker.io": {"auth": "dXNlcjpwYXNzd29yZA=="}},
+            "credHelpers": {"gcr.io": "gcloud"},
+            "data": {"key": "value"},
+            "psFormat": "table {{.ID}}"
+        }"#;
+        let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(config.credential(&reference).unwrap(), expected);
+        let data = r#"{"data": {"auth": "dXNlcjpwYXNzd29yZA=="}}"#;
+        assert!(DockerConfig::from_slice(data.as_bytes()).is_ok());
+
+        // docker config.json without auths, the credential helpers are
+        // not run.
+        for data in [
+            r#"{"credsStore": "desktop"}"#,
+            r#"{"credHelpers": {"docker.io": "desktop"}}"#,
+            r#"{}"#,
+        ]
+        .iter()
+        {
+            let config = DockerConfig::from_slice(data.as_bytes()).unwrap();
+            assert_eq!(config.credential(&reference).unwrap(), None);
+        }
+
+        assert!(DockerConfig::from_slice(b"not json").is_err());
+        assert!(DockerConfig::from_slice(b"[]").is_err());
+        assert!(DockerConfig::from_slice(br#"{"kind": "Secret", "data": {}}"#).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::digest::{split_digest, HashReader, LayerDigestHasher};
+use crate::errors::PullError;
+
+/// The buffer size of a copy out of the cache.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The BlobCache looks image blobs up by digest in a read-only directory
+/// the host fills, like a virtio-fs share of blobs it pre-fetched. The blobs
+/// are named like the layer store does, `<algorithm>_<encoded digest>`.
+///
+/// The host is not trusted: a cached blob is only used once it matches the
+/// digest and size of its descriptor in the verified image manifest, else
+/// the blob is pulled from the image source. The host may still change the
+/// file once checked, so no more than the blob size is read from it, and
+/// the data is verified again as it is copied.
+#[derive(Debug)]
+pub struct BlobCache {
+    dir: PathBuf,
+
+    hits: AtomicU64,
+
+    misses: AtomicU64,
+
+    rejected: AtomicU64,
+
+    cache_bytes: AtomicU64,
+}
+
+/// The metrics of a blob cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlobCacheStats {
+    /// The number of blobs read from the cache.
+    pub hits: u64,
+
+    /// The number of blobs not in the cache.
+    pub misses: u64,
+
+    /// The number of cached blobs not matching their descriptor, when
+    /// opened or copied, they are pulled from the image source instead.
+    pub rejected: u64,
+
+    /// The number of bytes of the cached blobs used.
+    pub cache_bytes: u64,
+}
+
+impl BlobCache {
+    /// Constructs a blob cache looking blobs up in `dir`.
+    pub fn new(dir: &Path) -> Self {
+        BlobCache {
+            dir: dir.to_path_buf(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            cache_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// The directory of the cached blobs.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The metrics of the cache so far.
+    pub fn stats(&self) -> BlobCacheStats {
+        BlobCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            cache_bytes: self.cache_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Open the cached blob `digest`, once checked against the blob digest
+    /// and `size`. It returns `None` if the blob is not in the cache or does
+    /// not match.
+    pub async fn open(&self, digest: &str, size: u64) -> Option<tokio::fs::File> {
+        // The digest becomes a path, it must not be able to escape the cache.
+        let file = split_digest(digest).ok().and_then(|(algorithm, encoded)| {
+            File::open(self.dir.join(format!("{}_{}", algorithm, encoded))).ok()
+        });
+        let file = match file {
+            Some(file) => file,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        let expected = digest.to_string();
+        match tokio::task::spawn_blocking(move || verify(file, &expected, size)).await {
+            Ok(Ok(file)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(tokio::fs::File::from_std(file))
+            }
+            _ => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Write the cached blob `file`, as opened for the blob `digest` of
+    /// `size` bytes, from `offset` into `out`. The whole blob is read to be
+    /// verified as it is copied, it fails with `PullError::CachedBlobMismatch`
+    /// if it changed since it was opened, once the data is written: `out`
+    /// is then to be discarded.
+    pub async fn copy<T: AsyncWrite + Unpin>(
+        &self,
+        file: tokio::fs::File,
+        digest: &str,
+        size: u64,
+        offset: u64,
+        mut out: T,
+    ) -> Result<()> {
+        let mut hasher = LayerDigestHasher::new(digest)?;
+        let mut file = file.take(size);
+        let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+        let mut read = 0;
+        let mut copied = 0;
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+
+            // The data before `offset` is only verified.
+            let start = offset.saturating_sub(read).min(n as u64) as usize;
+            out.write_all(&buf[start..n]).await?;
+            read += n as u64;
+            copied += (n - start) as u64;
+        }
+        out.flush().await?;
+
+        if read != size || hasher.digest_str() != digest {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(PullError::CachedBlobMismatch {
+                digest: digest.to_string(),
+            }
+            .into());
+        }
+        self.cache_bytes.fetch_add(copied, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+// Check that `file` holds the `size` bytes of the blob `digest`, and rewind
+// it to be read again.
+fn verify(mut file: File, digest: &str, size: u64) -> Result<File> {
+    let mut reader = HashReader::new(&mut file, digest)?;
+    let read = io::copy(&mut reader, &mut io::sink())?;
+    let actual = reader.digest_str();
+    if read != size || actual != digest {
+        return Err(anyhow!(
+            "cached blob {} does not match, {} bytes with digest {}",
+            digest,
+            read,
+            actual
+        ));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::digest_bytes;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_blob_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = BlobCache::new(dir.path());
+
+        let blob = b"blob data";
+        let digest = digest_bytes(blob, None).unwrap();
+        assert!(cache.open(&digest, blob.len() as u64).await.is_none());
+        assert!(cache.open("sha256:../blob", 4).await.is_none());
+
+        fs::write(dir.path().join(digest.replace(':', "_")), blob).unwrap();
+        let size = blob.len() as u64;
+        let file = cache.open(&digest, size).await.unwrap();
+        let mut data = Vec::new();
+        cache.copy(file, &digest, size, 5, &mut data).await.unwrap();
+        assert_eq!(data, b"data");
+
+        // No more than the blob size is read from a file growing once
+        // opened, and a file changed once opened fails the copy.
+        let path = dir.path().join(digest.replace(':', "_"));
+        let file = cache.open(&digest, size).await.unwrap();
+        fs::write(&path, b"blob data and more").unwrap();
+        let mut data = Vec::new();
+        cache.copy(file, &digest, size, 0, &mut data).await.unwrap();
+        assert_eq!(data, blob);
+        fs::write(&path, blob).unwrap();
+        let file = cache.open(&digest, size).await.unwrap();
+        fs::write(&path, b"blob dat!").unwrap();
+        let err = cache
+            .copy(file, &digest, size, 0, &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PullError>(),
+            Some(&PullError::CachedBlobMismatch {
+                digest: digest.clone()
+            })
+        );
+        fs::write(&path, blob).unwrap();
+
+        // A blob not matching its descriptor is not used.
+        assert!(cache.open(&digest, 4).await.is_none());
+        let other = digest_bytes(b"other data", None).unwrap();
+        fs::write(dir.path().join(other.replace(':', "_")), blob).unwrap();
+        assert!(cache.open(&other, blob.len() as u64).await.is_none());
+
+        assert_eq!(
+            cache.stats(),
+            BlobCacheStats {
+                hits: 3,
+                misses: 2,
+                rejected: 3,
+                cache_bytes: 4 + 9,
+            }
+        );
+    }
+}
@@ -4,15 +4,34 @@
 
 use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use crate::decoder::CompressionDetection;
 use crate::snapshots::SnapshotType;
 use crate::CC_IMAGE_WORK_DIR;
 
 const DEFAULT_WORK_DIR: &str = "/var/lib/image-rs/";
 
+const DEFAULT_MAX_DOWNLOADS: usize = 6;
+const DEFAULT_MAX_DOWNLOADS_PER_REGISTRY: usize = 3;
+const DEFAULT_MAX_UNPACKS: usize = 2;
+
+const DEFAULT_MAX_LAYER_BYTES: u64 = 8 << 30;
+const DEFAULT_MAX_IMAGE_BYTES: u64 = 16 << 30;
+const DEFAULT_MAX_LAYER_ENTRIES: u64 = 1_000_000;
+const DEFAULT_MAX_PATH_DEPTH: usize = 128;
+const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+const DEFAULT_MAX_MANIFEST_BYTES: u64 = 4 << 20;
+const DEFAULT_MAX_CONFIG_BYTES: u64 = 8 << 20;
+
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RETRY_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 30_000;
+
 /// `image-rs` configuration information.
 #[derive(Clone, Debug, Deserialize)]
 pub struct ImageConfig {
@@ -24,6 +43,132 @@ pub struct ImageConfig {
 
     /// Security validation control
     pub security_validate: bool,
+
+    /// The platform to pull for multi-platform images, in
+    /// "os/architecture[/variant]" format. Defaults to the host platform.
+    #[serde(default)]
+    pub platform: Option<String>,
+
+    /// The registry credentials file, in docker `config.json`, containers
+    /// `auth.json` or Kubernetes docker config secret format.
+    #[serde(default)]
+    pub auth_file: Option<PathBuf>,
+
+    /// Fetch registry credentials from the KBS as a "Credential" resource,
+    /// auth info passed in from the host is rejected when enabled.
+    #[serde(default)]
+    pub kbs_credentials: bool,
+
+    /// The registries configuration file, in containers `registries.conf`
+    /// version 2 format, to resolve short image names and to pull images from
+    /// mirrors or rewritten locations.
+    #[serde(default)]
+    pub registries_config: Option<PathBuf>,
+
+    /// The registry short image names resolve to without a registries
+    /// config, like "docker.io". It is unset by default: short image names
+    /// are rejected without a registries config, and images must be named
+    /// by their fully qualified reference, rather than silently pulled from
+    /// a registry the guest owner did not choose.
+    #[serde(default)]
+    pub default_registry: Option<String>,
+
+    /// The TLS and protocol settings of registries, keyed by registry
+    /// "host[:port]".
+    #[serde(default)]
+    pub registry_tls: HashMap<String, RegistryTlsConfig>,
+
+    /// How registry requests failing with transient errors are retried.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The limits of concurrent layer downloads and unpacking jobs.
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+
+    /// A read-only directory of image blobs pre-fetched by the host, named
+    /// `<algorithm>_<encoded digest>`. The blobs are not trusted, they are
+    /// only used when they match the verified image manifest, and pulled
+    /// from the registry otherwise.
+    #[serde(default)]
+    pub blob_cache_dir: Option<PathBuf>,
+
+    /// How the foreign layers are pulled, the layers of non-distributable
+    /// media types which name the URLs to fetch them from.
+    #[serde(default)]
+    pub foreign_layers: ForeignLayerConfig,
+
+    /// The limits on the data a pull may unpack or buffer.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    /// How the compression of layers is detected from their magic bytes,
+    /// "strict" by default, or "lenient".
+    #[serde(default)]
+    pub compression_detection: CompressionDetection,
+}
+
+/// The TLS and protocol settings to access a registry with.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RegistryTlsConfig {
+    /// CA certificates bundle in PEM format, trusted in addition to the
+    /// built-in root certificates.
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+
+    /// Client certificate chain in PEM format, for mutual TLS.
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+
+    /// Private key of the client certificate in PEM format.
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+
+    /// Accept any registry certificate, for test registries only.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+
+    /// Access the registry over plain HTTP instead of HTTPS.
+    #[serde(default)]
+    pub plain_http: bool,
+}
+
+/// The foreign layers settings. The URLs of a foreign layer descriptor are
+/// tried in order, for the URL hosts allowed only, before the registry the
+/// image is pulled from. The fetched data is verified against the layer
+/// digest and size like any layer blob.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ForeignLayerConfig {
+    /// Fail the pull of the images with foreign layers.
+    #[serde(default)]
+    pub refuse: bool,
+
+    /// The "host[:port]" of the URLs foreign layers may be fetched from.
+    /// The URLs of other hosts are ignored, and so are all the URLs when
+    /// no host is allowed. Redirects are not followed, as they may lead to
+    /// any host.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+impl ForeignLayerConfig {
+    /// Whether the foreign layer `url` may be fetched, an HTTP or HTTPS URL
+    /// of an allowed host.
+    pub fn allows(&self, url: &str) -> bool {
+        let url = match reqwest::Url::parse(url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => return false,
+        };
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+
+        self.allowed_hosts.iter().any(|allowed| match url.port() {
+            Some(port) => *allowed == format!("{}:{}", host, port),
+            None => allowed == host,
+        })
+    }
 }
 
 impl Default for ImageConfig {
@@ -37,6 +182,193 @@ impl Default for ImageConfig {
             work_dir,
             default_snapshot: SnapshotType::Overlay,
             security_validate: false,
+            platform: None,
+            auth_file: None,
+            kbs_credentials: false,
+            registries_config: None,
+            default_registry: None,
+            registry_tls: HashMap::new(),
+            retry: RetryConfig::default(),
+            scheduler: SchedulerConfig::default(),
+            blob_cache_dir: None,
+            foreign_layers: ForeignLayerConfig::default(),
+            limits: LimitsConfig::default(),
+            compression_detection: CompressionDetection::default(),
+        }
+    }
+}
+
+/// The retry settings for registry requests failing with transient errors,
+/// like 5xx or 429 responses and connection resets. The delay between
+/// attempts doubles from the initial backoff up to the max backoff, unless
+/// the registry asks for a delay with a Retry-After header.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// The number of attempts of a request, including the first one.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+
+    /// The delay before the first retry, in milliseconds.
+    #[serde(default = "default_retry_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+
+    /// The maximum delay between two attempts, in milliseconds.
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_retry_max_attempts() -> u32 {
+    DEFAULT_RETRY_MAX_ATTEMPTS
+}
+
+fn default_retry_initial_backoff_ms() -> u64 {
+    DEFAULT_RETRY_INITIAL_BACKOFF_MS
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    DEFAULT_RETRY_MAX_BACKOFF_MS
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            initial_backoff_ms: DEFAULT_RETRY_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_RETRY_MAX_BACKOFF_MS,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay before retrying after the failed `attempt`, counted from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        let backoff = self.initial_backoff_ms.saturating_mul(factor);
+
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+
+    /// The maximum delay between two attempts.
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
+
+/// The limits of the pull scheduler, shared by all the image pulls.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// The maximum number of concurrent blob downloads.
+    #[serde(default = "default_max_downloads")]
+    pub max_downloads: usize,
+
+    /// The maximum number of concurrent blob downloads from one registry.
+    #[serde(default = "default_max_downloads_per_registry")]
+    pub max_downloads_per_registry: usize,
+
+    /// The maximum number of concurrent layer decrypt/unpack jobs. The
+    /// layers downloaded while none is free wait for one on disk, in their
+    /// partial files, so they don't hold back the other downloads.
+    #[serde(default = "default_max_unpacks")]
+    pub max_unpacks: usize,
+}
+
+fn default_max_downloads() -> usize {
+    DEFAULT_MAX_DOWNLOADS
+}
+
+fn default_max_downloads_per_registry() -> usize {
+    DEFAULT_MAX_DOWNLOADS_PER_REGISTRY
+}
+
+fn default_max_unpacks() -> usize {
+    DEFAULT_MAX_UNPACKS
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            max_downloads: DEFAULT_MAX_DOWNLOADS,
+            max_downloads_per_registry: DEFAULT_MAX_DOWNLOADS_PER_REGISTRY,
+            max_unpacks: DEFAULT_MAX_UNPACKS,
+        }
+    }
+}
+
+/// The limits on the data a pull may unpack or buffer, against layers
+/// expanding to fill the guest storage or memory. A pull exceeding a limit
+/// fails, and its partial layer is removed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct LimitsConfig {
+    /// The maximum uncompressed size of a layer, in bytes.
+    #[serde(default = "default_max_layer_bytes")]
+    pub max_layer_bytes: u64,
+
+    /// The maximum uncompressed size of the layers unpacked by an image
+    /// pull, in bytes. The layers already in the layer store do not count.
+    #[serde(default = "default_max_image_bytes")]
+    pub max_image_bytes: u64,
+
+    /// The maximum number of entries in a layer tarball.
+    #[serde(default = "default_max_layer_entries")]
+    pub max_layer_entries: u64,
+
+    /// The maximum number of components of a layer entry path.
+    #[serde(default = "default_max_path_depth")]
+    pub max_path_depth: usize,
+
+    /// The maximum length of a layer entry path or link target, in bytes.
+    #[serde(default = "default_max_path_length")]
+    pub max_path_length: usize,
+
+    /// The maximum size of an image manifest or index, in bytes.
+    #[serde(default = "default_max_manifest_bytes")]
+    pub max_manifest_bytes: u64,
+
+    /// The maximum size of an image config, in bytes.
+    #[serde(default = "default_max_config_bytes")]
+    pub max_config_bytes: u64,
+}
+
+fn default_max_layer_bytes() -> u64 {
+    DEFAULT_MAX_LAYER_BYTES
+}
+
+fn default_max_image_bytes() -> u64 {
+    DEFAULT_MAX_IMAGE_BYTES
+}
+
+fn default_max_layer_entries() -> u64 {
+    DEFAULT_MAX_LAYER_ENTRIES
+}
+
+fn default_max_path_depth() -> usize {
+    DEFAULT_MAX_PATH_DEPTH
+}
+
+fn default_max_path_length() -> usize {
+    DEFAULT_MAX_PATH_LENGTH
+}
+
+fn default_max_manifest_bytes() -> u64 {
+    DEFAULT_MAX_MANIFEST_BYTES
+}
+
+fn default_max_config_bytes() -> u64 {
+    DEFAULT_MAX_CONFIG_BYTES
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_layer_bytes: DEFAULT_MAX_LAYER_BYTES,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            max_layer_entries: DEFAULT_MAX_LAYER_ENTRIES,
+            max_path_depth: DEFAULT_MAX_PATH_DEPTH,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            max_manifest_bytes: DEFAULT_MAX_MANIFEST_BYTES,
+            max_config_bytes: DEFAULT_MAX_CONFIG_BYTES,
         }
     }
 }
@@ -71,6 +403,7 @@ mod tests {
 
         assert_eq!(config.work_dir, work_dir);
         assert_eq!(config.default_snapshot, SnapshotType::Overlay);
+        assert_eq!(config.default_registry, None);
 
         let env_work_dir = "/tmp";
         std::env::set_var(CC_IMAGE_WORK_DIR, env_work_dir);
@@ -79,12 +412,52 @@ mod tests {
         assert_eq!(config.work_dir, work_dir);
     }
 
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryConfig {
+            max_attempts: 10,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        };
+
+        assert_eq!(retry.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry.backoff(4), Duration::from_millis(800));
+        assert_eq!(retry.backoff(5), Duration::from_millis(1000));
+        assert_eq!(retry.backoff(100), Duration::from_millis(1000));
+    }
+
     #[test]
     fn test_image_config_from_file() {
         let data = r#"{
             "work_dir": "/var/lib/image-rs/",
             "default_snapshot": "overlay",
-            "security_validate": false
+            "security_validate": false,
+            "platform": "linux/arm64/v8",
+            "kbs_credentials": true,
+            "default_registry": "docker.io",
+            "registry_tls": {
+                "registry.local:5000": {
+                    "ca_file": "/etc/image-rs/ca.pem",
+                    "plain_http": false
+                },
+                "localhost:5000": {
+                    "plain_http": true
+                }
+            },
+            "retry": {
+                "max_attempts": 3
+            },
+            "scheduler": {
+                "max_unpacks": 4
+            },
+            "foreign_layers": {
+                "allowed_hosts": ["mcr.microsoft.com", "localhost:8080"]
+            },
+            "limits": {
+                "max_layer_bytes": 1048576
+            },
+            "compression_detection": "lenient"
         }"#;
 
         let tempdir = tempfile::tempdir().unwrap();
@@ -100,5 +473,51 @@ mod tests {
 
         assert_eq!(config.work_dir, work_dir);
         assert_eq!(config.default_snapshot, SnapshotType::Overlay);
+        assert_eq!(config.platform.as_deref(), Some("linux/arm64/v8"));
+        assert!(config.kbs_credentials);
+        assert_eq!(config.default_registry.as_deref(), Some("docker.io"));
+        assert_eq!(
+            config.registry_tls["registry.local:5000"].ca_file,
+            Some(PathBuf::from("/etc/image-rs/ca.pem"))
+        );
+        assert!(config.registry_tls["localhost:5000"].plain_http);
+        assert_eq!(config.retry.max_attempts, 3);
+        assert_eq!(
+            config.retry.initial_backoff_ms,
+            DEFAULT_RETRY_INITIAL_BACKOFF_MS
+        );
+        assert_eq!(config.scheduler.max_unpacks, 4);
+        assert_eq!(config.scheduler.max_downloads, DEFAULT_MAX_DOWNLOADS);
+        assert!(!config.foreign_layers.refuse);
+        assert_eq!(config.limits.max_layer_bytes, 1048576);
+        assert_eq!(config.limits.max_image_bytes, DEFAULT_MAX_IMAGE_BYTES);
+        assert_eq!(config.compression_detection, CompressionDetection::Lenient);
+    }
+
+    #[test]
+    fn test_foreign_layer_allows() {
+        let config = ForeignLayerConfig {
+            refuse: false,
+            allowed_hosts: vec![
+                "mcr.microsoft.com".to_string(),
+                "localhost:8080".to_string(),
+            ],
+        };
+
+        let tests = [
+            ("https://mcr.microsoft.com/v2/windows/blobs/sha256:1", true),
+            ("http://localhost:8080/layer.tar.gz", true),
+            ("https://mcr.microsoft.com:443/layer", true),
+            ("https://mcr.microsoft.com:8443/layer", false),
+            ("https://localhost/layer", false),
+            ("https://mcr.microsoft.com.evil.com/layer", false),
+            ("file:///mcr.microsoft.com/layer", false),
+            ("not a url", false),
+        ];
+        for (url, allowed) in tests.iter() {
+            assert_eq!(config.allows(url), *allowed, "{}", url);
+        }
+
+        assert!(!ForeignLayerConfig::default().allows("https://mcr.microsoft.com/layer"));
     }
 }
@@ -2,12 +2,28 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use bzip2;
 use flate2;
 use serde::Deserialize;
 use std::fmt;
-use std::io;
+use std::io::{self, Read};
+use xz2;
 use zstd;
 
+/// The magic bytes of the compression formats.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// The magic of POSIX and GNU tar headers, at offset 257 of the header.
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// The size of a tar block, a layer starts with a tar header or the
+/// zero-filled end-of-archive blocks when uncompressed.
+const TAR_BLOCK_SIZE: usize = 512;
+
 /// Represents the layer compression algorithm type,
 /// and allows to decompress corresponding compressed data.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
@@ -15,6 +31,8 @@ pub enum Compression {
     Uncompressed,
     Gzip,
     Zstd,
+    Bzip2,
+    Xz,
 }
 
 impl Default for Compression {
@@ -29,13 +47,90 @@ impl fmt::Display for Compression {
             Compression::Uncompressed => "uncompressed",
             Compression::Gzip => "gzip",
             Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+            Compression::Xz => "xz",
         };
 
         write!(f, "{}", output)
     }
 }
 
+/// How the compression of a layer is detected from its magic bytes, when they
+/// do not match the compression its media type declares.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionDetection {
+    /// A mismatch between the detected and declared compression is an
+    /// error, and so are layers of unknown media types.
+    #[default]
+    Strict,
+
+    /// The detected compression is used, and layers of unknown media types
+    /// are accepted when their compression is detected.
+    Lenient,
+}
+
+/// Detect the format of a layer from its first bytes, at least 512 bytes
+/// unless the layer is shorter. Uncompressed layers are detected by their
+/// tar header first, as the name of their first entry may start like a
+/// compression magic. It returns `None` when no format is recognized.
+pub fn detect_format(header: &[u8]) -> Option<Compression> {
+    let tar_magic = header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len());
+    let end_of_archive =
+        header.len() >= TAR_BLOCK_SIZE && header[..TAR_BLOCK_SIZE].iter().all(|b| *b == 0);
+    if tar_magic == Some(TAR_MAGIC) || end_of_archive {
+        return Some(Compression::Uncompressed);
+    }
+
+    let magics = [
+        (GZIP_MAGIC, Compression::Gzip),
+        (ZSTD_MAGIC, Compression::Zstd),
+        (XZ_MAGIC, Compression::Xz),
+    ];
+    if let Some((_, format)) = magics.iter().find(|(magic, _)| header.starts_with(magic)) {
+        return Some(*format);
+    }
+
+    // The bzip2 magic is followed by the block size, from '1' to '9'.
+    match header.get(BZIP2_MAGIC.len()) {
+        Some(b'1'..=b'9') if header.starts_with(BZIP2_MAGIC) => Some(Compression::Bzip2),
+        _ => None,
+    }
+}
+
+/// Read the first bytes of `input` to detect its format, as `detect_format`
+/// does. It returns the detected format, with a reader yielding the whole
+/// input again.
+pub fn sniff_format<'a, R>(mut input: R) -> io::Result<(Option<Compression>, Box<dyn Read + 'a>)>
+where
+    R: Read + 'a,
+{
+    let mut header = Vec::with_capacity(TAR_BLOCK_SIZE);
+    (&mut input)
+        .take(TAR_BLOCK_SIZE as u64)
+        .read_to_end(&mut header)?;
+
+    let format = detect_format(&header);
+    Ok((format, Box::new(io::Cursor::new(header).chain(input))))
+}
+
 impl Compression {
+    /// Wrap one `Read` with a decoder, which yields the decompressed data
+    /// as it is read, so that a layer never needs to be fully buffered.
+    /// Uncompressed data is passed through as is.
+    pub fn decoder<'a, R>(&self, input: R) -> io::Result<Box<dyn io::Read + 'a>>
+    where
+        R: io::Read + 'a,
+    {
+        match *self {
+            Self::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(input))),
+            Self::Zstd => Ok(Box::new(zstd::Decoder::new(input)?)),
+            Self::Bzip2 => Ok(Box::new(bzip2::read::BzDecoder::new(input))),
+            Self::Xz => Ok(Box::new(xz2::read::XzDecoder::new(input))),
+            Self::Uncompressed => Ok(Box::new(input)),
+        }
+    }
+
     /// Decompress input data from one `Read` and output data to one `Write`.
     /// Uncompressed data are not supported and an error will be returned.
     pub fn decompress<R, W>(&self, input: R, output: &mut W) -> std::io::Result<()>
@@ -43,44 +138,23 @@ impl Compression {
         R: io::Read,
         W: io::Write,
     {
-        match *self {
-            Self::Gzip => gzip_decode(input, output),
-            Self::Zstd => zstd_decode(input, output),
-            Self::Uncompressed => Err(io::Error::new(
+        if *self == Self::Uncompressed {
+            return Err(io::Error::new(
                 io::ErrorKind::Other,
                 "uncompressed input data".to_string(),
-            )),
+            ));
         }
-    }
-}
 
-// Decompress a gzip encoded data with flate2 crate.
-fn gzip_decode<R, W>(input: R, output: &mut W) -> std::io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut decoder = flate2::read::GzDecoder::new(input);
-    io::copy(&mut decoder, output)?;
-    Ok(())
-}
-
-// Decompress a zstd encoded data with zstd crate.
-fn zstd_decode<R, W>(input: R, output: &mut W) -> std::io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut decoder = zstd::Decoder::new(input)?;
-    io::copy(&mut decoder, output)?;
-    Ok(())
+        io::copy(&mut self.decoder(input)?, output)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use flate2::write::GzEncoder;
-    use std::io::Write;
+    use std::io::{Read, Write};
 
     #[test]
     fn test_uncompressed_decode() {
@@ -92,6 +166,17 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_uncompressed_decoder() {
+        let data: Vec<u8> = b"This is some text!".to_vec();
+        let mut output = Vec::new();
+
+        let compression = Compression::Uncompressed;
+        let mut decoder = compression.decoder(data.as_slice()).unwrap();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(data, output);
+    }
+
     #[test]
     fn test_gzip_decode() {
         let data: Vec<u8> = b"This is some text!".to_vec();
@@ -114,6 +199,61 @@ mod tests {
         assert_eq!(data, output);
     }
 
+    #[test]
+    fn test_detect_format() {
+        let data = b"This is some text!".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let zstd = zstd::encode_all(&data[..], 1).unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "file.txt", &data[..]).unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        // A tar whose first entry name starts like the bzip2 magic.
+        let mut bzh_tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        bzh_tar
+            .append_data(&mut header, "BZh9.txt", &data[..])
+            .unwrap();
+        let bzh_tar = bzh_tar.into_inner().unwrap();
+
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        encoder.write_all(&data).unwrap();
+        let bzip2 = encoder.finish().unwrap();
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+        encoder.write_all(&data).unwrap();
+        let xz = encoder.finish().unwrap();
+
+        let tests: [(&[u8], Option<Compression>); 10] = [
+            (&gzip, Some(Compression::Gzip)),
+            (&zstd, Some(Compression::Zstd)),
+            (&bzip2, Some(Compression::Bzip2)),
+            (&xz, Some(Compression::Xz)),
+            (&tar, Some(Compression::Uncompressed)),
+            (&bzh_tar, Some(Compression::Uncompressed)),
+            (b"BZhello", None),
+            (&[0u8; 1024], Some(Compression::Uncompressed)),
+            (&data, None),
+            (b"", None),
+        ];
+        for (input, format) in tests.iter() {
+            assert_eq!(detect_format(input), *format);
+
+            let (detected, mut reader) = sniff_format(*input).unwrap();
+            assert_eq!(detected, *format);
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, *input);
+        }
+    }
+
     #[test]
     fn test_zstd_decode() {
         let data: Vec<u8> = b"This is some text!".to_vec();
@@ -128,4 +268,47 @@ mod tests {
             .is_ok());
         assert_eq!(data, output);
     }
+
+    #[test]
+    fn test_bzip2_decode() {
+        let data: Vec<u8> = b"This is some text!".to_vec();
+
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let bytes = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        let compression = Compression::Bzip2;
+        assert!(compression
+            .decompress(bytes.as_slice(), &mut output)
+            .is_ok());
+        assert_eq!(data, output);
+
+        // A truncated stream is an error rather than partial data.
+        let mut output = Vec::new();
+        assert!(compression
+            .decompress(&bytes[..bytes.len() - 4], &mut output)
+            .is_err());
+    }
+
+    #[test]
+    fn test_xz_decode() {
+        let data: Vec<u8> = b"This is some text!".to_vec();
+
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(&data).unwrap();
+        let bytes = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        let compression = Compression::Xz;
+        assert!(compression
+            .decompress(bytes.as_slice(), &mut output)
+            .is_ok());
+        assert_eq!(data, output);
+
+        let mut output = Vec::new();
+        assert!(compression
+            .decompress(&bytes[..bytes.len() - 4], &mut output)
+            .is_err());
+    }
 }
@@ -3,7 +3,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{anyhow, Result};
-use ocicrypt_rs::config::CryptoConfig;
 use ocicrypt_rs::encryption::decrypt_layer;
 use ocicrypt_rs::helpers::create_decrypt_config;
 use ocicrypt_rs::spec::{
@@ -49,9 +48,13 @@ impl Decryptor {
         self.encrypted
     }
 
-    /// get_plaintext_layer descrypts encrypted_layer data and return the
-    /// plaintext_layer data. descriptor and decrypt_config are required for
-    /// layer data decryption process.
+    /// get_plaintext_layer wraps the encrypted_layer stream with a decryptor,
+    /// which yields the plaintext layer data as it is read. descriptor and
+    /// decrypt_config are required for layer data decryption process.
+    ///
+    /// ocicrypt-rs keyprovider module will create a new runtime to talk with
+    /// attestation agent, so this must not be called within an async runtime
+    /// context, e.g. run it with `tokio::task::spawn_blocking`.
     ///
     /// * `decrypt_config` - decryption key info in following format:\
     ///           - \<filename> \
@@ -60,12 +63,12 @@ impl Decryptor {
     ///           - \<filename>:fd=\<filedescriptor> \
     ///           - \<filename>:\<password> \
     ///           - provider:<cmd/gprc>
-    pub async fn get_plaintext_layer(
+    pub fn get_plaintext_layer<R: Read>(
         &self,
         descriptor: &OciDescriptor,
-        encrypted_layer: Vec<u8>,
+        encrypted_layer: R,
         decrypt_config: &str,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<impl Read> {
         if !self.is_encrypted() {
             return Err(anyhow!("unencrypted media type: {}", self.media_type));
         }
@@ -75,38 +78,13 @@ impl Decryptor {
         }
 
         let cc = create_decrypt_config(vec![decrypt_config.to_string()], vec![])?;
-        let descript = descriptor.clone();
-
-        // ocicrypt-rs keyprovider module will create a new runtime to talk with
-        // attestation agent, to avoid startup a runtime within a runtime, we
-        // spawn a new thread here.
-        let handler = tokio::task::spawn_blocking(move || {
-            decrypt_layer_data(&encrypted_layer, &descript, &cc)
-        });
+        if let Some(decrypt_config) = &cc.decrypt_config {
+            let (layer_decryptor, _dec_digest) =
+                decrypt_layer(decrypt_config, encrypted_layer, descriptor, false)?;
 
-        if let Ok(decrypted_data) = handler.await? {
-            Ok(decrypted_data)
+            layer_decryptor.ok_or_else(|| anyhow!("missing layer decryptor"))
         } else {
-            Err(anyhow!("decrypt failed!"))
+            Err(anyhow!("no decrypt config available"))
         }
     }
 }
-
-fn decrypt_layer_data(
-    encrypted_layer: &[u8],
-    descriptor: &OciDescriptor,
-    crypto_config: &CryptoConfig,
-) -> Result<Vec<u8>> {
-    if let Some(decrypt_config) = &crypto_config.decrypt_config {
-        let (layer_decryptor, _dec_digest) =
-            decrypt_layer(decrypt_config, encrypted_layer, descriptor, false)?;
-        let mut plaintext_data: Vec<u8> = Vec::new();
-        let mut decryptor = layer_decryptor.ok_or_else(|| anyhow!("missing layer decryptor"))?;
-
-        decryptor.read_to_end(&mut plaintext_data)?;
-
-        Ok(plaintext_data)
-    } else {
-        Err(anyhow!("no decrypt config available"))
-    }
-}
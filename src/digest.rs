@@ -0,0 +1,287 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use sha2::Digest;
+use std::io;
+
+use crate::errors::PullError;
+
+pub const DIGEST_SHA256: &str = "sha256";
+pub const DIGEST_SHA384: &str = "sha384";
+pub const DIGEST_SHA512: &str = "sha512";
+
+/// Incremental hasher for the digest algorithms used by image layers.
+#[derive(Clone)]
+pub enum LayerDigestHasher {
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+impl LayerDigestHasher {
+    /// Construct a hasher for the algorithm used by an expected digest
+    /// in "digest-algorithm:digest-value" format.
+    pub fn new(digest: &str) -> Result<Self> {
+        let (algorithm, _) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid digest format: {}", digest))?;
+
+        match algorithm {
+            DIGEST_SHA256 => Ok(Self::Sha256(sha2::Sha256::new())),
+            DIGEST_SHA384 => Ok(Self::Sha384(sha2::Sha384::new())),
+            DIGEST_SHA512 => Ok(Self::Sha512(sha2::Sha512::new())),
+            _ => Err(anyhow!("unsupported digest format: {}", digest)),
+        }
+    }
+
+    /// Feed data into the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha384(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Return the digest of the data hashed so far in
+    /// "digest-algorithm:digest-value" format.
+    pub fn digest_str(&self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{}:{:x}", DIGEST_SHA256, hasher.clone().finalize()),
+            Self::Sha384(hasher) => format!("{}:{:x}", DIGEST_SHA384, hasher.clone().finalize()),
+            Self::Sha512(hasher) => format!("{}:{:x}", DIGEST_SHA512, hasher.clone().finalize()),
+        }
+    }
+}
+
+/// Split a digest in "digest-algorithm:digest-value" format into its
+/// algorithm and hex encoded value. It is an error if the algorithm is not
+/// supported, or if the value is not a valid digest of the algorithm, so
+/// that the value is safe to use in a path.
+pub fn split_digest(digest: &str) -> Result<(&str, &str)> {
+    let (algorithm, encoded) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid digest format: {}", digest))?;
+    let encoded_len = match algorithm {
+        DIGEST_SHA256 => 64,
+        DIGEST_SHA384 => 96,
+        DIGEST_SHA512 => 128,
+        _ => return Err(anyhow!("unsupported digest format: {}", digest)),
+    };
+
+    if encoded.len() != encoded_len
+        || !encoded
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return Err(anyhow!("invalid digest format: {}", digest));
+    }
+
+    Ok((algorithm, encoded))
+}
+
+/// Compute the digest of `data` with the same algorithm as `digest`,
+/// or with sha256 if `digest` is `None`.
+pub fn digest_bytes(data: &[u8], digest: Option<&str>) -> Result<String> {
+    let mut hasher = match digest {
+        Some(digest) => LayerDigestHasher::new(digest)?,
+        None => LayerDigestHasher::Sha256(sha2::Sha256::new()),
+    };
+    hasher.update(data);
+
+    Ok(hasher.digest_str())
+}
+
+/// HashReader computes the digest of all the data read through it,
+/// so that content can be verified while it is streamed.
+pub struct HashReader<R> {
+    inner: R,
+    hasher: LayerDigestHasher,
+}
+
+impl<R> HashReader<R> {
+    /// Wrap `inner` with a hasher using the same algorithm as `digest`.
+    pub fn new(inner: R, digest: &str) -> Result<Self> {
+        Ok(HashReader {
+            inner,
+            hasher: LayerDigestHasher::new(digest)?,
+        })
+    }
+
+    /// Return the digest of the data read so far.
+    pub fn digest_str(&self) -> String {
+        self.hasher.digest_str()
+    }
+}
+
+impl<R: io::Read> io::Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// VerifyReader checks a blob against the digest and size declared by its
+/// descriptor while it is read. Reading fails as soon as more data than the
+/// declared size arrives, and `verify` checks the blob once fully read.
+pub struct VerifyReader<R> {
+    inner: R,
+    hasher: LayerDigestHasher,
+    digest: String,
+    size: u64,
+    read_size: u64,
+}
+
+impl<R> VerifyReader<R> {
+    /// Wrap `inner` to verify it against the blob `digest` and `size`.
+    pub fn new(inner: R, digest: &str, size: u64) -> Result<Self> {
+        Ok(VerifyReader {
+            inner,
+            hasher: LayerDigestHasher::new(digest)?,
+            digest: digest.to_string(),
+            size,
+            read_size: 0,
+        })
+    }
+
+    /// Return an error if more data than the declared size was read.
+    pub fn check_size(&self) -> std::result::Result<(), PullError> {
+        if self.read_size > self.size {
+            return Err(PullError::BlobTooLarge {
+                layer: self.digest.clone(),
+                expected: self.size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Verify the size and digest of the data read.
+    pub fn verify(&self) -> std::result::Result<(), PullError> {
+        self.check_size()?;
+        if self.read_size != self.size {
+            return Err(PullError::BlobSizeMismatch {
+                layer: self.digest.clone(),
+                expected: self.size,
+                actual: self.read_size,
+            });
+        }
+
+        let digest = self.hasher.digest_str();
+        if digest != self.digest {
+            return Err(PullError::BlobDigestMismatch {
+                layer: self.digest.clone(),
+                expected: self.digest.clone(),
+                actual: digest,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: io::Read> io::Read for VerifyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_size()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Never read more than one byte past the declared size, so that an
+        // oversized blob is detected without buffering it.
+        let limit = (self.size - self.read_size)
+            .saturating_add(1)
+            .min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.read_size += n as u64;
+        self.check_size()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_hash_reader() {
+        let data = b"This is some text!";
+        let sha256 = format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(data));
+        let sha384 = format!("{}:{:x}", DIGEST_SHA384, sha2::Sha384::digest(data));
+        let sha512 = format!("{}:{:x}", DIGEST_SHA512, sha2::Sha512::digest(data));
+
+        for expected in [sha256, sha384, sha512].iter() {
+            let mut reader = HashReader::new(&data[..], expected).unwrap();
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+
+            assert_eq!(&reader.digest_str(), expected);
+            assert_eq!(output, data);
+        }
+
+        assert!(HashReader::new(&data[..], "md5:0000").is_err());
+        assert!(HashReader::new(&data[..], "sha256").is_err());
+    }
+
+    #[test]
+    fn test_digest_bytes() {
+        let data = b"This is some text!";
+        let sha256 = format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(data));
+        let sha512 = format!("{}:{:x}", DIGEST_SHA512, sha2::Sha512::digest(data));
+
+        assert_eq!(digest_bytes(data, None).unwrap(), sha256);
+        assert_eq!(digest_bytes(data, Some(&sha256)).unwrap(), sha256);
+        assert_eq!(digest_bytes(data, Some("sha512:ffff")).unwrap(), sha512);
+        assert!(digest_bytes(data, Some("md5:ffff")).is_err());
+    }
+
+    #[test]
+    fn test_verify_reader() {
+        let data = b"This is some text!";
+        let digest = format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(data));
+        let size = data.len() as u64;
+
+        let mut reader = VerifyReader::new(&data[..], &digest, size).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert!(reader.verify().is_ok());
+        assert_eq!(output, data);
+
+        // Wrong digest
+        let other = format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(b"other"));
+        let mut reader = VerifyReader::new(&data[..], &other, size).unwrap();
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert!(matches!(
+            reader.verify(),
+            Err(PullError::BlobDigestMismatch { .. })
+        ));
+
+        // Truncated blob
+        let mut reader = VerifyReader::new(&data[..], &digest, size + 1).unwrap();
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(
+            reader.verify(),
+            Err(PullError::BlobSizeMismatch {
+                layer: digest.clone(),
+                expected: size + 1,
+                actual: size,
+            })
+        );
+
+        // Oversized blob fails while reading
+        let mut reader = VerifyReader::new(&data[..], &digest, size - 1).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert_eq!(
+            reader.check_size(),
+            Err(PullError::BlobTooLarge {
+                layer: digest,
+                expected: size - 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::archive::read_limited;
+use crate::config::LimitsConfig;
+use crate::digest::{digest_bytes, split_digest};
+use crate::errors::Limit;
+
+/// The prefix of the image references pointing to an image directory.
+pub const DIR_PREFIX: &str = "dir:";
+
+/// The files of an image directory.
+const MANIFEST_FILE: &str = "manifest.json";
+const VERSION_FILE: &str = "version";
+
+/// The supported versions of the image directory format.
+const DIR_VERSION_PREFIX: &str = "Directory Transport Version: 1.";
+
+/// An image stored in a directory, as written by the `dir:` transport of
+/// containers/image: the image manifest is in `manifest.json`, the manifests
+/// of an image index in `<digest>.manifest.json`, and every blob in a file
+/// named after its digest, without the algorithm.
+pub struct DirImage {
+    path: PathBuf,
+    manifest_digest: String,
+    max_manifest_bytes: u64,
+}
+
+impl DirImage {
+    /// Open the image directory `path`. The manifests are read within the
+    /// manifest size limit of `limits`.
+    pub fn open(path: &Path, limits: &LimitsConfig) -> Result<Self> {
+        let version = File::open(path.join(VERSION_FILE))
+            .map_err(|e| anyhow!("{} is not an image directory: {}", path.display(), e))?;
+        let version = read_limited(version, Limit::ManifestBytes, limits.max_manifest_bytes)?;
+        let version = String::from_utf8_lossy(&version);
+        if !version.starts_with(DIR_VERSION_PREFIX) {
+            return Err(anyhow!(
+                "unsupported image directory version {:?} in {}",
+                version.trim(),
+                path.display()
+            ));
+        }
+
+        let mut image = DirImage {
+            path: path.to_path_buf(),
+            manifest_digest: String::new(),
+            max_manifest_bytes: limits.max_manifest_bytes,
+        };
+        image.manifest_digest = digest_bytes(&image.read_manifest(None)?, None)?;
+
+        Ok(image)
+    }
+
+    /// The image directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The digest of the image manifest or index of the directory.
+    pub fn manifest_digest(&self) -> &str {
+        &self.manifest_digest
+    }
+
+    /// Read the image manifest or index, or with `digest` one of the image
+    /// manifests of the index, within the manifest size limit.
+    pub fn read_manifest(&self, digest: Option<&str>) -> Result<Vec<u8>> {
+        let name = match digest {
+            Some(digest) => format!("{}.{}", split_digest(digest)?.1, MANIFEST_FILE),
+            None => MANIFEST_FILE.to_string(),
+        };
+
+        let file = File::open(self.path.join(&name))
+            .map_err(|e| anyhow!("failed to read {} of {}: {}", name, self.path.display(), e))?;
+        read_limited(file, Limit::ManifestBytes, self.max_manifest_bytes)
+    }
+
+    /// Write the blob `digest` from `offset` into `out`.
+    pub async fn copy_blob<T: AsyncWrite + Unpin>(
+        &self,
+        digest: &str,
+        offset: u64,
+        mut out: T,
+    ) -> Result<()> {
+        let mut file = tokio::fs::File::open(self.blob_path(digest)?).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        tokio::io::copy(&mut file, &mut out).await?;
+        out.flush().await?;
+
+        Ok(())
+    }
+
+    // The path of the blob `digest`, it is an error if the digest is not
+    // a valid one or the blob is not in the directory.
+    fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        // The digest becomes a path, it must not be able to escape the
+        // directory.
+        let path = self.path.join(split_digest(digest)?.1);
+        if !path.is_file() {
+            return Err(anyhow!(
+                "blob {} not found in {}",
+                digest,
+                self.path.display()
+            ));
+        }
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_dir_image() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DirImage::open(dir.path(), &LimitsConfig::default()).is_err());
+
+        fs::write(
+            dir.path().join(VERSION_FILE),
+            "Directory Transport Version: 2.0\n",
+        )
+        .unwrap();
+        assert!(DirImage::open(dir.path(), &LimitsConfig::default()).is_err());
+
+        let manifest = br#"{"schemaVersion":2}"#;
+        let blob = b"blob data";
+        let blob_digest = digest_bytes(blob, None).unwrap();
+        fs::write(
+            dir.path().join(VERSION_FILE),
+            "Directory Transport Version: 1.1\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join(MANIFEST_FILE), manifest).unwrap();
+        fs::write(dir.path().join(split_digest(&blob_digest).unwrap().1), blob).unwrap();
+
+        let image = DirImage::open(dir.path(), &LimitsConfig::default()).unwrap();
+        assert_eq!(
+            image.manifest_digest(),
+            digest_bytes(manifest, None).unwrap()
+        );
+        assert_eq!(image.read_manifest(None).unwrap(), manifest);
+        assert!(image.read_manifest(Some(&blob_digest)).is_err());
+
+        let mut data = Vec::new();
+        image.copy_blob(&blob_digest, 5, &mut data).await.unwrap();
+        assert_eq!(data, b"data");
+
+        let missing = digest_bytes(b"missing", None).unwrap();
+        assert!(image.copy_blob(&missing, 0, &mut data).await.is_err());
+        assert!(image
+            .copy_blob("sha256:../manifest.json", 0, &mut data)
+            .await
+            .is_err());
+
+        let limits = LimitsConfig {
+            max_manifest_bytes: 8,
+            ..Default::default()
+        };
+        assert!(DirImage::open(dir.path(), &limits).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Errors of pulling image content which callers may need to tell apart,
+/// they are returned wrapped in `anyhow::Error` and can be downcasted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PullError {
+    /// The blob digest does not match the layer descriptor.
+    BlobDigestMismatch {
+        layer: String,
+        expected: String,
+        actual: String,
+    },
+    /// The blob size does not match the layer descriptor.
+    BlobSizeMismatch {
+        layer: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// More data than the layer descriptor declares was received.
+    BlobTooLarge { layer: String, expected: u64 },
+    /// The manifest digest does not match the digest it was pulled by.
+    ManifestDigestMismatch {
+        reference: String,
+        expected: String,
+        actual: String,
+    },
+    /// The image config digest does not match the image manifest.
+    ConfigDigestMismatch {
+        reference: String,
+        expected: String,
+        actual: String,
+    },
+    /// The blob read from the blob cache does not match its descriptor,
+    /// it changed since it was checked.
+    CachedBlobMismatch { digest: String },
+    /// The pull was cancelled.
+    Cancelled { reference: String },
+    /// The pull did not complete within its timeout.
+    Timeout {
+        reference: String,
+        timeout: Duration,
+    },
+    /// The image is not in the local store, and the pull policy forbids
+    /// pulling it.
+    NotPresent { reference: String },
+    /// The layer is a foreign layer, and foreign layers are refused.
+    ForeignLayerRefused { layer: String },
+    /// The layer magic bytes do not match the compression of its media type,
+    /// or its format is not supported.
+    CompressionMismatch {
+        layer: String,
+        media_type: String,
+        detected: String,
+    },
+    /// A resource limit of the pull was exceeded.
+    LimitExceeded { limit: Limit, max: u64 },
+}
+
+/// The resource limits of a pull, named after their `LimitsConfig` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    LayerBytes,
+    ImageBytes,
+    LayerEntries,
+    PathDepth,
+    PathLength,
+    ManifestBytes,
+    ConfigBytes,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Limit::LayerBytes => "max_layer_bytes",
+            Limit::ImageBytes => "max_image_bytes",
+            Limit::LayerEntries => "max_layer_entries",
+            Limit::PathDepth => "max_path_depth",
+            Limit::PathLength => "max_path_length",
+            Limit::ManifestBytes => "max_manifest_bytes",
+            Limit::ConfigBytes => "max_config_bytes",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for PullError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PullError::BlobDigestMismatch {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "layer {} blob digest mismatch: expected {}, got {}",
+                layer, expected, actual
+            ),
+            PullError::BlobSizeMismatch {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "layer {} blob size mismatch: expected {} bytes, got {} bytes",
+                layer, expected, actual
+            ),
+            PullError::BlobTooLarge { layer, expected } => write!(
+                f,
+                "layer {} blob exceeds the declared size of {} bytes",
+                layer, expected
+            ),
+            PullError::ManifestDigestMismatch {
+                reference,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "image {} manifest digest mismatch: expected {}, got {}",
+                reference, expected, actual
+            ),
+            PullError::ConfigDigestMismatch {
+                reference,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "image {} config digest mismatch: expected {}, got {}",
+                reference, expected, actual
+            ),
+            PullError::CachedBlobMismatch { digest } => {
+                write!(f, "cached blob {} does not match its descriptor", digest)
+            }
+            PullError::Cancelled { reference } => write!(f, "pull image {} cancelled", reference),
+            PullError::Timeout { reference, timeout } => write!(
+                f,
+                "pull image {} did not complete within {:?}",
+                reference, timeout
+            ),
+            PullError::NotPresent { reference } => write!(
+                f,
+                "image {} is not present locally and the pull policy is Never",
+                reference
+            ),
+            PullError::ForeignLayerRefused { layer } => {
+                write!(f, "layer {} is a foreign layer, which is refused", layer)
+            }
+            PullError::CompressionMismatch {
+                layer,
+                media_type,
+                detected,
+            } => write!(
+                f,
+                "layer {} of media type {} is {} data",
+                layer, media_type, detected
+            ),
+            PullError::LimitExceeded { limit, max } => {
+                write!(f, "pull exceeds the {} limit of {}", limit, max)
+            }
+        }
+    }
+}
+
+impl Error for PullError {}
@@ -3,22 +3,39 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::{anyhow, Result};
-use oci_spec::image::{ImageConfiguration, Os};
+use oci_distribution::manifest::OciImageManifest;
+use oci_distribution::Reference;
+use oci_spec::image::{History, ImageConfiguration, Os};
 use serde::Deserialize;
+use signature::TransportName;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
+use crate::auth::{Credential, DockerConfig};
+use crate::blob_cache::BlobCache;
 use crate::bundle::{create_runtime_config, BUNDLE_ROOTFS};
 use crate::config::ImageConfig;
 use crate::decoder::Compression;
+use crate::decrypt::Decryptor;
+use crate::digest::digest_bytes;
+use crate::errors::PullError;
 use crate::meta_store::{MetaStore, METAFILE};
+use crate::platform::Platform;
+use crate::progress::{ProgressReporter, PullProgress, PullStage};
 use crate::pull::PullClient;
+use crate::registry::config::{RegistriesConfig, ShortNameMode};
+use crate::scheduler::PullScheduler;
 use crate::snapshots::overlay::OverLay;
 use crate::snapshots::{SnapshotType, Snapshotter};
+use crate::source::{open_local, parse_transport};
 use crate::validate::security_validate;
 
 /// The metadata info for container image layer.
@@ -38,6 +55,32 @@ pub struct LayerMeta {
 
     /// The image layer storage path.
     pub store_path: String,
+
+    /// The digest of the decrypt config an encrypted layer was decrypted
+    /// with, rather than the config itself which may hold a passphrase.
+    #[serde(default)]
+    pub decrypt_config_digest: Option<String>,
+}
+
+impl LayerMeta {
+    /// Check that the stored layer may be reused with `decrypt_config`: an
+    /// encrypted layer is only reused with the decrypt config which
+    /// decrypted it, the stored data proves nothing about another key.
+    pub fn check_decrypt_config(&self, decrypt_config: &Option<&str>) -> Result<()> {
+        if !self.encrypted {
+            return Ok(());
+        }
+
+        let dc = decrypt_config.ok_or_else(|| anyhow!("decrypt_config is None"))?;
+        if self.decrypt_config_digest.as_deref() != Some(&digest_bytes(dc.as_bytes(), None)?) {
+            return Err(anyhow!(
+                "layer {} was decrypted with another decrypt_config",
+                self.compressed_digest
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 /// The metadata info for container image.
@@ -55,6 +98,11 @@ pub struct ImageMeta {
     /// The image configuration.
     pub image_config: ImageConfiguration,
 
+    /// The platform of the image, the default one for the images stored
+    /// before it was recorded.
+    #[serde(default)]
+    pub platform: Platform,
+
     /// Whether image is signed.
     pub signed: bool,
 
@@ -62,6 +110,113 @@ pub struct ImageMeta {
     pub layer_metas: Vec<LayerMeta>,
 }
 
+/// The description of an image, resolved without pulling its layers.
+#[derive(Clone, Debug)]
+pub struct ImageInspect {
+    /// The resolved image reference.
+    pub reference: String,
+
+    /// The digest of the image manifest.
+    pub digest: String,
+
+    /// The digest of the image configuration, the image ID once pulled.
+    pub id: String,
+
+    /// The media type of the image manifest, if the manifest declares it.
+    pub media_type: Option<String>,
+
+    /// The platform of the image.
+    pub platform: Platform,
+
+    /// The image layers, from the base layer up.
+    pub layers: Vec<LayerInspect>,
+
+    /// The labels of the image configuration.
+    pub labels: HashMap<String, String>,
+
+    /// The history of the image layers.
+    pub history: Vec<History>,
+
+    /// The image configuration.
+    pub image_config: ImageConfiguration,
+
+    /// The signature policy decision, if the policy was evaluated.
+    pub policy: Option<PolicyDecision>,
+}
+
+/// The description of an image layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerInspect {
+    /// The digest of the layer blob.
+    pub digest: String,
+
+    /// The digest of the uncompressed layer, from the image configuration.
+    pub diff_id: Option<String>,
+
+    /// The media type of the layer blob.
+    pub media_type: String,
+
+    /// The size of the layer blob.
+    pub size: i64,
+
+    /// Whether the layer is encrypted.
+    pub encrypted: bool,
+}
+
+/// The decision of a signature policy dry run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyDecision {
+    /// Whether the policy allows the image.
+    pub allowed: bool,
+
+    /// Why the image is refused.
+    pub reason: Option<String>,
+}
+
+/// The pull policy of an image, deciding whether the image is pulled or
+/// taken from the local store, like the Kubernetes image pull policies.
+///
+/// The local store maps each image reference to the image it was last
+/// pulled as. A tag reference maps to one image at most, and moves to
+/// another image when an `Always` pull finds the tag now resolves to it.
+/// A digest reference maps to the image with that manifest digest pulled
+/// from the same repository, whatever tag it was pulled by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PullPolicy {
+    /// Always resolve the image manifest from the image source. The layers
+    /// already in the local store are reused.
+    #[default]
+    Always,
+
+    /// Use the image of the local store the reference maps to, and pull
+    /// the image only if there is none. No registry is contacted for
+    /// images of the local store.
+    IfNotPresent,
+
+    /// Only use the image of the local store the reference maps to, the
+    /// pull fails if there is none.
+    Never,
+}
+
+/// The options of an image pull.
+#[derive(Clone, Debug, Default)]
+pub struct PullOptions {
+    /// The channel to send the pull progress events to.
+    pub progress: Option<UnboundedSender<PullProgress>>,
+
+    /// The token to cancel the pull with.
+    pub cancel: Option<CancellationToken>,
+
+    /// The deadline of the whole pull, counted from its start.
+    pub timeout: Option<Duration>,
+
+    /// The timeout of each registry request.
+    pub request_timeout: Option<Duration>,
+
+    /// Whether the image is pulled or taken from the local store.
+    pub pull_policy: PullPolicy,
+}
+
 /// The`image-rs` client will support OCI image
 /// pulling, image signing verfication, image layer
 /// decryption/unpack/store and management.
@@ -74,6 +229,18 @@ pub struct ImageClient {
 
     /// The supported snapshots for `image-rs` client.
     pub snapshots: HashMap<SnapshotType, Box<dyn Snapshotter>>,
+
+    /// The scheduler of layer downloads and unpacking, shared by all the
+    /// image pulls of the client.
+    pub scheduler: Arc<PullScheduler>,
+
+    /// The untrusted blob cache of the host, shared by all the image pulls
+    /// of the client, if one is configured.
+    pub blob_cache: Option<Arc<BlobCache>>,
+
+    /// The registry credentials fetched from the KBS, they are kept in
+    /// memory only and live as long as the client.
+    kbs_credentials: Option<DockerConfig>,
 }
 
 impl Default for ImageClient {
@@ -99,9 +266,15 @@ impl Default for ImageClient {
         );
 
         ImageClient {
+            scheduler: Arc::new(PullScheduler::new(&config.scheduler)),
+            blob_cache: config
+                .blob_cache_dir
+                .as_ref()
+                .map(|dir| Arc::new(BlobCache::new(dir))),
             config,
             meta_store: Arc::new(Mutex::new(meta_store)),
             snapshots,
+            kbs_credentials: None,
         }
     }
 }
@@ -111,6 +284,11 @@ impl ImageClient {
     /// and store the pulled data under user defined work_dir/layers.
     /// It will return the image ID with prepeared bundle: a rootfs directory,
     /// and config.json will be ready in the bundle_dir passed by user.
+    ///
+    /// The image_url may start with a transport prefix: `docker://` for
+    /// a registry, `oci:` or `oci-archive:` for an OCI image layout,
+    /// `docker-archive:` for a `docker save` archive, or `dir:` for an
+    /// image directory. Without a prefix, the image is pulled from a registry.
     pub async fn pull_image(
         &mut self,
         image_url: &str,
@@ -118,34 +296,179 @@ impl ImageClient {
         auth_info: &Option<&str>,
         decrypt_config: &Option<&str>,
     ) -> Result<String> {
-        let mut client =
-            PullClient::new(image_url, &self.config.work_dir.join("layers"), auth_info)?;
-        let (image_manifest, image_digest, image_config) = client.pull_manifest().await?;
+        self.pull_image_with_options(
+            image_url,
+            bundle_dir,
+            auth_info,
+            decrypt_config,
+            &PullOptions::default(),
+        )
+        .await
+    }
 
-        let id = image_manifest.config.digest.clone();
-        if self.meta_store.lock().await.image_db.contains_key(&id) {
-            return Ok(id);
+    /// pull_image_with_options pulls an image like `pull_image`, with the
+    /// pull options to follow its progress, cancel it or bound its duration.
+    ///
+    /// The pull policy decides whether the image is taken from the local
+    /// store instead. Images of the local store were checked against the
+    /// signature policy when they were pulled, they are mounted in the
+    /// bundle without contacting any registry. Like for a pull, the images
+    /// with encrypted layers are only mounted with a decrypt config.
+    ///
+    /// A cancelled or timed out pull stops its downloads and unpacking, and
+    /// removes its layers, but those another pull is waiting for. No layer
+    /// or image is recorded in the `MetaStore` and nothing is mounted in the
+    /// bundle.
+    pub async fn pull_image_with_options(
+        &mut self,
+        image_url: &str,
+        bundle_dir: &Path,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        options: &PullOptions,
+    ) -> Result<String> {
+        let cancel = options
+            .cancel
+            .as_ref()
+            .map(|cancel| cancel.child_token())
+            .unwrap_or_default();
+        let deadline = async {
+            match options.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let pull = self.do_pull_image(
+            image_url,
+            bundle_dir,
+            auth_info,
+            decrypt_config,
+            options,
+            &cancel,
+        );
+        tokio::pin!(pull);
+        tokio::select! {
+            res = &mut pull => res,
+            _ = deadline => {
+                // Let the pull clean up after itself before returning.
+                cancel.cancel();
+                pull.await.map_err(|e| match e.downcast_ref::<PullError>() {
+                    Some(PullError::Cancelled { .. }) => PullError::Timeout {
+                        reference: image_url.to_string(),
+                        timeout: options.timeout.unwrap_or_default(),
+                    }
+                    .into(),
+                    _ => e,
+                })
+            }
         }
+    }
 
-        if self.config.security_validate {
-            if let Some(wrapped_aa_kbc_params) = decrypt_config {
-                let wrapped_aa_kbc_params = wrapped_aa_kbc_params.to_string();
-                let aa_kbc_params =
-                    wrapped_aa_kbc_params.trim_start_matches("provider:attestation-agent:");
+    async fn do_pull_image(
+        &mut self,
+        image_url: &str,
+        bundle_dir: &Path,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        options: &PullOptions,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let progress = ProgressReporter::new(options.progress.clone());
+        if options.pull_policy != PullPolicy::Always {
+            if let Some(image_data) = self.local_image(image_url).await? {
+                check_decrypt_config(&image_data, decrypt_config)?;
+                self.mount_image(&image_data, bundle_dir)?;
+                progress.report(&image_data.digest, PullStage::Mounted);
+                return Ok(image_data.id);
+            }
 
-                security_validate(image_url, &image_digest, aa_kbc_params)
-                    .await
-                    .map_err(|e| anyhow!("Security validate failed: {:?}", e))?;
+            if options.pull_policy == PullPolicy::Never {
+                return Err(PullError::NotPresent {
+                    reference: image_url.to_string(),
+                }
+                .into());
+            }
+        }
+
+        let pulled = self
+            .pull_manifest(
+                image_url,
+                auth_info,
+                decrypt_config,
+                options,
+                &progress,
+                cancel,
+            )
+            .await?;
+        let (image_url, client, (image_manifest, image_digest, image_config)) = pulled;
+
+        progress.report(
+            &image_digest,
+            PullStage::ManifestResolved {
+                reference: image_url.clone(),
+                layers: image_manifest.layers.len(),
+            },
+        );
+
+        if self.config.security_validate {
+            if let Some(aa_kbc_params) = aa_kbc_params(decrypt_config) {
+                let res = validate_image(&client, &image_url, &image_digest, aa_kbc_params).await;
+                progress.report(
+                    &image_digest,
+                    PullStage::Validated {
+                        allowed: res.is_ok(),
+                    },
+                );
+                res.map_err(|e| anyhow!("Security validate failed: {:?}", e))?;
             } else {
                 return Err(anyhow!("Security validation need aa_kbc_params."));
             }
         }
 
+        // The image may be in the local store already, pulled by another
+        // reference or before its tag moved.
+        let id = image_manifest.config.digest.clone();
+        let refs = reference_keys(&client, &image_url, &image_digest)?;
+        let cached = {
+            let mut ms = self.meta_store.lock().await;
+            let cached = ms.image_db.get(&id).cloned();
+            if cached.is_some() {
+                for key in refs.iter() {
+                    ms.image_refs.insert(key.clone(), id.clone());
+                }
+            }
+            cached
+        };
+        if let Some(image_data) = cached {
+            check_decrypt_config(&image_data, decrypt_config)?;
+            self.mount_image(&image_data, bundle_dir)?;
+            progress.report(&image_data.digest, PullStage::Mounted);
+            return Ok(id);
+        }
+
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes())?;
+        if image_config.os() != &Os::Linux {
+            return Err(anyhow!("unsupport OS image {:?}", image_config.os()));
+        }
+
+        // The image configuration is authoritative for the image platform,
+        // check it before any layer is downloaded.
+        let platform = Platform::from(&image_config);
+        if !client.platform.matches(&platform) {
+            return Err(anyhow!(
+                "image platform {} does not match requested platform {}",
+                platform,
+                client.platform
+            ));
+        }
+
         let mut image_data = ImageMeta {
             id,
             digest: image_digest,
-            reference: image_url.to_string(),
-            image_config: ImageConfiguration::from_reader(image_config.as_bytes())?,
+            reference: image_url,
+            image_config,
+            platform,
             ..Default::default()
         };
 
@@ -166,14 +489,29 @@ impl ImageClient {
             .await?;
 
         image_data.layer_metas = layer_metas;
-        let layer_db: HashMap<String, LayerMeta> = image_data
-            .layer_metas
-            .iter()
-            .map(|layer| (layer.compressed_digest.clone(), layer.clone()))
-            .collect();
+        if cancel.is_cancelled() {
+            return Err(PullError::Cancelled {
+                reference: image_data.reference,
+            }
+            .into());
+        }
+
+        self.mount_image(&image_data, bundle_dir)?;
+        progress.report(&image_data.digest, PullStage::Mounted);
+
+        let image_id = image_data.id.clone();
+        let mut ms = self.meta_store.lock().await;
+        for key in refs {
+            ms.image_refs.insert(key, image_id.clone());
+        }
+        ms.image_db.insert(image_data.id.clone(), image_data);
 
-        self.meta_store.lock().await.layer_db.extend(layer_db);
+        Ok(image_id)
+    }
 
+    // Mount the image layers in the bundle rootfs, and create the bundle
+    // runtime config.
+    fn mount_image(&mut self, image_data: &ImageMeta, bundle_dir: &Path) -> Result<()> {
         let layer_path = image_data
             .layer_metas
             .iter()
@@ -190,125 +528,1199 @@ impl ImageClient {
             ));
         }
 
-        let image_config = image_data.image_config.clone();
-        if image_config.os() != &Os::Linux {
-            return Err(anyhow!("unsupport OS image {:?}", image_config.os()));
+        create_runtime_config(&image_data.image_config, bundle_dir)?;
+
+        Ok(())
+    }
+
+    // Find the image of the local store `image_url` maps to. Short image
+    // names are resolved like they are for a pull, and the first candidate
+    // in the local store wins.
+    async fn local_image(&self, image_url: &str) -> Result<Option<ImageMeta>> {
+        let keys = match parse_transport(image_url) {
+            (TransportName::Docker, image) => {
+                let registries = match &self.config.registries_config {
+                    Some(path) => Some(RegistriesConfig::from_file(path)?),
+                    None => None,
+                };
+                let candidates = self.resolve_image(&registries, image)?;
+                candidates.iter().map(reference_key).collect()
+            }
+            _ => vec![image_url.to_string()],
+        };
+
+        let ms = self.meta_store.lock().await;
+        Ok(keys
+            .iter()
+            .find_map(|key| ms.image_refs.get(key))
+            .and_then(|id| ms.image_db.get(id))
+            .cloned())
+    }
+
+    /// inspect_remote resolves an image reference and describes the image,
+    /// from its manifest and config, without downloading or decrypting any
+    /// of its layers. The image is resolved like `pull_image` does.
+    ///
+    /// With `evaluate_policy`, the image is also checked against the
+    /// signature policy as a dry run: the decision is returned in the
+    /// description rather than failing the inspection.
+    pub async fn inspect_remote(
+        &mut self,
+        image_url: &str,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        evaluate_policy: bool,
+    ) -> Result<ImageInspect> {
+        let (reference, client, (image_manifest, digest, image_config)) = self
+            .pull_manifest(
+                image_url,
+                auth_info,
+                decrypt_config,
+                &PullOptions::default(),
+                &ProgressReporter::default(),
+                &CancellationToken::new(),
+            )
+            .await?;
+
+        let policy = if evaluate_policy {
+            let res = match aa_kbc_params(decrypt_config) {
+                Some(aa_kbc_params) => {
+                    validate_image(&client, &reference, &digest, aa_kbc_params).await
+                }
+                None => Err(anyhow!("Security validation need aa_kbc_params.")),
+            };
+            Some(PolicyDecision {
+                allowed: res.is_ok(),
+                reason: res.err().map(|e| format!("{:?}", e)),
+            })
+        } else {
+            None
+        };
+
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes())?;
+        let diff_ids = image_config.rootfs().diff_ids();
+        let layers = image_manifest
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| LayerInspect {
+                digest: layer.digest.clone(),
+                diff_id: diff_ids.get(i).cloned(),
+                media_type: layer.media_type.clone(),
+                size: layer.size,
+                encrypted: Decryptor::from_media_type(&layer.media_type).is_encrypted(),
+            })
+            .collect();
+
+        Ok(ImageInspect {
+            reference,
+            digest,
+            id: image_manifest.config.digest.clone(),
+            media_type: image_manifest.media_type.clone(),
+            platform: Platform::from(&image_config),
+            layers,
+            labels: image_config.labels_of_config().cloned().unwrap_or_default(),
+            history: image_config.history().clone(),
+            image_config,
+            policy,
+        })
+    }
+
+    // Pull the image manifest and config of `image_url`, from the image
+    // source its transport prefix selects.
+    async fn pull_manifest(
+        &mut self,
+        image_url: &str,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        options: &PullOptions,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(String, PullClient, (OciImageManifest, String, String))> {
+        match parse_transport(image_url) {
+            (TransportName::Docker, image) => {
+                self.pull_registry_manifest(
+                    image,
+                    auth_info,
+                    decrypt_config,
+                    options,
+                    progress,
+                    cancel,
+                )
+                .await
+            }
+            _ => self.pull_local_manifest(image_url, progress, cancel).await,
         }
+    }
 
-        create_runtime_config(&image_config, bundle_dir)?;
-        let image_id = image_data.id.clone();
-        self.meta_store
-            .lock()
-            .await
-            .image_db
-            .insert(image_data.id.clone(), image_data);
+    // Pull the image manifest and config from a local image source, an OCI
+    // image layout, an image archive or an image directory.
+    async fn pull_local_manifest(
+        &self,
+        image_url: &str,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(String, PullClient, (OciImageManifest, String, String))> {
+        let local = open_local(image_url, &self.config.limits)?;
+        let mut client = PullClient::from_local(local, &self.config.work_dir.join("layers"))?;
+        client.scheduler = self.scheduler.clone();
+        client.progress = progress.clone();
+        client.cancel = cancel.clone();
+        client.foreign_layers = self.config.foreign_layers.clone();
+        client.limits = self.config.limits.clone();
+        client.compression_detection = self.config.compression_detection;
+        if let Some(platform) = &self.config.platform {
+            client.platform = Platform::from_str(platform)?;
+        }
 
-        Ok(image_id)
+        let manifest = client.pull_manifest().await?;
+        Ok((image_url.to_string(), client, manifest))
+    }
+
+    // Pull the image manifest and config from the registries, trying the
+    // short name candidates in order. The resolved, fully qualified reference
+    // is returned, it is the one checked by the security policy and recorded
+    // in the image metadata.
+    async fn pull_registry_manifest(
+        &mut self,
+        image_url: &str,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+        options: &PullOptions,
+        progress: &ProgressReporter,
+        cancel: &CancellationToken,
+    ) -> Result<(String, PullClient, (OciImageManifest, String, String))> {
+        let registries = match &self.config.registries_config {
+            Some(path) => Some(RegistriesConfig::from_file(path)?),
+            None => None,
+        };
+
+        let candidates = self.resolve_image(&registries, image_url)?;
+
+        // A candidate failing, from its credentials to its manifest, does
+        // not stop the short name resolution: the next one is tried.
+        let mut pulled = None;
+        let mut last_err = anyhow!("no image to pull for {}", image_url);
+        for reference in candidates {
+            let mut client = match self
+                .pull_client(&reference, &registries, auth_info, decrypt_config)
+                .await
+            {
+                Ok(client) => client,
+                Err(e) if cancel.is_cancelled() => return Err(e),
+                Err(e) => {
+                    last_err = e;
+                    continue;
+                }
+            };
+            client.progress = progress.clone();
+            client.cancel = cancel.clone();
+            client.client.set_request_timeout(options.request_timeout);
+            match client.pull_manifest().await {
+                Ok(manifest) => {
+                    pulled = Some((reference, client, manifest));
+                    break;
+                }
+                Err(e) if cancel.is_cancelled() => return Err(e),
+                Err(e) => last_err = e,
+            }
+        }
+
+        let (reference, client, manifest) = pulled.ok_or(last_err)?;
+        Ok((reference.whole(), client, manifest))
+    }
+
+    // Resolve `image` to the fully qualified references it may stand for,
+    // through the registries config. Without one, short image names resolve
+    // to the default registry alone, and are rejected if there is none.
+    fn resolve_image(
+        &self,
+        registries: &Option<RegistriesConfig>,
+        image: &str,
+    ) -> Result<Vec<Reference>> {
+        match registries {
+            Some(registries) => registries.resolve(image),
+            None => RegistriesConfig {
+                unqualified_search_registries: self
+                    .config
+                    .default_registry
+                    .iter()
+                    .cloned()
+                    .collect(),
+                short_name_mode: ShortNameMode::Enforcing,
+                ..Default::default()
+            }
+            .resolve(image),
+        }
     }
+
+    // Construct the client to pull `reference` from the mirrors and location
+    // set in the registries config, with the credentials and TLS settings for
+    // each of them.
+    async fn pull_client(
+        &mut self,
+        reference: &Reference,
+        registries: &Option<RegistriesConfig>,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+    ) -> Result<PullClient> {
+        let sources = match registries {
+            Some(registries) => registries.pull_sources(reference)?,
+            None => vec![reference.clone()],
+        };
+
+        let mut client = PullClient::new(
+            &reference.whole(),
+            &self.config.work_dir.join("layers"),
+            &None,
+        )?;
+        client.client.set_retry(self.config.retry.clone());
+        client.scheduler = self.scheduler.clone();
+        client.blob_cache = self.blob_cache.clone();
+        client.foreign_layers = self.config.foreign_layers.clone();
+        client.limits = self.config.limits.clone();
+        client.compression_detection = self.config.compression_detection;
+        for (i, source) in sources.iter().enumerate() {
+            // The auth info passed in is for the image location, which is
+            // the last source, mirrors get their own credentials.
+            let auth_info = if i == sources.len() - 1 {
+                auth_info
+            } else {
+                &None
+            };
+            let credential = self.credential(source, auth_info, decrypt_config).await?;
+            client.client.set_credential(source, credential);
+
+            if let Some(tls) = self.config.registry_tls.get(source.registry()) {
+                client.client.set_tls(source.registry(), tls)?;
+            }
+        }
+        client.sources = sources;
+
+        if let Some(platform) = &self.config.platform {
+            client.platform = Platform::from_str(platform)?;
+        }
+
+        Ok(client)
+    }
+
+    // Resolve the registry credential to pull an image with. When credentials
+    // are fetched from the KBS, no auth info from the host is accepted.
+    // Otherwise the auth info passed in takes precedence over the configured
+    // auth file.
+    async fn credential(
+        &mut self,
+        reference: &Reference,
+        auth_info: &Option<&str>,
+        decrypt_config: &Option<&str>,
+    ) -> Result<Credential> {
+        if self.config.kbs_credentials {
+            if auth_info.is_some() {
+                return Err(anyhow!(
+                    "Auth info is not accepted when registry credentials come from the KBS."
+                ));
+            }
+
+            if self.kbs_credentials.is_none() {
+                let aa_kbc_params = aa_kbc_params(decrypt_config)
+                    .ok_or_else(|| anyhow!("Getting registry credentials need aa_kbc_params."))?;
+                let credentials = DockerConfig::from_kbs(aa_kbc_params)
+                    .await
+                    .map_err(|e| anyhow!("Get registry credentials failed: {:?}", e))?;
+                self.kbs_credentials = Some(credentials);
+            }
+
+            return Ok(self
+                .kbs_credentials
+                .as_ref()
+                .map(|credentials| credentials.credential(reference))
+                .transpose()?
+                .flatten()
+                .unwrap_or_default());
+        }
+
+        if let Some(auth_info) = auth_info {
+            return Credential::try_from(*auth_info);
+        }
+
+        if let Some(auth_file) = &self.config.auth_file {
+            if let Some(credential) = DockerConfig::from_file(auth_file)?.credential(reference)? {
+                return Ok(credential);
+            }
+        }
+
+        Ok(Credential::Anonymous)
+    }
+}
+
+// Check the image pulled by `client` against the signature policy. The
+// policy applies to the image reference as requested, not to the mirror or
+// location the image is pulled from. Local images are checked for their
+// transport, in the scope of the path they are read from. The docker
+// reference their storage names them with is read from the image, it is
+// only matched against the signatures, never used to select the scope, and
+// only required by the policy requirements that verify signatures.
+async fn validate_image(
+    client: &PullClient,
+    image_url: &str,
+    image_digest: &str,
+    aa_kbc_params: &str,
+) -> Result<()> {
+    let (transport, policy_reference, local_scope) = match client.local() {
+        Some(local) => (
+            local.transport(),
+            local.docker_reference(),
+            local.local_scope()?,
+        ),
+        None => (TransportName::Docker, Some(image_url), None),
+    };
+
+    security_validate(
+        transport,
+        policy_reference,
+        local_scope,
+        image_digest,
+        aa_kbc_params,
+    )
+    .await
+}
+
+// The keys the image pulled by `client` with `image_url` is found by in the
+// local store: the reference it was pulled by, and for registry images the
+// digest reference of the image in its repository too.
+fn reference_keys(client: &PullClient, image_url: &str, image_digest: &str) -> Result<Vec<String>> {
+    if client.local().is_some() {
+        return Ok(vec![image_url.to_string()]);
+    }
+
+    let reference = Reference::try_from(image_url)?;
+    let digest_reference = Reference::with_digest(
+        reference.registry().to_string(),
+        reference.repository().to_string(),
+        image_digest.to_string(),
+    );
+
+    Ok(vec![reference_key(&reference), digest_reference.whole()])
+}
+
+// The key of `reference` in the local store, digest references are found
+// by their digest only, whatever their tag.
+fn reference_key(reference: &Reference) -> String {
+    match reference.digest() {
+        Some(digest) => Reference::with_digest(
+            reference.registry().to_string(),
+            reference.repository().to_string(),
+            digest.to_string(),
+        )
+        .whole(),
+        None => reference.whole(),
+    }
+}
+
+// Check that a stored image may be mounted with `decrypt_config`: like
+// for a pull, the encrypted layers need the decrypt config they were
+// decrypted with.
+fn check_decrypt_config(image_data: &ImageMeta, decrypt_config: &Option<&str>) -> Result<()> {
+    for layer in image_data.layer_metas.iter() {
+        layer
+            .check_decrypt_config(decrypt_config)
+            .map_err(|e| anyhow!("image {} has encrypted layers, {}", image_data.reference, e))?;
+    }
+
+    Ok(())
+}
+
+// Get the aa_kbc_params wrapped in the decrypt config,
+// like "provider:attestation-agent:null_kbc::null".
+fn aa_kbc_params<'a>(decrypt_config: &Option<&'a str>) -> Option<&'a str> {
+    decrypt_config.map(|config| config.trim_start_matches("provider:attestation-agent:"))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::digest::digest_bytes;
+    use crate::test_registry::{
+        write_signature, Fault, TestImage, TestKbs, TestRegistry, TEST_GPG_PUBKEY,
+    };
+    use crate::validate::{SimpleSigning, IMAGE_SECURITY_CONFIG_DIR, POLICY_FILE_PATH};
     use std::process::Command;
 
+    // The tests writing a policy share the image security config directory.
+    static SECURITY_CONFIG: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
     #[tokio::test]
     async fn test_pull_image() {
         let work_dir = tempfile::tempdir().unwrap();
-        std::env::set_var("CC_IMAGE_WORK_DIR", &work_dir.path());
-
-        // TODO test with more OCI image registries and fix broken registries.
-        let oci_images = vec![
-            // Alibaba Container Registry
-            "registry.cn-hangzhou.aliyuncs.com/acs/busybox:v1.29.2",
-            // Amazon Elastic Container Registry
-            // "public.ecr.aws/docker/library/hello-world:linux"
-
-            // Azure Container Registry
-            "mcr.microsoft.com/hello-world",
-            // Docker container Registry
-            "docker.io/i386/busybox",
-            // Google Container Registry
-            "gcr.io/google-containers/busybox:1.27.2",
-            // JFrog Container Registry
-            // "releases-docker.jfrog.io/reg2/busybox:1.33.1"
-        ];
+        let registry = TestRegistry::start().await.unwrap();
+        let image = |compression, content: &[u8]| {
+            TestImage::new("linux/amd64")
+                .unwrap()
+                .layer(&[("bin/busybox", b"busybox")], Compression::Gzip)
+                .unwrap()
+                .layer(&[("etc/hostname", content)], compression)
+                .unwrap()
+        };
+        registry.push_image("busybox", "gzip", &image(Compression::Gzip, b"gzip"));
+        registry.push_image("busybox", "zstd", &image(Compression::Zstd, b"zstd"));
+        registry.push_image(
+            "busybox",
+            "uncompressed",
+            &image(Compression::Uncompressed, b"uncompressed"),
+        );
+        registry.push_index(
+            "busybox",
+            "multi-arch",
+            &[
+                TestImage::new("linux/arm64/v8")
+                    .unwrap()
+                    .layer(&[("etc/hostname", b"arm64")], Compression::Gzip)
+                    .unwrap(),
+                image(Compression::Gzip, b"multi-arch"),
+            ],
+        );
 
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
         let mut image_client = ImageClient::default();
-        for image in oci_images.iter() {
-            let bundle_dir = tempfile::tempdir().unwrap();
+        image_client.config.platform = Some("linux/amd64".to_string());
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
 
-            assert!(image_client
-                .pull_image(image, bundle_dir.path(), &None, &None)
+        for tag in ["gzip", "zstd", "uncompressed", "multi-arch"].iter() {
+            let bundle_dir = tempfile::tempdir().unwrap();
+            image_client
+                .pull_image(
+                    &registry.reference("busybox", tag),
+                    bundle_dir.path(),
+                    &None,
+                    &None,
+                )
                 .await
-                .is_ok());
+                .unwrap();
+
+            let rootfs = bundle_dir.path().join(BUNDLE_ROOTFS);
+            assert_eq!(
+                std::fs::read(rootfs.join("bin/busybox")).unwrap(),
+                b"busybox"
+            );
+            assert_eq!(
+                std::fs::read(rootfs.join("etc/hostname")).unwrap(),
+                tag.as_bytes()
+            );
+            let _ = Command::new("umount").arg(&rootfs).output();
         }
 
         assert_eq!(image_client.meta_store.lock().await.image_db.len(), 4);
     }
 
     #[tokio::test]
-    async fn test_pull_signed_image() {
+    async fn test_pull_image_test_registry() {
         let work_dir = tempfile::tempdir().unwrap();
-        std::env::set_var("CC_IMAGE_WORK_DIR", &work_dir.path());
-        let signature_script = format!(
-            "{}/scripts/install_test_signatures.sh",
-            std::env::var("CARGO_MANIFEST_DIR").unwrap()
-        );
+        let registry = TestRegistry::start().await.unwrap();
+        let images = [
+            TestImage::new("linux/amd64")
+                .unwrap()
+                .layer(&[("platform", b"amd64")], Compression::Gzip)
+                .unwrap(),
+            TestImage::new("linux/arm64/v8")
+                .unwrap()
+                .layer(&[("platform", b"arm64")], Compression::Zstd)
+                .unwrap(),
+        ];
+        registry.push_index("busybox", "latest", &images);
+        registry.set_auth("user", "password");
+
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.platform = Some("linux/arm64/v8".to_string());
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+
+        let image = registry.reference("busybox", "latest");
+        let bundle_dir = tempfile::tempdir().unwrap();
+        assert!(image_client
+            .pull_image(&image, bundle_dir.path(), &None, &None)
+            .await
+            .is_err());
 
-        Command::new(&signature_script)
-            .arg("install")
-            .output()
+        image_client
+            .pull_image(&image, bundle_dir.path(), &Some("user:password"), &None)
+            .await
             .unwrap();
+        let rootfs = bundle_dir.path().join(BUNDLE_ROOTFS);
+        assert_eq!(std::fs::read(rootfs.join("platform")).unwrap(), b"arm64");
+        let _ = Command::new("umount").arg(&rootfs).output();
+    }
 
-        let images_can_be_pulled = vec![
-            // Test can pull a unencrypted signed image from a protected registry.
-            "quay.io/kata-containers/confidential-containers:signed",
-            // Test can pull an unencrypted unsigned image from an unprotected registry.
-            "quay.io/prometheus/busybox:latest",
-        ];
+    #[tokio::test]
+    async fn test_pull_short_name() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("hostname", b"busybox")], Compression::Gzip)
+            .unwrap();
+        registry.push_image("busybox", "latest", &image);
 
-        let images_cannot_be_pulled = vec![
-            // Test cannot pull an unencrypted unsigned image from a protected registry.
-            "quay.io/kata-containers/confidential-containers:unsigned",
-            // Test unencrypted signed image with unknown signature is rejected.
-            "quay.io/kata-containers/confidential-containers:other_signed",
-        ];
+        // The first candidate registry is blocked, the short name resolves
+        // to the next one.
+        let registries_config = work_dir.path().join("registries.conf");
+        std::fs::write(
+            &registries_config,
+            format!(
+                r#"
+                unqualified-search-registries = ["blocked.example.com", "{}"]
+                short-name-mode = "permissive"
+
+                [[registry]]
+                location = "blocked.example.com"
+                blocked = true
+                "#,
+                registry.host()
+            ),
+        )
+        .unwrap();
 
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
         let mut image_client = ImageClient::default();
-        image_client.config.security_validate = true;
+        image_client.config.work_dir = work_dir.path().to_path_buf();
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+        image_client.config.registries_config = Some(registries_config);
 
         let bundle_dir = tempfile::tempdir().unwrap();
+        image_client
+            .pull_image("busybox", bundle_dir.path(), &None, &None)
+            .await
+            .unwrap();
+        let rootfs = bundle_dir.path().join(BUNDLE_ROOTFS);
+        assert_eq!(std::fs::read(rootfs.join("hostname")).unwrap(), b"busybox");
+        let _ = Command::new("umount").arg(&rootfs).output();
+
+        // Without a registries config, short names resolve to the default
+        // registry, and are rejected when it is not set, as by default.
+        image_client.config.registries_config = None;
+        image_client.config.default_registry = Some(registry.host());
+        image_client
+            .pull_image("busybox", bundle_dir.path(), &None, &None)
+            .await
+            .unwrap();
+        let _ = Command::new("umount").arg(&rootfs).output();
+
+        image_client.config.default_registry = ImageConfig::default().default_registry;
+        let err = image_client
+            .pull_image("busybox", bundle_dir.path(), &None, &None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("short image name"), "{}", err);
+        assert!(image_client
+            .pull_image(
+                &registry.reference("busybox", "latest"),
+                bundle_dir.path(),
+                &None,
+                &None
+            )
+            .await
+            .is_ok());
+        let _ = Command::new("umount").arg(&rootfs).output();
+    }
+
+    #[tokio::test]
+    async fn test_pull_signed_image() {
+        let _security_config = SECURITY_CONFIG.lock().await;
+        let work_dir = tempfile::tempdir().unwrap();
+        let registry = TestRegistry::start().await.unwrap();
+        let sigstore = tempfile::tempdir().unwrap();
+        let protected = format!("{}/protected", registry.host());
+
+        let images: Vec<(&str, TestImage)> = [
+            "protected/signed",
+            "protected/unsigned",
+            "protected/other_signed",
+            "unprotected/busybox",
+        ]
+        .iter()
+        .map(|repository| {
+            let image = TestImage::new("linux/amd64")
+                .unwrap()
+                .layer(&[("hostname", b"busybox")], Compression::Gzip)
+                .unwrap()
+                .label("repository", repository);
+            registry.push_image(repository, "latest", &image);
+            (*repository, image)
+        })
+        .collect();
+        for (repository, image) in images.iter() {
+            // The other image is signed for another reference than its own.
+            let signed_reference = match *repository {
+                "protected/signed" => registry.reference("protected/signed", "latest"),
+                "protected/other_signed" => registry.reference("protected/signed", "latest"),
+                _ => continue,
+            };
+            let digest = digest_bytes(&image.manifest(), None).unwrap();
+            let signature = image.signature(&signed_reference).unwrap();
+            write_signature(sigstore.path(), repository, &digest, &signature).unwrap();
+        }
+
+        // The images of the protected namespace must be signed by the test
+        // key, and their signatures are in the sigstore.
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+        std::fs::create_dir_all(SimpleSigning::SigstoreConfigDir.to_string()).unwrap();
+        let policy = serde_json::json!({
+            "default": [{ "type": "insecureAcceptAnything" }],
+            "transports": {
+                "docker": {
+                    &protected: [{
+                        "type": "signedBy",
+                        "scheme": "simple",
+                        "keyType": "GPGKeys",
+                        "keyPath": SimpleSigning::GpgKeyRing.to_string(),
+                    }],
+                },
+            },
+        });
+        std::fs::write(POLICY_FILE_PATH, policy.to_string()).unwrap();
+        std::fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_GPG_PUBKEY),
+            SimpleSigning::GpgKeyRing.to_string(),
+        )
+        .unwrap();
+        std::fs::write(
+            Path::new(&SimpleSigning::SigstoreConfigDir.to_string()).join("test.yaml"),
+            format!(
+                "docker:\n    {}:\n        sigstore: file://{}\n",
+                protected,
+                sigstore.path().display()
+            ),
+        )
+        .unwrap();
 
-        for image in images_cannot_be_pulled.iter() {
-            assert!(image_client
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.work_dir = work_dir.path().to_path_buf();
+        image_client.config.security_validate = true;
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+
+        let tests = [
+            // A signed image of the protected namespace.
+            ("protected/signed", true),
+            // An unsigned image of the protected namespace.
+            ("protected/unsigned", false),
+            // An image of the protected namespace signed for another image.
+            ("protected/other_signed", false),
+            // An unsigned image out of the protected namespace.
+            ("unprotected/busybox", true),
+        ];
+        for (repository, allowed) in tests.iter() {
+            let bundle_dir = tempfile::tempdir().unwrap();
+            let res = image_client
                 .pull_image(
-                    image,
+                    &registry.reference(repository, "latest"),
                     bundle_dir.path(),
                     &None,
-                    &Some("provider:attestation-agent:null_kbc::null")
+                    &Some("provider:attestation-agent:null_kbc::null"),
                 )
-                .await
-                .is_err());
+                .await;
+            let _ = Command::new("umount")
+                .arg(bundle_dir.path().join(BUNDLE_ROOTFS))
+                .output();
+            assert_eq!(res.is_ok(), *allowed, "{}: {:?}", repository, res);
         }
 
-        for image in images_can_be_pulled.iter() {
-            assert!(image_client
+        assert_eq!(image_client.meta_store.lock().await.image_db.len(), 2);
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+    }
+
+    #[tokio::test]
+    async fn test_pull_dir_policy() {
+        let _security_config = SECURITY_CONFIG.lock().await;
+        let work_dir = tempfile::tempdir().unwrap();
+        let images = tempfile::tempdir().unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("bin/busybox", b"busybox")], Compression::Gzip)
+            .unwrap();
+        for name in ["trusted/busybox", "signed/busybox", "other/busybox"] {
+            image.write_dir(&images.path().join(name)).unwrap();
+        }
+
+        // Image directories have no docker reference: the trusted ones are
+        // accepted by their path, the signed ones need signatures which can
+        // not be matched without one, and any other one is rejected.
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+        std::fs::create_dir_all(SimpleSigning::SigstoreConfigDir.to_string()).unwrap();
+        std::fs::write(
+            Path::new(&SimpleSigning::SigstoreConfigDir.to_string()).join("test.yaml"),
+            "default-docker:\n    sigstore: file:///var/lib/containers/sigstore\n",
+        )
+        .unwrap();
+        std::fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join(TEST_GPG_PUBKEY),
+            SimpleSigning::GpgKeyRing.to_string(),
+        )
+        .unwrap();
+        let policy = serde_json::json!({
+            "default": [{ "type": "reject" }],
+            "transports": {
+                "dir": {
+                    images.path().join("trusted").display().to_string(): [
+                        { "type": "insecureAcceptAnything" },
+                    ],
+                    images.path().join("signed").display().to_string(): [{
+                        "type": "signedBy",
+                        "scheme": "simple",
+                        "keyType": "GPGKeys",
+                        "keyPath": SimpleSigning::GpgKeyRing.to_string(),
+                    }],
+                },
+            },
+        });
+        std::fs::write(POLICY_FILE_PATH, policy.to_string()).unwrap();
+
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.work_dir = work_dir.path().to_path_buf();
+        image_client.config.security_validate = true;
+
+        let tests = [
+            ("trusted/busybox", None),
+            ("signed/busybox", Some("has no docker reference")),
+            ("other/busybox", Some(r#"The policy is "reject""#)),
+        ];
+        for (name, reason) in tests.iter() {
+            let bundle_dir = tempfile::tempdir().unwrap();
+            let res = image_client
                 .pull_image(
-                    image,
+                    &format!("dir:{}", images.path().join(name).display()),
                     bundle_dir.path(),
                     &None,
-                    &Some("provider:attestation-agent:null_kbc::null")
+                    &Some("provider:attestation-agent:null_kbc::null"),
                 )
-                .await
-                .is_ok());
+                .await;
+            let _ = Command::new("umount")
+                .arg(bundle_dir.path().join(BUNDLE_ROOTFS))
+                .output();
+            match reason {
+                None => assert!(res.is_ok(), "{}: {:?}", name, res),
+                Some(reason) => {
+                    let err = format!("{:?}", res.unwrap_err());
+                    assert!(err.contains(reason), "{}: {}", name, err);
+                }
+            }
         }
 
-        assert_eq!(image_client.meta_store.lock().await.image_db.len(), 2);
+        assert_eq!(image_client.meta_store.lock().await.image_db.len(), 1);
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+    }
+
+    #[tokio::test]
+    async fn test_kbs_credentials() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("hostname", b"busybox")], Compression::Gzip)
+            .unwrap();
+        registry.push_image("busybox", "latest", &image);
+        registry.set_auth("user", "password");
+        let reference = registry.reference("busybox", "latest");
+
+        let credential = serde_json::json!({
+            "auths": {
+                registry.host(): { "auth": base64::encode("user:password") },
+            },
+        });
+        let kbs = TestKbs::start(
+            vec![(
+                "Credential".to_string(),
+                credential.to_string().into_bytes(),
+            )]
+            .into_iter()
+            .collect(),
+        )
+        .await
+        .unwrap();
+
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.work_dir = work_dir.path().to_path_buf();
+        image_client.config.kbs_credentials = true;
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+        let bundle_dir = tempfile::tempdir().unwrap();
+
+        // Credentials from the host are refused.
+        assert!(image_client
+            .pull_image(
+                &reference,
+                bundle_dir.path(),
+                &Some("user:password"),
+                &Some("provider:attestation-agent:null_kbc::null")
+            )
+            .await
+            .is_err());
+
+        // The KBS can not be reached without aa_kbc_params.
+        assert!(image_client
+            .pull_image(&reference, bundle_dir.path(), &None, &None)
+            .await
+            .is_err());
+        assert!(image_client.meta_store.lock().await.image_db.is_empty());
+        assert!(kbs.requests().is_empty());
+
+        // The credentials come from the KBS.
+        image_client
+            .pull_image(
+                &reference,
+                bundle_dir.path(),
+                &None,
+                &Some("provider:attestation-agent:null_kbc::null"),
+            )
+            .await
+            .unwrap();
+        let _ = Command::new("umount")
+            .arg(bundle_dir.path().join(BUNDLE_ROOTFS))
+            .output();
+        assert_eq!(
+            kbs.requests(),
+            vec![(
+                "null_kbc".to_string(),
+                "null".to_string(),
+                "Credential".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_image_cancelled() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("bin/busybox", b"busybox")], Compression::Gzip)
+            .unwrap()
+            .layer(&[("etc/hostname", b"test")], Compression::Gzip)
+            .unwrap();
+        registry.push_image("busybox", "latest", &image);
+        let reference = registry.reference("busybox", "latest");
+
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.work_dir = work_dir.path().to_path_buf();
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+        let bundle_dir = tempfile::tempdir().unwrap();
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let options = PullOptions {
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let err = image_client
+            .pull_image_with_options(&reference, bundle_dir.path(), &None, &None, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PullError>(),
+            Some(PullError::Cancelled { .. })
+        ));
+
+        // The first layer is unpacked before the pull times out on the
+        // second one, it is removed with the pull.
+        let layer_digests = image.layer_digests();
+        registry.inject(Fault::StalledBlob(layer_digests[1].clone()));
+        let options = PullOptions {
+            timeout: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        let err = image_client
+            .pull_image_with_options(&reference, bundle_dir.path(), &None, &None, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PullError>(),
+            Some(PullError::Timeout { .. })
+        ));
+
+        let meta_store = image_client.meta_store.lock().await;
+        assert!(meta_store.image_db.is_empty());
+        assert!(meta_store.layer_db.is_empty());
+        assert!(meta_store.layer_pulls.is_empty());
+        for digest in layer_digests.iter() {
+            let store_path = work_dir
+                .path()
+                .join("layers")
+                .join(digest.replace(':', "_"));
+            assert!(!store_path.exists());
+        }
+        assert!(registry
+            .requests()
+            .iter()
+            .any(|r| r.ends_with(&format!("/blobs/{}", layer_digests[0]))));
+    }
+
+    #[tokio::test]
+    async fn test_inspect_remote() {
+        let _security_config = SECURITY_CONFIG.lock().await;
+        let work_dir = tempfile::tempdir().unwrap();
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
 
-        Command::new(&signature_script)
-            .arg("clean")
-            .output()
+        // Image directories without their layer blob, which is not needed to
+        // inspect the image, in a trusted directory and in another one.
+        let images = tempfile::tempdir().unwrap();
+        let trusted_dir = images.path().join("trusted/busybox");
+        let other_dir = images.path().join("other/busybox");
+        let diff_id = format!("sha256:{}", "1".repeat(64));
+        let layer_digest = format!("sha256:{}", "2".repeat(64));
+        let config = format!(
+            r#"{{"architecture": "amd64", "os": "linux",
+                "config": {{"Labels": {{"version": "1.0"}}}},
+                "rootfs": {{"type": "layers", "diff_ids": ["{}"]}},
+                "history": [{{"created_by": "ADD file.txt /"}}]}}"#,
+            diff_id
+        );
+        let config_digest = crate::digest::digest_bytes(config.as_bytes(), None).unwrap();
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "config": {{"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "{}", "size": {}}},
+                "layers": [{{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip+encrypted", "digest": "{}", "size": 1024}}]}}"#,
+            config_digest,
+            config.len(),
+            layer_digest
+        );
+        for image_dir in [&trusted_dir, &other_dir] {
+            std::fs::create_dir_all(image_dir).unwrap();
+            std::fs::write(
+                image_dir.join("version"),
+                "Directory Transport Version: 1.1\n",
+            )
             .unwrap();
+            std::fs::write(image_dir.join("manifest.json"), &manifest).unwrap();
+            std::fs::write(image_dir.join(&config_digest[7..]), &config).unwrap();
+        }
+
+        // Only the images of the trusted directory are accepted.
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+        std::fs::create_dir_all(IMAGE_SECURITY_CONFIG_DIR).unwrap();
+        let policy = serde_json::json!({
+            "default": [{ "type": "reject" }],
+            "transports": {
+                "dir": {
+                    images.path().join("trusted").display().to_string(): [
+                        { "type": "insecureAcceptAnything" },
+                    ],
+                },
+            },
+        });
+        std::fs::write(POLICY_FILE_PATH, policy.to_string()).unwrap();
+        let decrypt_config = Some("provider:attestation-agent:null_kbc::null");
+
+        let image_url = format!("dir:{}", trusted_dir.display());
+        let inspect = image_client
+            .inspect_remote(&image_url, &None, &decrypt_config, true)
+            .await
+            .unwrap();
+        assert_eq!(inspect.reference, image_url);
+        assert_eq!(
+            inspect.digest,
+            crate::digest::digest_bytes(manifest.as_bytes(), None).unwrap()
+        );
+        assert_eq!(inspect.id, config_digest);
+        assert_eq!(inspect.platform.to_string(), "linux/amd64");
+        assert_eq!(
+            inspect.layers,
+            vec![LayerInspect {
+                digest: layer_digest,
+                diff_id: Some(diff_id),
+                media_type: "application/vnd.oci.image.layer.v1.tar+gzip+encrypted".to_string(),
+                size: 1024,
+                encrypted: true,
+            }]
+        );
+        assert_eq!(inspect.labels.get("version").unwrap(), "1.0");
+        assert_eq!(inspect.history.len(), 1);
+
+        assert_eq!(
+            inspect.policy,
+            Some(PolicyDecision {
+                allowed: true,
+                reason: None,
+            })
+        );
+
+        // The policy dry run refuses the other image rather than failing.
+        let policy = image_client
+            .inspect_remote(
+                &format!("dir:{}", other_dir.display()),
+                &None,
+                &decrypt_config,
+                true,
+            )
+            .await
+            .unwrap()
+            .policy
+            .unwrap();
+        assert!(!policy.allowed);
+        let reason = policy.reason.unwrap();
+        assert!(reason.contains(r#"The policy is "reject""#), "{}", reason);
+
+        assert!(image_client
+            .inspect_remote(&image_url, &None, &None, false)
+            .await
+            .unwrap()
+            .policy
+            .is_none());
+        assert!(image_client.meta_store.lock().await.image_db.is_empty());
+        let _ = std::fs::remove_dir_all(IMAGE_SECURITY_CONFIG_DIR);
+    }
+
+    #[tokio::test]
+    async fn test_pull_policy() {
+        let work_dir = tempfile::tempdir().unwrap();
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
+        let mut image_client = ImageClient::default();
+        image_client.config.default_registry = Some("docker.io".to_string());
+        let bundle_dir = tempfile::tempdir().unwrap();
+
+        let options = PullOptions {
+            pull_policy: PullPolicy::Never,
+            ..Default::default()
+        };
+        let err = image_client
+            .pull_image_with_options("busybox", bundle_dir.path(), &None, &None, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PullError>(),
+            Some(PullError::NotPresent { .. })
+        ));
+
+        let digest = format!("sha256:{}", "1".repeat(64));
+        let image_data = ImageMeta {
+            id: format!("sha256:{}", "2".repeat(64)),
+            digest: digest.clone(),
+            reference: "docker.io/library/busybox:latest".to_string(),
+            ..Default::default()
+        };
+        {
+            let mut ms = image_client.meta_store.lock().await;
+            let reference = Reference::try_from(image_data.reference.as_str()).unwrap();
+            for key in [
+                reference_key(&reference),
+                format!("docker.io/library/busybox@{}", digest),
+            ] {
+                ms.image_refs.insert(key, image_data.id.clone());
+            }
+            ms.image_db
+                .insert(image_data.id.clone(), image_data.clone());
+        }
+
+        // Tags and digests of the same repository map to the stored image,
+        // whatever the tag of a digest reference.
+        for image in [
+            "busybox",
+            "docker://busybox:latest",
+            "docker.io/library/busybox:latest",
+            &format!("busybox@{}", digest),
+            &format!("busybox:1.0@{}", digest),
+        ] {
+            let found = image_client.local_image(image).await.unwrap();
+            assert_eq!(
+                found.map(|i| i.id),
+                Some(image_data.id.clone()),
+                "{}",
+                image
+            );
+        }
+
+        for image in [
+            "busybox:1.0",
+            "quay.io/library/busybox:latest",
+            &format!("quay.io/library/busybox@{}", digest),
+        ] {
+            assert!(
+                image_client.local_image(image).await.unwrap().is_none(),
+                "{}",
+                image
+            );
+        }
+
+        // A stored image with encrypted layers is not mounted without the
+        // decrypt config which decrypted them.
+        let decrypt_config = "provider:attestation-agent:null_kbc::null";
+        let encrypted_data = ImageMeta {
+            id: format!("sha256:{}", "3".repeat(64)),
+            reference: "docker.io/library/encrypted:latest".to_string(),
+            layer_metas: vec![LayerMeta {
+                encrypted: true,
+                compressed_digest: format!("sha256:{}", "4".repeat(64)),
+                decrypt_config_digest: Some(digest_bytes(decrypt_config.as_bytes(), None).unwrap()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        {
+            let mut ms = image_client.meta_store.lock().await;
+            let reference = Reference::try_from(encrypted_data.reference.as_str()).unwrap();
+            ms.image_refs
+                .insert(reference_key(&reference), encrypted_data.id.clone());
+            ms.image_db
+                .insert(encrypted_data.id.clone(), encrypted_data.clone());
+        }
+        let err = image_client
+            .pull_image_with_options("encrypted", bundle_dir.path(), &None, &None, &options)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("decrypt_config is None"),
+            "{}",
+            err
+        );
+
+        let wrong_key = "/run/image-security/other_key.pem";
+        let err = image_client
+            .pull_image_with_options(
+                "encrypted",
+                bundle_dir.path(),
+                &None,
+                &Some(wrong_key),
+                &options,
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("another decrypt_config"),
+            "{}",
+            err
+        );
+
+        // The decrypt config which decrypted the layers gets past the
+        // check, to the mount of the made up layer.
+        let err = image_client
+            .pull_image_with_options(
+                "encrypted",
+                bundle_dir.path(),
+                &None,
+                &Some(decrypt_config),
+                &options,
+            )
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("decrypt_config"), "{}", err);
     }
 }
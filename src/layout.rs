@@ -0,0 +1,449 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::archive::{read_limited, TarArchive, OCI_ARCHIVE_PREFIX};
+use crate::config::LimitsConfig;
+use crate::digest::split_digest;
+use crate::errors::Limit;
+
+/// The prefix of the image references pointing into an OCI image layout.
+pub const OCI_LAYOUT_PREFIX: &str = "oci:";
+
+/// The files and directory of an OCI image layout.
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+
+/// The supported version of the OCI image layout.
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+
+/// The annotation naming an image of the layout index.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// The annotation containerd sets to the full name of an exported image.
+const CONTAINERD_IMAGE_NAME_ANNOTATION: &str = "io.containerd.image.name";
+
+/// A reference to an image of an OCI image layout, in the
+/// `oci:<dir>[:<tag>|@<digest>]` format, or of a tar archive of an OCI
+/// image layout, in the `oci-archive:<path>[:<tag>|@<digest>]` format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayoutReference {
+    /// The layout directory, or the archive path.
+    pub path: PathBuf,
+
+    /// Whether the layout is in a tar archive.
+    pub archive: bool,
+
+    /// The name of the image in the layout index.
+    pub tag: Option<String>,
+
+    /// The digest of the image manifest or index.
+    pub digest: Option<String>,
+}
+
+impl FromStr for LayoutReference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (rest, archive) = match s.strip_prefix(OCI_ARCHIVE_PREFIX) {
+            Some(rest) => (rest, true),
+            None => (
+                s.strip_prefix(OCI_LAYOUT_PREFIX)
+                    .ok_or_else(|| anyhow!("invalid OCI layout reference {}", s))?,
+                false,
+            ),
+        };
+
+        let (path, tag, digest) = match rest.rsplit_once('@') {
+            Some((path, digest)) => (path, None, Some(digest.to_string())),
+            None => match rest.rsplit_once(':') {
+                // A colon in the layout path is not a tag separator.
+                Some((path, tag)) if !tag.contains('/') => (path, Some(tag.to_string()), None),
+                _ => (rest, None, None),
+            },
+        };
+
+        if path.is_empty() || tag.as_deref() == Some("") || digest.as_deref() == Some("") {
+            return Err(anyhow!("invalid OCI layout reference {}", s));
+        }
+
+        Ok(LayoutReference {
+            path: PathBuf::from(path),
+            archive,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for LayoutReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = if self.archive {
+            OCI_ARCHIVE_PREFIX
+        } else {
+            OCI_LAYOUT_PREFIX
+        };
+        write!(f, "{}{}", prefix, self.path.display())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutFile {
+    image_layout_version: String,
+}
+
+#[derive(Deserialize)]
+struct LayoutIndex {
+    manifests: Vec<LayoutIndexEntry>,
+}
+
+#[derive(Deserialize)]
+struct LayoutIndexEntry {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// OciLayout reads an image from an OCI image layout directory or archive:
+/// its `oci-layout` file, its `index.json`, and its blobs by digest.
+///
+/// Nothing read from the layout is trusted, blobs are verified against
+/// their digest as they are pulled, like the blobs from a registry.
+pub struct OciLayout {
+    reference: LayoutReference,
+
+    // The archive holding the layout, read in place.
+    archive: Option<TarArchive>,
+
+    // The digest of the image manifest or index the reference points to.
+    manifest_digest: String,
+
+    // The docker reference of the image, if the layout index names it.
+    docker_reference: Option<String>,
+
+    // The maximum size of the index and manifests read from the layout.
+    max_manifest_bytes: u64,
+}
+
+impl OciLayout {
+    /// Open the layout of `reference`, checking its layout version, and
+    /// resolve its image. Without tag nor digest in the reference, the
+    /// layout must hold one image. The layout index and the manifests are
+    /// read within the manifest size limit of `limits`.
+    pub fn open(reference: LayoutReference, limits: &LimitsConfig) -> Result<Self> {
+        let archive = match reference.archive {
+            true => Some(TarArchive::open(&reference.path)?),
+            false => None,
+        };
+        let mut layout = OciLayout {
+            reference,
+            archive,
+            manifest_digest: String::new(),
+            docker_reference: None,
+            max_manifest_bytes: limits.max_manifest_bytes,
+        };
+
+        let layout_file: LayoutFile = serde_json::from_slice(&layout.read_file(OCI_LAYOUT_FILE)?)
+            .map_err(|e| {
+            anyhow!(
+                "failed to parse {} of {}: {}",
+                OCI_LAYOUT_FILE,
+                layout.reference,
+                e
+            )
+        })?;
+        if layout_file.image_layout_version != OCI_LAYOUT_VERSION {
+            return Err(anyhow!(
+                "unsupported OCI layout version {} in {}",
+                layout_file.image_layout_version,
+                layout.reference
+            ));
+        }
+
+        let (manifest_digest, docker_reference) = layout.resolve()?;
+        layout.manifest_digest = manifest_digest;
+        layout.docker_reference = docker_reference;
+
+        Ok(layout)
+    }
+
+    /// The reference the layout was opened with.
+    pub fn reference(&self) -> &LayoutReference {
+        &self.reference
+    }
+
+    /// The digest of the image manifest or index the reference points to.
+    pub fn manifest_digest(&self) -> &str {
+        &self.manifest_digest
+    }
+
+    /// The docker reference of the image, if the layout index names it.
+    pub fn docker_reference(&self) -> Option<&str> {
+        self.docker_reference.as_deref()
+    }
+
+    // Resolve the digest of the image manifest or index the reference
+    // points to, with the docker reference of the image.
+    fn resolve(&self) -> Result<(String, Option<String>)> {
+        if let Some(digest) = &self.reference.digest {
+            self.blob_name(digest)?;
+            return Ok((digest.clone(), None));
+        }
+
+        let index: LayoutIndex =
+            serde_json::from_slice(&self.read_file(INDEX_FILE)?).map_err(|e| {
+                anyhow!(
+                    "failed to parse {} of {}: {}",
+                    INDEX_FILE,
+                    self.reference,
+                    e
+                )
+            })?;
+
+        let entries: Vec<&LayoutIndexEntry> = match &self.reference.tag {
+            Some(tag) => index
+                .manifests
+                .iter()
+                .filter(|entry| entry.annotations.get(REF_NAME_ANNOTATION) == Some(tag))
+                .collect(),
+            None => index.manifests.iter().collect(),
+        };
+
+        match entries.as_slice() {
+            [entry] => Ok((entry.digest.clone(), docker_reference(entry))),
+            [] => Err(anyhow!("no image {} in the OCI layout", self.reference)),
+            _ => Err(anyhow!(
+                "{} images match {} in the OCI layout, a tag or digest is required",
+                entries.len(),
+                self.reference
+            )),
+        }
+    }
+
+    /// The name of the blob `digest` in the layout, it is an error if the
+    /// digest is not a valid one or the blob is not in the layout.
+    pub fn blob_name(&self, digest: &str) -> Result<String> {
+        // The digest becomes a path, it must not be able to escape the layout.
+        let (algorithm, encoded) = split_digest(digest)?;
+
+        let name = format!("{}/{}/{}", BLOBS_DIR, algorithm, encoded);
+        let found = match &self.archive {
+            Some(archive) => archive.contains(&name),
+            None => self.reference.path.join(&name).is_file(),
+        };
+        if !found {
+            return Err(anyhow!("blob {} not found in {}", digest, self.reference));
+        }
+
+        Ok(name)
+    }
+
+    /// Read the whole manifest blob `digest`, within the manifest size limit.
+    pub fn read_manifest(&self, digest: &str) -> Result<Vec<u8>> {
+        self.read_file(&self.blob_name(digest)?)
+    }
+
+    /// Write the blob `digest` from `offset` into `out`.
+    pub async fn copy_blob<T: AsyncWrite + Unpin>(
+        &self,
+        digest: &str,
+        offset: u64,
+        mut out: T,
+    ) -> Result<()> {
+        let name = self.blob_name(digest)?;
+        if let Some(archive) = &self.archive {
+            return archive.copy(&name, offset, out).await;
+        }
+
+        let mut file = tokio::fs::File::open(self.reference.path.join(&name)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        tokio::io::copy(&mut file, &mut out).await?;
+        out.flush().await?;
+
+        Ok(())
+    }
+
+    // Read the file `name` of the layout.
+    fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        match &self.archive {
+            Some(archive) => archive.read(name, Limit::ManifestBytes, self.max_manifest_bytes),
+            None => {
+                let path = self.reference.path.join(name);
+                let file = File::open(&path)
+                    .map_err(|e| anyhow!("failed to read OCI layout {:?}: {}", path, e))?;
+                read_limited(file, Limit::ManifestBytes, self.max_manifest_bytes)
+            }
+        }
+    }
+}
+
+// The docker reference of the image of a layout index entry, either as set
+// by containerd, or as the image name when it is a fully qualified one.
+fn docker_reference(entry: &LayoutIndexEntry) -> Option<String> {
+    entry
+        .annotations
+        .get(CONTAINERD_IMAGE_NAME_ANNOTATION)
+        .or_else(|| {
+            entry
+                .annotations
+                .get(REF_NAME_ANNOTATION)
+                .filter(|name| name.contains('/'))
+        })
+        .and_then(|name| Reference::try_from(name.as_str()).ok())
+        .map(|reference| reference.whole())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::digest_bytes;
+    use std::fs;
+    use std::path::Path;
+
+    fn write_blob(dir: &Path, data: &[u8]) -> String {
+        let digest = digest_bytes(data, None).unwrap();
+        let (algorithm, encoded) = digest.split_once(':').unwrap();
+        fs::create_dir_all(dir.join(BLOBS_DIR).join(algorithm)).unwrap();
+        fs::write(dir.join(BLOBS_DIR).join(algorithm).join(encoded), data).unwrap();
+        digest
+    }
+
+    #[test]
+    fn test_layout_reference() {
+        let tests = &[
+            ("oci:/layout", "/layout", None, None),
+            ("oci:/layout:latest", "/layout", Some("latest"), None),
+            (
+                "oci:/layout@sha256:1234",
+                "/layout",
+                None,
+                Some("sha256:1234"),
+            ),
+            ("oci:layout:v1", "layout", Some("v1"), None),
+            ("oci:/a:b/layout", "/a:b/layout", None, None),
+            (
+                "oci-archive:/layout.tar:v1",
+                "/layout.tar",
+                Some("v1"),
+                None,
+            ),
+        ];
+
+        for (s, path, tag, digest) in tests.iter() {
+            let reference = LayoutReference::from_str(s).unwrap();
+            assert_eq!(reference.path, PathBuf::from(path));
+            assert_eq!(reference.archive, s.starts_with(OCI_ARCHIVE_PREFIX));
+            assert_eq!(reference.tag.as_deref(), *tag);
+            assert_eq!(reference.digest.as_deref(), *digest);
+            assert_eq!(&reference.to_string(), s);
+        }
+
+        for s in ["/layout", "oci:", "oci:/layout:", "oci:/layout@"].iter() {
+            assert!(LayoutReference::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oci_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |s: &str| {
+            OciLayout::open(
+                LayoutReference::from_str(&format!("oci:{}{}", dir.path().display(), s)).unwrap(),
+                &LimitsConfig::default(),
+            )
+        };
+
+        assert!(open("").is_err());
+        fs::write(
+            dir.path().join(OCI_LAYOUT_FILE),
+            r#"{"imageLayoutVersion": "2.0.0"}"#,
+        )
+        .unwrap();
+        assert!(open("").is_err());
+        fs::write(
+            dir.path().join(OCI_LAYOUT_FILE),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+
+        let v1 = write_blob(dir.path(), b"manifest v1");
+        let v2 = write_blob(dir.path(), b"manifest v2");
+        let index = format!(
+            r#"{{"schemaVersion": 2, "manifests": [
+                {{"digest": "{}", "annotations": {{"{}": "v1"}}}},
+                {{"digest": "{}", "annotations": {{"{}": "v2", "{}": "quay.io/busybox:v2"}}}}
+            ]}}"#,
+            v1, REF_NAME_ANNOTATION, v2, REF_NAME_ANNOTATION, CONTAINERD_IMAGE_NAME_ANNOTATION
+        );
+        fs::write(dir.path().join(INDEX_FILE), index).unwrap();
+
+        assert_eq!(open(":v1").unwrap().manifest_digest(), v1);
+        assert_eq!(open(":v2").unwrap().manifest_digest(), v2);
+        assert!(open(":v3").is_err());
+        assert!(open("").is_err());
+        assert_eq!(open(&format!("@{}", v2)).unwrap().manifest_digest(), v2);
+        assert_eq!(
+            open(":v2").unwrap().docker_reference(),
+            Some("quay.io/busybox:v2")
+        );
+        assert_eq!(open(":v1").unwrap().docker_reference(), None);
+
+        let layout = open(":v1").unwrap();
+        assert_eq!(layout.read_manifest(&v1).unwrap(), b"manifest v1");
+        let mut out = Vec::new();
+        layout.copy_blob(&v2, 9, &mut out).await.unwrap();
+        assert_eq!(out, b"v2");
+
+        assert!(layout.read_manifest("sha256:../../etc/passwd").is_err());
+        assert!(layout
+            .read_manifest(&format!("sha256:{}", "0".repeat(64)))
+            .is_err());
+
+        // The same layout, in an archive.
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_dir_all(".", dir.path()).unwrap();
+        let archive_path = dir.path().join("layout.tar");
+        fs::write(&archive_path, builder.into_inner().unwrap()).unwrap();
+
+        let reference = format!("oci-archive:{}:v2", archive_path.display());
+        let reference = LayoutReference::from_str(&reference).unwrap();
+        let layout = OciLayout::open(reference.clone(), &LimitsConfig::default()).unwrap();
+        assert_eq!(layout.manifest_digest(), v2);
+        let mut out = Vec::new();
+        layout.copy_blob(&v2, 0, &mut out).await.unwrap();
+        assert_eq!(out, b"manifest v2");
+
+        // The layout index is read within the manifest size limit, from a
+        // directory or an archive.
+        let limits = LimitsConfig {
+            max_manifest_bytes: 32,
+            ..Default::default()
+        };
+        assert!(OciLayout::open(reference, &limits).is_err());
+        let reference =
+            LayoutReference::from_str(&format!("oci:{}:v2", dir.path().display())).unwrap();
+        assert!(OciLayout::open(reference, &limits).is_err());
+    }
+}
@@ -8,13 +8,27 @@ extern crate strum;
 /// Environment macro for `image-rs` work dir.
 pub const CC_IMAGE_WORK_DIR: &str = "CC_IMAGE_WORK_DIR";
 
+pub mod archive;
+pub mod auth;
+pub mod blob_cache;
 pub mod bundle;
 pub mod config;
 pub mod decoder;
 pub mod decrypt;
+pub mod digest;
+pub mod dir;
+pub mod errors;
 pub mod image;
+pub mod layout;
 pub mod meta_store;
+pub mod platform;
+pub mod progress;
 pub mod pull;
+pub mod registry;
+pub mod scheduler;
 pub mod snapshots;
+pub mod source;
+#[cfg(any(test, feature = "test-registry"))]
+pub mod test_registry;
 pub mod unpack;
 pub mod validate;
@@ -4,6 +4,8 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 
 use crate::image::{ImageMeta, LayerMeta};
 
@@ -20,6 +22,16 @@ pub struct MetaStore {
 
     // snapshot_db holds map of snapshot with work dir index.
     pub snapshot_db: HashMap<String, usize>,
+
+    // image_refs holds map of image reference with the ID of the image it
+    // was last pulled as, a reference maps to one image at most.
+    #[serde(default)]
+    pub image_refs: HashMap<String, String>,
+
+    // layer_pulls holds map of layer digest with in-progress layer pull,
+    // for concurrent pulls of the same layer to wait for it.
+    #[serde(skip)]
+    pub layer_pulls: HashMap<String, Arc<OnceCell<LayerMeta>>>,
 }
 
 impl TryFrom<&Path> for MetaStore {
@@ -32,3 +44,35 @@ impl TryFrom<&Path> for MetaStore {
             .map_err(|e| anyhow!("failed to parse metastore file {}", e.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::ImageConfiguration;
+
+    #[test]
+    fn test_meta_store_without_platform() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join(METAFILE);
+        let meta_store = serde_json::json!({
+            "image_db": {
+                "sha256:1234": {
+                    "id": "sha256:1234",
+                    "digest": "sha256:5678",
+                    "reference": "docker.io/library/busybox:latest",
+                    "image_config": ImageConfiguration::default(),
+                    "signed": false,
+                    "layer_metas": [],
+                },
+            },
+            "layer_db": {},
+            "snapshot_db": {},
+        });
+        std::fs::write(&path, meta_store.to_string()).unwrap();
+
+        let meta_store = MetaStore::try_from(path.as_path()).unwrap();
+        let image = &meta_store.image_db["sha256:1234"];
+        assert_eq!(image.reference, "docker.io/library/busybox:latest");
+        assert_eq!(image.platform, Default::default());
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_spec::image::ImageConfiguration;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// The platform an image is built for, as described by the entries of
+/// an OCI image index or a Docker manifest list.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct Platform {
+    /// CPU architecture, in the GOARCH format.
+    pub architecture: String,
+
+    /// Operating system, in the GOOS format.
+    pub os: String,
+
+    /// Operating system version.
+    #[serde(rename = "os.version", default)]
+    pub os_version: Option<String>,
+
+    /// Mandatory operating system features.
+    #[serde(rename = "os.features", default)]
+    pub os_features: Vec<String>,
+
+    /// Variant of the CPU architecture.
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Construct the platform `image-rs` is running on.
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            arch => arch,
+        };
+
+        Platform {
+            architecture: architecture.to_string(),
+            os: std::env::consts::OS.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Check whether an image built for `candidate` can run on this platform.
+    /// The variant is only compared when it is set on both sides, once the
+    /// default variants are normalized, and all the OS features the candidate
+    /// requires must be provided by this platform.
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        if self.os != candidate.os || self.architecture != candidate.architecture {
+            return false;
+        }
+
+        if let (Some(variant), Some(other)) =
+            (self.normalized_variant(), candidate.normalized_variant())
+        {
+            if variant != other {
+                return false;
+            }
+        }
+
+        candidate
+            .os_features
+            .iter()
+            .all(|feature| self.os_features.contains(feature))
+    }
+
+    // The variant of architectures where it may be omitted, e.g. an arm64
+    // image without a variant is an arm64/v8 image.
+    fn normalized_variant(&self) -> Option<&str> {
+        match (self.architecture.as_str(), self.variant.as_deref()) {
+            (_, Some(variant)) if !variant.is_empty() => Some(variant),
+            ("arm64", _) => Some("v8"),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// Parse a platform in "os/architecture[/variant]" format, like
+    /// "linux/arm64/v8".
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(anyhow!("invalid platform: {:?}", s));
+        }
+
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|v| v.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+impl From<&ImageConfiguration> for Platform {
+    fn from(config: &ImageConfiguration) -> Self {
+        Platform {
+            architecture: config.architecture().to_string(),
+            os: config.os().to_string(),
+            os_version: config.os_version().clone(),
+            os_features: config.os_features().clone().unwrap_or_default(),
+            variant: config.variant().clone(),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_from_str() {
+        let platform = Platform::from_str("linux/arm64/v8").unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm64");
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+
+        let platform = Platform::from_str("linux/amd64").unwrap();
+        assert_eq!(platform.variant, None);
+        assert_eq!(platform.to_string(), "linux/amd64");
+
+        for case in ["", "linux", "linux/", "/amd64", "linux/arm/v7/extra"].iter() {
+            assert!(Platform::from_str(case).is_err());
+        }
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let platform = Platform::from_str("linux/arm64").unwrap();
+        assert!(platform.matches(&Platform::from_str("linux/arm64/v8").unwrap()));
+        assert!(!platform.matches(&Platform::from_str("linux/arm64/v9").unwrap()));
+        assert!(!platform.matches(&Platform::from_str("linux/amd64").unwrap()));
+        assert!(!platform.matches(&Platform::from_str("windows/arm64").unwrap()));
+
+        let platform = Platform::from_str("linux/arm/v7").unwrap();
+        assert!(platform.matches(&Platform::from_str("linux/arm").unwrap()));
+        assert!(!platform.matches(&Platform::from_str("linux/arm/v6").unwrap()));
+
+        let mut candidate = Platform::from_str("linux/amd64").unwrap();
+        candidate.os_features = vec!["sse4".to_string()];
+        let mut platform = Platform::from_str("linux/amd64").unwrap();
+        assert!(!platform.matches(&candidate));
+        platform.os_features = vec!["sse4".to_string()];
+        assert!(platform.matches(&candidate));
+    }
+}
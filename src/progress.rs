@@ -0,0 +1,281 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Layer download progress is reported at most once per this many bytes,
+/// and when the download completes.
+const DOWNLOAD_PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// The stage of an image pull reported by a progress event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PullStage {
+    /// The image manifest is resolved, for the platform to pull.
+    ManifestResolved { reference: String, layers: usize },
+
+    /// Layer bytes downloaded so far, out of the layer size.
+    Downloading { downloaded: u64, total: u64 },
+
+    /// The layer is being decrypted.
+    Decrypting,
+
+    /// The layer is being decompressed.
+    Decompressing,
+
+    /// The layer is being unpacked.
+    Unpacking,
+
+    /// The layer is already in the layer store, it is not pulled.
+    LayerCached,
+
+    /// The image was validated against the security policy.
+    Validated { allowed: bool },
+
+    /// The image rootfs is mounted in the bundle.
+    Mounted,
+}
+
+/// A progress event of an image pull.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PullProgress {
+    /// The digest of the layer the event is about, or of the image manifest
+    /// for the events about the whole image.
+    pub digest: String,
+
+    /// The stage the pull reached.
+    pub stage: PullStage,
+
+    /// The time elapsed since the pull started.
+    pub elapsed: Duration,
+}
+
+/// ProgressReporter sends the progress events of one image pull, if anyone
+/// listens to them. Events are dropped once the receiver is gone, the pull
+/// never waits for them to be handled.
+#[derive(Clone, Debug)]
+pub struct ProgressReporter {
+    sender: Option<UnboundedSender<PullProgress>>,
+    start: Instant,
+}
+
+impl Default for ProgressReporter {
+    fn default() -> ProgressReporter {
+        ProgressReporter::new(None)
+    }
+}
+
+impl ProgressReporter {
+    /// Construct a new ProgressReporter sending events to `sender`, with
+    /// the times of the events counted from now.
+    pub fn new(sender: Option<UnboundedSender<PullProgress>>) -> Self {
+        ProgressReporter {
+            sender,
+            start: Instant::now(),
+        }
+    }
+
+    /// Report that the pull reached `stage` for `digest`.
+    pub fn report(&self, digest: &str, stage: PullStage) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(PullProgress {
+                digest: digest.to_string(),
+                stage,
+                elapsed: self.start.elapsed(),
+            });
+        }
+    }
+}
+
+// The download progress of a layer blob, reported as the blob data goes by.
+struct DownloadProgress {
+    reporter: ProgressReporter,
+    digest: String,
+    total: u64,
+    downloaded: u64,
+    reported: u64,
+}
+
+impl DownloadProgress {
+    fn new(reporter: ProgressReporter, digest: &str, total: u64, offset: u64) -> Self {
+        reporter.report(
+            digest,
+            PullStage::Downloading {
+                downloaded: offset,
+                total,
+            },
+        );
+
+        DownloadProgress {
+            reporter,
+            digest: digest.to_string(),
+            total,
+            downloaded: offset,
+            reported: offset,
+        }
+    }
+
+    fn add(&mut self, n: usize) {
+        self.downloaded += n as u64;
+
+        if n > 0
+            && (self.downloaded - self.reported >= DOWNLOAD_PROGRESS_INTERVAL
+                || self.downloaded == self.total)
+        {
+            self.reported = self.downloaded;
+            self.reporter.report(
+                &self.digest,
+                PullStage::Downloading {
+                    downloaded: self.downloaded,
+                    total: self.total,
+                },
+            );
+        }
+    }
+}
+
+/// ProgressReader reports the download progress of a layer blob as the
+/// blob is read through it.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: DownloadProgress,
+}
+
+impl<R> ProgressReader<R> {
+    /// Wrap `inner`, the blob `digest` of `total` size from which `offset`
+    /// bytes are already downloaded.
+    pub fn new(
+        inner: R,
+        reporter: ProgressReporter,
+        digest: &str,
+        total: u64,
+        offset: u64,
+    ) -> Self {
+        ProgressReader {
+            inner,
+            progress: DownloadProgress::new(reporter, digest, total, offset),
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.add(n);
+        Ok(n)
+    }
+}
+
+/// ProgressWriter reports the download progress of a layer blob as the
+/// blob is written through it.
+pub struct ProgressWriter<W> {
+    inner: W,
+    progress: DownloadProgress,
+}
+
+impl<W> ProgressWriter<W> {
+    /// Wrap `inner`, the blob `digest` of `total` size from which `offset`
+    /// bytes are already downloaded.
+    pub fn new(
+        inner: W,
+        reporter: ProgressReporter,
+        digest: &str,
+        total: u64,
+        offset: u64,
+    ) -> Self {
+        ProgressWriter {
+            inner,
+            progress: DownloadProgress::new(reporter, digest, total, offset),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            self.progress.add(*n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_progress_reader() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let reporter = ProgressReporter::new(Some(sender));
+
+        let data = vec![0u8; 3 * DOWNLOAD_PROGRESS_INTERVAL as usize];
+        let total = data.len() as u64 + 100;
+        let mut reader = ProgressReader::new(&data[..], reporter, "sha256:1234", total, 100);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+
+        let mut downloaded = Vec::new();
+        while let Ok(progress) = receiver.try_recv() {
+            assert_eq!(progress.digest, "sha256:1234");
+            match progress.stage {
+                PullStage::Downloading {
+                    downloaded: d,
+                    total: t,
+                } if t == total => downloaded.push(d),
+                stage => panic!("unexpected stage {:?}", stage),
+            }
+        }
+
+        assert_eq!(downloaded.first(), Some(&100));
+        assert_eq!(downloaded.last(), Some(&total));
+        assert!(downloaded.len() <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_progress_writer() {
+        use tokio::io::AsyncWriteExt;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let reporter = ProgressReporter::new(Some(sender));
+
+        let mut out = Vec::new();
+        let mut writer = ProgressWriter::new(&mut out, reporter, "sha256:1234", 10, 4);
+        writer.write_all(b"abcdef").await.unwrap();
+        assert_eq!(out, b"abcdef");
+
+        let mut downloaded = Vec::new();
+        while let Ok(progress) = receiver.try_recv() {
+            if let PullStage::Downloading { downloaded: d, .. } = progress.stage {
+                downloaded.push(d);
+            }
+        }
+        assert_eq!(downloaded, vec![4, 10]);
+    }
+
+    #[test]
+    fn test_progress_reporter_without_receiver() {
+        ProgressReporter::default().report("sha256:1234", PullStage::Mounted);
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        drop(receiver);
+        ProgressReporter::new(Some(sender)).report("sha256:1234", PullStage::Mounted);
+    }
+}
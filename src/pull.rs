@@ -5,72 +5,382 @@
 use anyhow::{anyhow, Result};
 use futures_util::future;
 use oci_distribution::manifest::{OciDescriptor, OciImageManifest};
-use oci_distribution::{manifest, secrets::RegistryAuth, Client, Reference};
+use oci_distribution::{manifest, Reference};
 use oci_spec::image::MediaType;
-use sha2::Digest;
+use serde::Deserialize;
 use std::convert::TryFrom;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
+use tokio_util::io::SyncIoBridge;
+use tokio_util::sync::CancellationToken;
 
-use crate::decoder::Compression;
+use crate::auth::Credential;
+use crate::blob_cache::BlobCache;
+use crate::config::{ForeignLayerConfig, LimitsConfig};
+use crate::decoder::{sniff_format, Compression, CompressionDetection};
 use crate::decrypt::Decryptor;
+use crate::digest::{digest_bytes, split_digest, HashReader, VerifyReader, DIGEST_SHA256};
+use crate::errors::{Limit, PullError};
 use crate::image::LayerMeta;
 use crate::meta_store::MetaStore;
+use crate::platform::Platform;
+use crate::progress::{ProgressReader, ProgressReporter, ProgressWriter, PullStage};
+use crate::registry::RegistryClient;
+use crate::scheduler::PullScheduler;
+use crate::source::{ImageSource, RegistrySource};
 use crate::unpack::unpack;
 
-const DIGEST_SHA256: &str = "sha256";
-const DIGEST_SHA512: &str = "sha512";
+/// The buffer size of the pipe between a layer download and its unpacking.
+const LAYER_PIPE_SIZE: usize = 64 * 1024;
+
+/// The media type of the Docker foreign layers, like Windows base layers.
+const IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+/// The media types of bzip2 and xz compressed layers. OCI defines none, these
+/// follow the OCI tar+gzip naming, next to the generic MIME types.
+const IMAGE_LAYER_BZIP2_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.layer.v1.tar+bzip2",
+    "application/x-bzip2",
+];
+const IMAGE_LAYER_XZ_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.layer.v1.tar+xz",
+    "application/x-xz",
+];
+
+/// The prefix of the OCI non-distributable layer media types, encrypted or
+/// not.
+const OCI_NON_DISTRIBUTABLE_LAYER_PREFIX: &str =
+    "application/vnd.oci.image.layer.nondistributable.";
 
 /// The PullClient connects to remote OCI registry, pulls the container image,
 /// and save the image layers under data_dir and return the layer meta info.
 pub struct PullClient {
-    /// `oci-distribuion` client to talk with remote OCI registry.
-    pub client: Client,
-
-    /// OCI registry auth info.
-    pub auth: RegistryAuth,
+    /// Registry client to talk with remote OCI registry.
+    pub client: RegistryClient,
 
     /// OCI image reference.
     pub reference: Reference,
 
+    /// The references to pull the image from, tried in order, like the
+    /// mirrors of the image registry. Defaults to `reference`.
+    pub sources: Vec<Reference>,
+
+    // The source the image manifest was pulled from, the layers are
+    // pulled from the same source first, and from the other sources when
+    // they fail there.
+    source: Reference,
+
+    // The local image source to pull the image from instead of a registry.
+    local: Option<Arc<dyn ImageSource>>,
+
     /// OCI image layer data store dir.
     pub data_dir: PathBuf,
+
+    /// The platform to select from multi-platform images.
+    pub platform: Platform,
+
+    /// The scheduler bounding the concurrent layer downloads and unpacking.
+    pub scheduler: Arc<PullScheduler>,
+
+    /// The reporter of the layers pull progress.
+    pub progress: ProgressReporter,
+
+    /// The token to cancel the pull with. A cancelled pull stops its
+    /// downloads and blocking work, and removes its partial layers.
+    pub cancel: CancellationToken,
+
+    /// The untrusted cache to look the image blobs up in, before pulling
+    /// them from the image source.
+    pub blob_cache: Option<Arc<BlobCache>>,
+
+    /// How the foreign layers are pulled from the URLs of their descriptor.
+    pub foreign_layers: ForeignLayerConfig,
+
+    /// The limits on the data the pull may unpack or buffer.
+    pub limits: LimitsConfig,
+
+    /// How the compression of the layers is detected from their magic bytes.
+    pub compression_detection: CompressionDetection,
+
+    // The uncompressed bytes of the layers unpacked so far.
+    image_bytes: Arc<AtomicU64>,
+}
+
+/// The entries of an OCI image index or a Docker manifest list.
+#[derive(Deserialize)]
+struct ImageIndex {
+    manifests: Vec<ImageIndexEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageIndexEntry {
+    media_type: String,
+    digest: String,
+    platform: Option<Platform>,
 }
 
 impl PullClient {
     /// Constructs a new PullClient struct with provided image info,
-    /// data store dir and optional remote registry auth info.
+    /// data store dir and optional remote registry auth info in
+    /// "username:password" format.
     pub fn new(image: &str, data_dir: &Path, auth_info: &Option<&str>) -> Result<PullClient> {
-        let mut auth = RegistryAuth::Anonymous;
-        if let Some(auth_info) = auth_info {
-            if let Some((username, password)) = auth_info.split_once(':') {
-                auth = RegistryAuth::Basic(username.to_string(), password.to_string());
-            } else {
-                return Err(anyhow!("Invalid authentication info ({:?})", auth_info));
-            }
-        }
+        let credential = match auth_info {
+            Some(auth_info) => Credential::try_from(*auth_info)?,
+            None => Credential::Anonymous,
+        };
 
+        Self::with_credential(image, data_dir, credential)
+    }
+
+    /// Constructs a new PullClient struct with provided image info,
+    /// data store dir and remote registry credential.
+    pub fn with_credential(
+        image: &str,
+        data_dir: &Path,
+        credential: Credential,
+    ) -> Result<PullClient> {
         let reference = Reference::try_from(image)?;
-        let client = Client::default();
+        let mut client = RegistryClient::new()?;
+        client.set_credential(&reference, credential);
+
+        Ok(Self::with_client(client, reference, None, data_dir))
+    }
+
+    /// Constructs a new PullClient struct pulling the image of a local
+    /// image source, with the provided data store dir.
+    pub fn from_local(local: Box<dyn ImageSource>, data_dir: &Path) -> Result<PullClient> {
+        // The image is pinned to the manifest digest the local source
+        // resolves to, registry and repository have no meaning locally.
+        let reference = Reference::with_digest(
+            String::new(),
+            String::new(),
+            local.manifest_digest().unwrap_or_default().to_string(),
+        );
+
+        Ok(Self::with_client(
+            RegistryClient::new()?,
+            reference,
+            Some(Arc::from(local)),
+            data_dir,
+        ))
+    }
+
+    /// The local image source the image is pulled from, if not from
+    /// a registry.
+    pub fn local(&self) -> Option<&dyn ImageSource> {
+        self.local.as_deref()
+    }
 
-        Ok(PullClient {
+    fn with_client(
+        client: RegistryClient,
+        reference: Reference,
+        local: Option<Arc<dyn ImageSource>>,
+        data_dir: &Path,
+    ) -> PullClient {
+        PullClient {
             client,
-            auth,
+            sources: vec![reference.clone()],
+            source: reference.clone(),
+            local,
             reference,
             data_dir: data_dir.to_path_buf(),
-        })
+            platform: Platform::host(),
+            scheduler: Arc::new(PullScheduler::default()),
+            progress: ProgressReporter::default(),
+            cancel: CancellationToken::new(),
+            blob_cache: None,
+            foreign_layers: ForeignLayerConfig::default(),
+            limits: LimitsConfig::default(),
+            compression_detection: CompressionDetection::default(),
+            image_bytes: Arc::new(AtomicU64::new(0)),
+        }
     }
 
     /// pull_manifest pulls an image manifest and config data.
+    /// If the reference points to an OCI image index or a Docker manifest
+    /// list, the image manifest matching the requested platform is pulled.
+    /// The returned image digest is computed locally from the manifest data,
+    /// the digest reported by the registry is never trusted.
+    ///
+    /// The sources are tried in order until one of them succeeds, and the
+    /// image layers are pulled from that source afterwards, falling back to
+    /// the other sources blob by blob.
     pub async fn pull_manifest(&mut self) -> Result<(OciImageManifest, String, String)> {
         self.client
-            .pull_manifest_and_config(&self.reference, &self.auth)
-            .await
+            .set_max_manifest_bytes(self.limits.max_manifest_bytes);
+        if let Some(local) = &self.local {
+            return self
+                .cancellable(self.pull_manifest_from(local.as_ref()))
+                .await;
+        }
+
+        let mut failures = Vec::new();
+        let mut last_err = anyhow!("no source to pull image {}", self.name());
+
+        for source in self.sources.clone() {
+            let registry = RegistrySource::new(&self.client, source.clone());
+            match self.cancellable(self.pull_manifest_from(&registry)).await {
+                Ok(res) => {
+                    self.source = source;
+                    return Ok(res);
+                }
+                Err(e) if self.cancel.is_cancelled() => return Err(e),
+                Err(e) => {
+                    failures.push(format!("{}: {}", registry, e));
+                    last_err = e;
+                }
+            }
+        }
+
+        if failures.len() > 1 {
+            failures.pop();
+            last_err = last_err.context(format!(
+                "pull image {} failed from all sources, earlier failures: {}",
+                self.name(),
+                failures.join("; ")
+            ));
+        }
+
+        Err(last_err)
+    }
+
+    // Pull the image manifest and config data from `source`.
+    async fn pull_manifest_from(
+        &self,
+        source: &dyn ImageSource,
+    ) -> Result<(OciImageManifest, String, String)> {
+        let pinned_digest = source.manifest_digest().map(|d| d.to_string());
+        let mut manifest_data = self
+            .pull_verified_manifest(source, None, pinned_digest.as_deref())
+            .await?;
+
+        if let Ok(index) = serde_json::from_slice::<ImageIndex>(&manifest_data) {
+            let entry = self.select_manifest(&index)?;
+            manifest_data = self
+                .pull_verified_manifest(source, Some(&entry.digest), Some(&entry.digest))
+                .await?;
+        }
+
+        // The image manifest is identified by its sha256 digest, unless it
+        // is pinned with another algorithm.
+        let algorithm_hint = pinned_digest.filter(|d| !d.starts_with(DIGEST_SHA256));
+        let image_digest = digest_bytes(&manifest_data, algorithm_hint.as_deref())?;
+
+        let image_manifest: OciImageManifest = serde_json::from_slice(&manifest_data)
+            .map_err(|e| anyhow!("failed to parse image manifest: {}", e))?;
+
+        let image_config = self.pull_config(source, &image_manifest.config).await?;
+
+        let config_digest = digest_bytes(&image_config, Some(&image_manifest.config.digest))?;
+        if config_digest != image_manifest.config.digest {
+            return Err(PullError::ConfigDigestMismatch {
+                reference: source.to_string(),
+                expected: image_manifest.config.digest.clone(),
+                actual: config_digest,
+            }
+            .into());
+        }
+
+        Ok((
+            image_manifest,
+            image_digest,
+            String::from_utf8(image_config)?,
+        ))
+    }
+
+    // Pull the raw manifest data of `source`, or the manifest `digest` of
+    // its image index, and verify it against `expected_digest` if the
+    // manifest is pinned.
+    async fn pull_verified_manifest(
+        &self,
+        source: &dyn ImageSource,
+        digest: Option<&str>,
+        expected_digest: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let manifest_data = source.get_manifest(digest).await?;
+        if manifest_data.len() as u64 > self.limits.max_manifest_bytes {
+            return Err(PullError::LimitExceeded {
+                limit: Limit::ManifestBytes,
+                max: self.limits.max_manifest_bytes,
+            }
+            .into());
+        }
+
+        if let Some(expected) = expected_digest {
+            let digest = digest_bytes(&manifest_data, Some(expected))?;
+            if digest != expected {
+                return Err(PullError::ManifestDigestMismatch {
+                    reference: source.to_string(),
+                    expected: expected.to_string(),
+                    actual: digest,
+                }
+                .into());
+            }
+        }
+
+        Ok(manifest_data)
+    }
+
+    // Select the image manifest matching the requested platform from an
+    // image index or manifest list.
+    fn select_manifest<'a>(&self, index: &'a ImageIndex) -> Result<&'a ImageIndexEntry> {
+        index
+            .manifests
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry.media_type.as_str(),
+                    manifest::OCI_IMAGE_MEDIA_TYPE | manifest::IMAGE_MANIFEST_MEDIA_TYPE
+                )
+            })
+            .find(|entry| match &entry.platform {
+                Some(platform) => self.platform.matches(platform),
+                None => false,
+            })
+            .ok_or_else(|| {
+                let available: Vec<String> = index
+                    .manifests
+                    .iter()
+                    .filter_map(|entry| entry.platform.as_ref().map(|p| p.to_string()))
+                    .collect();
+                anyhow!(
+                    "no image manifest for platform {} in {}, available platforms: {:?}",
+                    self.platform,
+                    self.name(),
+                    available
+                )
+            })
     }
 
     /// pull_layers pulls an image layers and do ondemand decrypt/decompress.
+    /// Each layer is streamed from the registry through decryption,
+    /// decompression and unpacking, so memory usage is bounded no matter
+    /// how big the layer is.
+    ///
+    /// Layers already in the layer db are reused without any network I/O,
+    /// and concurrent pulls of the same layer are merged, so that the layer
+    /// is fetched and unpacked only once while the other callers wait for it.
+    /// The pulled layers are added to the layer db once all the layer pulls
+    /// are done, unless the pull was cancelled. The layers of a cancelled
+    /// pull are removed then, except those another pull is waiting for.
+    ///
+    /// The downloads and unpacking are bounded by the scheduler, which
+    /// starts them in layer order, from the base layer up. A layer waiting
+    /// for an unpack slot is downloaded into its partial file meanwhile.
+    ///
+    /// Foreign layers are fetched from the allowed URLs of their descriptor
+    /// first, and from the image source if none succeeds. No layer is
+    /// pulled if foreign layers are refused and the image has one.
     /// It returns the layer metadata for layer db to track.
     pub async fn pull_layers(
         &self,
@@ -79,155 +389,1527 @@ impl PullClient {
         decrypt_config: &Option<&str>,
         meta_store: Arc<Mutex<MetaStore>>,
     ) -> Result<Vec<LayerMeta>> {
-        let layer_metas = layer_descs.into_iter().enumerate().map(|(i, layer)| {
-            let client = &self.client;
-            let reference = &self.reference;
+        if self.foreign_layers.refuse {
+            if let Some(layer) = layer_descs
+                .iter()
+                .find(|layer| is_foreign_layer(&layer.media_type))
+            {
+                return Err(PullError::ForeignLayerRefused {
+                    layer: layer.digest.clone(),
+                }
+                .into());
+            }
+        }
+
+        // A layer pull stays in `layer_pulls` until its layer is added to
+        // the layer db or removed, for the other pulls to wait for it.
+        let layer_pulls = layer_descs.into_iter().enumerate().map(|(i, layer)| {
             let ms = meta_store.clone();
             async move {
-                let mut out: Vec<u8> = Vec::new();
-                let mut layer_data: Vec<u8> = Vec::new();
-                let plaintext_layer: Vec<u8>;
+                let decryptor = Decryptor::from_media_type(&layer.media_type);
+                if decryptor.is_encrypted() && decrypt_config.is_none() {
+                    return (layer.digest, None, Err(anyhow!("decrypt_config is None")));
+                }
 
-                client
-                    .pull_blob(reference, &layer.digest, &mut layer_data)
-                    .await?;
+                let layer_pull = {
+                    let mut ms = ms.lock().await;
+                    if let Some(layer_meta) = ms.layer_db.get(&layer.digest) {
+                        self.progress.report(&layer.digest, PullStage::LayerCached);
+                        return (layer.digest, None, Ok(layer_meta.clone()));
+                    }
 
-                let mut layer_meta = LayerMeta::default();
-                let mut media_type_str: &str = layer.media_type.as_str();
+                    ms.layer_pulls
+                        .entry(layer.digest.clone())
+                        .or_default()
+                        .clone()
+                };
 
-                let decryptor = Decryptor::from_media_type(&layer.media_type);
-                if decryptor.is_encrypted() {
-                    if let Some(dc) = decrypt_config {
-                        plaintext_layer = decryptor
-                            .get_plaintext_layer(&layer, layer_data, dc)
-                            .await?;
-                        media_type_str = decryptor.media_type.as_str();
-                        layer_meta.encrypted = true;
-                    } else {
-                        return Err(anyhow!("decrypt_config is None"));
+                let res = layer_pull
+                    .get_or_try_init(|| self.pull_layer(&layer, &diff_ids[i], decrypt_config))
+                    .await
+                    .cloned();
+
+                (layer.digest, Some(layer_pull), res)
+            }
+        });
+
+        // All the layer pulls are waited for, even once one failed, so that
+        // none is left behind with its partial data.
+        let layer_pulls = future::join_all(layer_pulls).await;
+
+        let cancelled = self.cancel.is_cancelled();
+        let mut ms = meta_store.lock().await;
+        for (digest, layer_pull, res) in layer_pulls.iter() {
+            let layer_pull = match layer_pull {
+                Some(layer_pull) => layer_pull,
+                None => continue,
+            };
+            match ms.layer_pulls.get(digest) {
+                Some(pull) if Arc::ptr_eq(pull, layer_pull) => {}
+                _ => continue,
+            }
+
+            if !cancelled {
+                if let Ok(layer_meta) = res {
+                    ms.layer_db.insert(digest.clone(), layer_meta.clone());
+                }
+                ms.layer_pulls.remove(digest);
+                continue;
+            }
+
+            // The layer is only removed when no other pull holds it, besides
+            // `layer_pulls` and this pull, which may hold it more than once.
+            let held = layer_pulls
+                .iter()
+                .filter(|(_, pull, _)| matches!(pull, Some(pull) if Arc::ptr_eq(pull, layer_pull)))
+                .count();
+            if Arc::strong_count(layer_pull) == held + 1 {
+                if let Some(layer_meta) =
+                    ms.layer_pulls.remove(digest).and_then(|p| p.get().cloned())
+                {
+                    let store_path = Path::new(&layer_meta.store_path);
+                    if store_path.exists() {
+                        fs::remove_dir_all(store_path)?;
                     }
-                } else {
-                    plaintext_layer = layer_data;
                 }
+            }
+        }
+        drop(ms);
+
+        if cancelled {
+            return Err(self.cancelled());
+        }
 
-                let layer_db = &ms.lock().await.layer_db;
-                if let Some(layer_meta) = layer_db.get(&layer.digest) {
-                    return Ok::<_, anyhow::Error>(layer_meta.clone());
+        // A stored layer, or one pulled for another image at the same time,
+        // may have been decrypted with another decrypt config.
+        layer_pulls
+            .into_iter()
+            .map(|(_, _, res)| {
+                let layer_meta = res?;
+                layer_meta.check_decrypt_config(decrypt_config)?;
+                Ok(layer_meta)
+            })
+            .collect()
+    }
+
+    // Pull one layer, decrypt/decompress and unpack it into the data store.
+    async fn pull_layer(
+        &self,
+        layer: &OciDescriptor,
+        diff_id: &str,
+        decrypt_config: &Option<&str>,
+    ) -> Result<LayerMeta> {
+        // The digest becomes the layer paths, it must not be able to escape
+        // the data store before the layer is verified.
+        let (algorithm, encoded) = split_digest(&layer.digest)?;
+        let mut layer_meta = LayerMeta::default();
+        let mut media_type_str: &str = layer.media_type.as_str();
+
+        let decryptor = Decryptor::from_media_type(&layer.media_type);
+        let mut layer_decrypt_config = None;
+        if decryptor.is_encrypted() {
+            if let Some(dc) = decrypt_config {
+                layer_decrypt_config = Some(dc.to_string());
+                media_type_str = decryptor.media_type.as_str();
+                layer_meta.encrypted = true;
+                layer_meta.decrypt_config_digest = Some(digest_bytes(dc.as_bytes(), None)?);
+            } else {
+                return Err(anyhow!("decrypt_config is None"));
+            }
+        }
+
+        // convert docker layer media type to oci format
+        if media_type_str == manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE
+            || media_type_str == IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE
+        {
+            media_type_str = manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE;
+        }
+
+        if media_type_str == manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE {
+            media_type_str = manifest::IMAGE_LAYER_MEDIA_TYPE;
+        }
+
+        // The compression the media type declares is checked against the
+        // layer magic bytes as it is unpacked. Layers of other media types,
+        // like application/octet-stream, are only accepted in lenient mode,
+        // by their magic bytes.
+        let media_type = MediaType::from(media_type_str);
+        let declared_decoder = match media_type {
+            MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => {
+                Some(Compression::Uncompressed)
+            }
+            MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
+                Some(Compression::Gzip)
+            }
+            MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
+                Some(Compression::Zstd)
+            }
+            _ if IMAGE_LAYER_BZIP2_MEDIA_TYPES.contains(&media_type_str) => {
+                Some(Compression::Bzip2)
+            }
+            _ if IMAGE_LAYER_XZ_MEDIA_TYPES.contains(&media_type_str) => Some(Compression::Xz),
+            _ if self.compression_detection == CompressionDetection::Lenient => None,
+            _ => return Err(anyhow!("unhandled media type: {}", &layer.media_type)),
+        };
+
+        layer_meta.compressed_digest = layer.digest.clone();
+
+        let store_path = format!("{}/{}_{}", self.data_dir.display(), algorithm, encoded);
+        let destination = PathBuf::from(&store_path);
+
+        // The data of an interrupted blob download is kept in a partial file,
+        // the download resumes from its end once the data is replayed. A
+        // complete partial file is only replayed, without any request.
+        let size = u64::try_from(layer.size)
+            .map_err(|_| anyhow!("invalid layer {} size {}", &layer.digest, layer.size))?;
+        let partial_path = PathBuf::from(format!("{}.partial", &store_path));
+        fs::create_dir_all(&self.data_dir)?;
+        let offset = match fs::metadata(&partial_path) {
+            Ok(metadata) if metadata.len() <= size => metadata.len(),
+            Ok(_) => {
+                fs::remove_file(&partial_path)?;
+                0
+            }
+            Err(_) => 0,
+        };
+        let offset = self
+            .copy_cached_blob(&layer.digest, size, offset, &partial_path)
+            .await?;
+
+        // A download waits for a download slot, and the unpacking for an
+        // unpack slot. The blob is streamed to its unpacking when an unpack
+        // slot is free as its download starts. Else the blob is downloaded
+        // into the partial file first, releasing its download slot once
+        // done, and unpacked from there when an unpack slot frees up.
+        let urls = match &layer.urls {
+            Some(urls) if is_foreign_layer(&layer.media_type) => urls.as_slice(),
+            _ => &[],
+        };
+        let mut offset = offset;
+        let mut streamed = None;
+        if offset < size {
+            let download_permit = self
+                .cancellable(self.scheduler.download(self.source.registry()))
+                .await?;
+            match self.scheduler.try_unpack() {
+                Some(unpack_permit) => streamed = Some((download_permit, unpack_permit)),
+                None => {
+                    offset = self
+                        .download_blob(&layer.digest, size, urls, offset, &partial_path)
+                        .await?;
                 }
+            }
+        }
+        let (_download_permit, _unpack_permit) = match streamed {
+            Some((download_permit, unpack_permit)) => (Some(download_permit), unpack_permit),
+            None => (None, self.cancellable(self.scheduler.unpack()).await?),
+        };
+
+        // The blob is written into one end of an in-memory pipe while
+        // a blocking task decrypts, decompresses and unpacks the data
+        // read from the other end.
+        let (writer, reader) = tokio::io::duplex(LAYER_PIPE_SIZE);
+        let source = ProgressReader::new(
+            SyncIoBridge::new(reader),
+            self.progress.clone(),
+            &layer.digest,
+            size,
+            offset,
+        );
+        let unpack_task = {
+            let layer_unpack = LayerUnpack {
+                layer: layer.clone(),
+                decryptor,
+                decrypt_config: layer_decrypt_config,
+                decoder: declared_decoder,
+                detection: self.compression_detection,
+                diff_id: diff_id.to_string(),
+                destination: destination.clone(),
+                partial_path: partial_path.clone(),
+                offset,
+                limits: self.limits.clone(),
+                image_bytes: self.image_bytes.clone(),
+                progress: self.progress.clone(),
+                cancel: self.cancel.clone(),
+            };
+            tokio::task::spawn_blocking(move || layer_unpack.run(source))
+        };
+
+        // The unpacking task is always waited for, even when the pull is
+        // cancelled, so that the partial layer can be removed.
+        let (pull_res, unpack_res) = tokio::join!(
+            self.cancellable(async move {
+                let mut writer = writer;
+                self.pull_blob(&layer.digest, size, urls, offset, &mut writer)
+                    .await
+            }),
+            unpack_task
+        );
+
+        // The partial file is only kept when the download itself failed,
+        // rather than the unpacking which stops reading the download.
+        let resumable = match &pull_res {
+            Err(_) if self.cancel.is_cancelled() => false,
+            Err(e) => !is_broken_pipe(e),
+            Ok(_) => false,
+        };
+
+        let unpack_res =
+            match (pull_res, unpack_res?) {
+                (Ok(_), res) => res,
+                (Err(e), Ok(_)) => Err(e),
+                (Err(pull_err), Err(unpack_err)) => Err(unpack_err
+                    .context(format!("pull layer {} failed: {}", &layer.digest, pull_err))),
+            };
 
-                // convert docker layer media type to oci format
-                if media_type_str == manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE {
-                    media_type_str = manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE;
+        match unpack_res {
+            Ok((uncompressed_digest, decoder)) => {
+                layer_meta.uncompressed_digest = uncompressed_digest;
+                layer_meta.decoder = decoder;
+            }
+            Err(e) => {
+                if destination.exists() {
+                    fs::remove_dir_all(&destination)?;
+                }
+                if !resumable && partial_path.exists() {
+                    fs::remove_file(&partial_path)?;
+                }
+                if self.cancel.is_cancelled() {
+                    return Err(self.cancelled());
                 }
+                return Err(e);
+            }
+        }
 
-                let media_type = MediaType::from(media_type_str);
-                layer_meta.decoder = match media_type {
-                    MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => {
-                        Compression::Uncompressed
-                    }
-                    MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
-                        Compression::Gzip
-                    }
-                    MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
-                        Compression::Zstd
-                    }
-                    _ => return Err(anyhow!("unhandled media type: {}", &layer.media_type)),
+        if partial_path.exists() {
+            fs::remove_file(&partial_path)?;
+        }
+        layer_meta.store_path = destination.display().to_string();
+
+        Ok(layer_meta)
+    }
+
+    // Run `f` until it completes or the pull is cancelled.
+    async fn cancellable<T, F: Future<Output = Result<T>>>(&self, f: F) -> Result<T> {
+        tokio::select! {
+            res = f => res,
+            _ = self.cancel.cancelled() => Err(self.cancelled()),
+        }
+    }
+
+    fn cancelled(&self) -> anyhow::Error {
+        PullError::Cancelled {
+            reference: self.name(),
+        }
+        .into()
+    }
+
+    // Pull the image config blob of `source`, from the blob cache if it
+    // holds it. No more than the declared config size is read.
+    async fn pull_config(
+        &self,
+        source: &dyn ImageSource,
+        config: &OciDescriptor,
+    ) -> Result<Vec<u8>> {
+        let size = u64::try_from(config.size)
+            .map_err(|_| anyhow!("invalid config {} size {}", &config.digest, config.size))?;
+        if size > self.limits.max_config_bytes {
+            return Err(PullError::LimitExceeded {
+                limit: Limit::ConfigBytes,
+                max: self.limits.max_config_bytes,
+            }
+            .into());
+        }
+
+        // A cached config changed since it was opened is pulled from the
+        // source instead.
+        if let Some(cache) = &self.blob_cache {
+            if let Some(file) = cache.open(&config.digest, size).await {
+                let mut data = Vec::new();
+                let out = CountingWriter {
+                    inner: &mut data,
+                    written: 0,
+                    limit: size,
                 };
+                if cache.copy(file, &config.digest, size, 0, out).await.is_ok() {
+                    return Ok(data);
+                }
+            }
+        }
+
+        let mut data = Vec::new();
+        let mut out = CountingWriter {
+            inner: &mut data,
+            written: 0,
+            limit: size,
+        };
+        source
+            .stream_blob(&config.digest, 0, &mut out)
+            .await
+            .map_err(|e| {
+                e.context(format!(
+                    "pull config {} of {} bytes failed",
+                    &config.digest, size
+                ))
+            })?;
+
+        Ok(data)
+    }
+
+    // Pull the blob `digest` of `size` bytes from `offset` into `out`, from
+    // the allowed foreign layer `urls`, or else from the source the image
+    // manifest was pulled from, then the other sources. A transfer failing
+    // part way is resumed from the next location. The blob is verified
+    // against its digest as it is unpacked, whichever location served it.
+    async fn pull_blob(
+        &self,
+        digest: &str,
+        size: u64,
+        urls: &[String],
+        offset: u64,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<()> {
+        if offset >= size {
+            return Ok(());
+        }
+
+        let mut out = CountingWriter {
+            inner: out,
+            written: offset,
+            limit: u64::MAX,
+        };
+        let mut failures = Vec::new();
+        for url in urls.iter().filter(|url| self.foreign_layers.allows(url)) {
+            match self.client.pull_url(url, out.written, &mut out).await {
+                Ok(_) => return Ok(()),
+                Err(e) => failures.push(format!("{:#}", e)),
+            }
+        }
+
+        if let Some(local) = &self.local {
+            let res = local.stream_blob(digest, out.written, &mut out).await;
+            return match res {
+                Err(e) if !failures.is_empty() => Err(e.context(format!(
+                    "foreign layer {} URLs failed: {}",
+                    digest,
+                    failures.join("; ")
+                ))),
+                res => res,
+            };
+        }
 
-                if layer_meta.decoder == Compression::Uncompressed {
-                    layer_meta.uncompressed_digest = layer.digest.clone();
-                    layer_meta.compressed_digest = layer.digest.clone();
-                } else {
-                    layer_meta.compressed_digest = layer.digest.clone();
-                    layer_meta
-                        .decoder
-                        .decompress(plaintext_layer.as_slice(), &mut out)?;
-
-                    if diff_ids[i].starts_with(DIGEST_SHA256) {
-                        layer_meta.uncompressed_digest =
-                            format!("{}:{:x}", DIGEST_SHA256, sha2::Sha256::digest(&out));
-                    } else if diff_ids[i].starts_with(DIGEST_SHA512) {
-                        layer_meta.uncompressed_digest =
-                            format!("{}:{:x}", DIGEST_SHA512, sha2::Sha512::digest(&out));
-                    } else {
-                        return Err(anyhow!("unsupported digest format: {}", diff_ids[i]));
+        // No other source is tried once the unpacking stopped reading.
+        let sources = std::iter::once(&self.source)
+            .chain(self.sources.iter().filter(|source| **source != self.source));
+        let mut last_err = None;
+        for source in sources {
+            let registry = RegistrySource::new(&self.client, source.clone());
+            match registry.stream_blob(digest, out.written, &mut out).await {
+                Ok(_) => return Ok(()),
+                Err(e) if is_broken_pipe(&e) => return Err(e),
+                Err(e) => {
+                    if let Some((name, e)) = last_err.replace((registry.to_string(), e)) {
+                        failures.push(format!("{}: {:#}", name, e));
                     }
                 }
+            }
+        }
 
-                // uncompressed digest should equal to the diff_ids in image_config.
-                if layer_meta.uncompressed_digest != diff_ids[i] {
-                    return Err(anyhow!(
-                        "unequal uncompressed digest {:?} config diff_id {:?}",
-                        layer_meta.uncompressed_digest,
-                        diff_ids[i]
-                    ));
-                }
+        let (_, last_err) = last_err
+            .unwrap_or_else(|| (String::new(), anyhow!("no source to pull blob {}", digest)));
+        if failures.is_empty() {
+            return Err(last_err);
+        }
 
-                let store_path = format!(
-                    "{}/{}",
-                    self.data_dir.display(),
-                    &layer.digest.to_string().replace(':', "_")
-                );
-                let destination = Path::new(&store_path);
+        Err(last_err.context(format!(
+            "pull blob {} failed from all locations, earlier failures: {}",
+            digest,
+            failures.join("; ")
+        )))
+    }
 
-                if let Err(e) = unpack(out, destination) {
-                    fs::remove_dir_all(destination)?;
-                    return Err(e);
-                }
+    // Download the blob `digest` of `size` bytes into the partial file
+    // holding its first `offset` bytes, for it to be unpacked from there.
+    // No more than `size` bytes are written. The partial file is kept when
+    // the download fails, to be resumed, unless the pull is cancelled. It
+    // returns the size of the partial file.
+    async fn download_blob(
+        &self,
+        digest: &str,
+        size: u64,
+        urls: &[String],
+        offset: u64,
+        partial_path: &Path,
+    ) -> Result<u64> {
+        let partial = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(partial_path)
+            .await?;
+        let mut partial = ProgressWriter::new(partial, self.progress.clone(), digest, size, offset);
+        let mut out = CountingWriter {
+            inner: &mut partial,
+            written: offset,
+            limit: size,
+        };
+        let res = self
+            .cancellable(self.pull_blob(digest, size, urls, offset, &mut out))
+            .await;
+        let written = out.written;
+        let res = match res {
+            Ok(_) => partial.flush().await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
 
-                layer_meta.store_path = destination.display().to_string();
+        if res.is_err() && self.cancel.is_cancelled() {
+            fs::remove_file(partial_path)?;
+            return Err(self.cancelled());
+        }
+        res?;
 
-                Ok::<_, anyhow::Error>(layer_meta)
-            }
+        Ok(written)
+    }
+
+    // Copy the blob `digest` of `size` bytes from the blob cache into the
+    // partial file holding its first `offset` bytes, if the cache holds it.
+    // The blob is unpacked from that copy, which the host cannot change
+    // once it is verified. It returns the size of the partial file: `size`
+    // once the cached blob is copied, or else `offset` for the blob to be
+    // pulled from the image source.
+    async fn copy_cached_blob(
+        &self,
+        digest: &str,
+        size: u64,
+        offset: u64,
+        partial_path: &Path,
+    ) -> Result<u64> {
+        let cache = match &self.blob_cache {
+            Some(cache) if offset < size => cache,
+            _ => return Ok(offset),
+        };
+        let file = match cache.open(digest, size).await {
+            Some(file) => file,
+            None => return Ok(offset),
+        };
+
+        let mut partial = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(partial_path)
+            .await?;
+        let res = self
+            .cancellable(cache.copy(file, digest, size, offset, &mut partial))
+            .await;
+        if res.is_ok() {
+            return Ok(size);
+        }
+
+        // The data copied is dropped, and so are the layer pulls of a
+        // cancelled pull.
+        partial.set_len(offset).await?;
+        if self.cancel.is_cancelled() {
+            return Err(self.cancelled());
+        }
+
+        Ok(offset)
+    }
+
+    // The name of the image in messages, local images are named by their
+    // local reference.
+    fn name(&self) -> String {
+        match &self.local {
+            Some(local) => local.to_string(),
+            None => self.reference.whole(),
+        }
+    }
+}
+
+/// LayerUnpack is the blocking part of a layer pull, it decrypts, decompresses
+/// and unpacks the layer blob into `destination`.
+struct LayerUnpack {
+    layer: OciDescriptor,
+    decryptor: Decryptor,
+    decrypt_config: Option<String>,
+
+    // The compression declared by the layer media type, if known.
+    decoder: Option<Compression>,
+    detection: CompressionDetection,
+
+    diff_id: String,
+    destination: PathBuf,
+
+    // The file holding the data of an interrupted download of the blob,
+    // and the size of that data.
+    partial_path: PathBuf,
+    offset: u64,
+
+    limits: LimitsConfig,
+    image_bytes: Arc<AtomicU64>,
+
+    progress: ProgressReporter,
+    cancel: CancellationToken,
+}
+
+impl LayerUnpack {
+    /// run unpacks the layer blob read from `input`. The blob is verified
+    /// against the digest and size of its descriptor, and the uncompressed
+    /// digest is computed on the fly and verified against `diff_id`.
+    ///
+    /// The blob data is appended to the partial file as it is read. The
+    /// first `offset` bytes of the blob are read from that file, they are
+    /// the data of an interrupted download that `input` resumes.
+    /// All reads fail once the pull is cancelled.
+    /// It returns the uncompressed digest of the layer, with the compression
+    /// the layer is decompressed with.
+    fn run<R: Read>(&self, input: R) -> Result<(String, Compression)> {
+        let layer = &self.layer;
+        let size = u64::try_from(layer.size)
+            .map_err(|_| anyhow!("invalid layer {} size {}", &layer.digest, layer.size))?;
+
+        let partial = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.partial_path)?;
+        let replay = File::open(&self.partial_path)?.take(self.offset);
+        let input = replay.chain(TeeReader {
+            inner: input,
+            file: partial,
         });
+        let mut blob = VerifyReader::new(self.cancellable(input), &layer.digest, size)?;
+
+        let dc = match &self.decrypt_config {
+            Some(dc) => dc,
+            None => {
+                self.report_unpacking();
+                let res = self.unpack_plaintext_layer(&mut blob);
+                blob.check_size()?;
+                let unpacked = res?;
+
+                // Drain whatever is left, so the blob download can run to completion.
+                io::copy(&mut blob, &mut io::sink())?;
+                blob.verify()?;
+                return Ok(unpacked);
+            }
+        };
 
-        let layer_metas = future::try_join_all(layer_metas).await?;
+        // Encrypted layers are downloaded into the partial file and verified
+        // before decryption, so that no unverified ciphertext is ever fed to
+        // the decryptor.
+        let copied = io::copy(&mut blob, &mut io::sink());
+        blob.check_size()?;
+        copied?;
+        blob.verify()?;
 
-        Ok(layer_metas)
+        self.progress.report(&layer.digest, PullStage::Decrypting);
+        let file = self.cancellable(File::open(&self.partial_path)?);
+        let plaintext_layer = self.decryptor.get_plaintext_layer(layer, file, dc)?;
+        self.report_unpacking();
+        self.unpack_plaintext_layer(plaintext_layer)
+    }
+
+    // Decompress and unpack a plaintext layer into the destination, within
+    // the limits, and verify the uncompressed digest against the diff_id.
+    fn unpack_plaintext_layer<R: Read>(&self, plaintext_layer: R) -> Result<(String, Compression)> {
+        let (detected, plaintext_layer) = sniff_format(plaintext_layer)?;
+        let decoder = self.select_decoder(detected)?;
+
+        let mut layer_reader = LimitReader {
+            inner: decoder.decoder(plaintext_layer)?,
+            read: 0,
+            limits: &self.limits,
+            image_bytes: &self.image_bytes,
+            exceeded: None,
+        };
+        let res = unpack_layer(
+            &mut layer_reader,
+            &self.diff_id,
+            &self.destination,
+            &self.limits,
+        );
+        layer_reader.check()?;
+
+        Ok((res?, decoder))
+    }
+
+    // Select the decoder of the layer from the compression declared by its
+    // media type and the format detected from its magic bytes.
+    fn select_decoder(&self, detected: Option<Compression>) -> Result<Compression> {
+        let mismatch = |detected: &dyn std::fmt::Display| PullError::CompressionMismatch {
+            layer: self.layer.digest.clone(),
+            media_type: self.layer.media_type.clone(),
+            detected: detected.to_string(),
+        };
+
+        match (self.decoder, detected) {
+            (Some(declared), Some(detected)) if declared == detected => Ok(declared),
+            (_, Some(detected)) if self.detection == CompressionDetection::Lenient => Ok(detected),
+            (_, Some(detected)) => Err(mismatch(&detected).into()),
+            // Old tar formats have no magic bytes, the media type is trusted.
+            (Some(declared), None) => Ok(declared),
+            (None, None) => Err(mismatch(&"unknown format").into()),
+        }
+    }
+
+    fn report_unpacking(&self) {
+        if self.decoder != Some(Compression::Uncompressed) {
+            self.progress
+                .report(&self.layer.digest, PullStage::Decompressing);
+        }
+        self.progress
+            .report(&self.layer.digest, PullStage::Unpacking);
+    }
+
+    fn cancellable<R: Read>(&self, inner: R) -> CancellableReader<R> {
+        CancellableReader {
+            inner,
+            cancel: self.cancel.clone(),
+        }
+    }
+}
+
+// CancellableReader fails all reads once `cancel` is cancelled.
+struct CancellableReader<R> {
+    inner: R,
+    cancel: CancellationToken,
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.is_cancelled() {
+            return Err(io::Error::other("pull cancelled"));
+        }
+
+        self.inner.read(buf)
     }
 }
 
+// LimitReader fails the reads past the layer bytes limit, or past the image
+// bytes limit, counted across the layers of the pull in `image_bytes`.
+struct LimitReader<'a, R> {
+    inner: R,
+    read: u64,
+    limits: &'a LimitsConfig,
+    image_bytes: &'a AtomicU64,
+    exceeded: Option<PullError>,
+}
+
+impl<R> LimitReader<'_, R> {
+    // Return the error of the limit exceeded, if any.
+    fn check(&mut self) -> std::result::Result<(), PullError> {
+        match self.exceeded.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for LimitReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        let image_bytes = self.image_bytes.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+
+        let (limit, max) = if self.read > self.limits.max_layer_bytes {
+            (Limit::LayerBytes, self.limits.max_layer_bytes)
+        } else if image_bytes > self.limits.max_image_bytes {
+            (Limit::ImageBytes, self.limits.max_image_bytes)
+        } else {
+            return Ok(n);
+        };
+
+        let err = PullError::LimitExceeded { limit, max };
+        let msg = err.to_string();
+        self.exceeded = Some(err);
+        Err(io::Error::other(msg))
+    }
+}
+
+// CountingWriter counts the bytes written through it, in addition to the
+// `written` bytes it starts from, and fails the writes past `limit` bytes.
+struct CountingWriter<'a> {
+    inner: &'a mut (dyn AsyncWrite + Send + Unpin),
+    written: u64,
+    limit: u64,
+}
+
+impl AsyncWrite for CountingWriter<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.written.saturating_add(buf.len() as u64) > self.limit {
+            return Poll::Ready(Err(io::Error::other(format!(
+                "data exceeds {} bytes",
+                self.limit
+            ))));
+        }
+
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            self.written += *n as u64;
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+// Whether `media_type` is the media type of a foreign layer, a layer which
+// may be fetched from the URLs of its descriptor.
+fn is_foreign_layer(media_type: &str) -> bool {
+    media_type.starts_with(OCI_NON_DISTRIBUTABLE_LAYER_PREFIX)
+        || media_type == IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE
+}
+
+// Whether `err` comes from writing into a pipe whose reader is gone, like
+// the layer pipe once the unpacking stopped reading.
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        matches!(e.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+// TeeReader appends all the data read through it to `file`.
+struct TeeReader<R> {
+    inner: R,
+    file: File,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.file.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+// Unpack an uncompressed layer into `destination`, and verify the
+// uncompressed digest against `diff_id`.
+fn unpack_layer<R: Read>(
+    layer: R,
+    diff_id: &str,
+    destination: &Path,
+    limits: &LimitsConfig,
+) -> Result<String> {
+    let mut layer_reader = HashReader::new(layer, diff_id)?;
+    unpack(&mut layer_reader, destination, limits)?;
+
+    // Tar archives may carry padding after the end-of-archive blocks, it
+    // must be consumed as well to compute the complete uncompressed digest.
+    io::copy(&mut layer_reader, &mut io::sink())?;
+    let uncompressed_digest = layer_reader.digest_str();
+
+    // uncompressed digest should equal to the diff_ids in image_config.
+    if uncompressed_digest != diff_id {
+        return Err(anyhow!(
+            "unequal uncompressed digest {:?} config diff_id {:?}",
+            uncompressed_digest,
+            diff_id
+        ));
+    }
+
+    Ok(uncompressed_digest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{RetryConfig, SchedulerConfig};
+    use crate::decoder::Compression;
+    use crate::source::open_local;
+    use crate::test_registry::{Fault, TestImage, TestRegistry};
     use oci_spec::image::ImageConfiguration;
     use tempfile;
 
     #[tokio::test]
     async fn test_pull_client() {
-        let oci_images = vec![
-            "docker.io/arronwang/busybox_gzip",
-            "docker.io/arronwang/busybox_zstd",
-            "docker.io/arronwang/busybox_encrypted",
+        let registry = TestRegistry::start().await.unwrap();
+        let compressions = [
+            ("busybox_gzip", Compression::Gzip),
+            ("busybox_zstd", Compression::Zstd),
+            ("busybox_uncompressed", Compression::Uncompressed),
+            ("busybox_bzip2", Compression::Bzip2),
+            ("busybox_xz", Compression::Xz),
         ];
 
-        for image in oci_images.iter() {
+        for (repository, compression) in compressions.iter() {
+            let image = TestImage::new("linux/amd64")
+                .unwrap()
+                .layer(&[("bin/busybox", b"busybox")], *compression)
+                .unwrap()
+                .layer(&[("etc/hostname", b"test")], *compression)
+                .unwrap();
+            registry.push_image(repository, "latest", &image);
+
             let tempdir = tempfile::tempdir().unwrap();
-            let mut client = PullClient::new(image, tempdir.path(), &None).unwrap();
-            let (image_manifest, _image_digest, image_config) =
-                client.pull_manifest().await.unwrap();
-
-            let image_config = ImageConfiguration::from_reader(image_config.as_bytes()).unwrap();
-            let diff_ids = image_config.rootfs().diff_ids();
-
-            let config_dir = std::env!("CARGO_MANIFEST_DIR");
-            let keyprovider_config =
-                format!("{}/{}", config_dir, "test_data/ocicrypt_keyprovider.conf");
-            let decrypt_config = Path::new(config_dir)
-                .join("test_data")
-                .join("private_key_for_tests.pem:test");
-
-            std::env::set_var("OCICRYPT_KEYPROVIDER_CONFIG", keyprovider_config);
-
-            assert!(client
-                .pull_layers(
-                    image_manifest.layers.clone(),
-                    diff_ids,
-                    &Some(decrypt_config.to_str().unwrap()),
-                    Arc::new(Mutex::new(MetaStore::default()))
-                )
-                .await
-                .is_ok());
+            let mut client = test_registry_client(&registry, repository, tempdir.path());
+            let layer_metas = pull_test_image(&mut client).await.unwrap();
+            assert_eq!(layer_metas.len(), 2, "{}", repository);
+            assert_eq!(
+                fs::read(Path::new(&layer_metas[0].store_path).join("bin/busybox")).unwrap(),
+                b"busybox"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_encrypted_layer() {
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .encrypted_layer()
+            .unwrap();
+        registry.push_image("busybox_encrypted", "latest", &image);
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut client = test_registry_client(&registry, "busybox_encrypted", tempdir.path());
+
+        // The layer is not pulled without a decrypt config.
+        assert!(pull_test_image(&mut client).await.is_err());
+
+        let decrypt_config = Path::new(std::env!("CARGO_MANIFEST_DIR"))
+            .join("test_data")
+            .join("private_key_for_tests.pem:test");
+        let decrypt_config = Some(decrypt_config.to_str().unwrap());
+        let meta_store = Arc::new(Mutex::new(MetaStore::default()));
+        let layer_metas = pull_test_image_with(&mut client, &decrypt_config, meta_store.clone())
+            .await
+            .unwrap();
+        assert!(layer_metas[0].encrypted);
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[0].store_path).join("bin/busybox")).unwrap(),
+            b"busybox"
+        );
+
+        // The stored layer is not reused with another key.
+        let wrong_key = Path::new(std::env!("CARGO_MANIFEST_DIR"))
+            .join("test_data")
+            .join("public_key_for_tests.pem");
+        let err = pull_test_image_with(
+            &mut client,
+            &Some(wrong_key.to_str().unwrap()),
+            meta_store.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("another decrypt_config"),
+            "{}",
+            err
+        );
+        let cached = pull_test_image_with(&mut client, &decrypt_config, meta_store)
+            .await
+            .unwrap();
+        assert_eq!(cached[0].store_path, layer_metas[0].store_path);
+    }
+
+    #[tokio::test]
+    async fn test_pull_faults() {
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("file.txt", &[7u8; 64 * 1024][..])], Compression::Gzip)
+            .unwrap();
+        registry.push_image("faults", "latest", &image);
+        let layer_digest = &image.layer_digests()[0];
+
+        // Rate limited requests are retried, and a blob transfer cut short
+        // is resumed from where it stopped.
+        registry.inject(Fault::TooManyRequests);
+        registry.inject(Fault::TruncatedBlob(layer_digest.clone()));
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut client = test_registry_client(&registry, "faults", tempdir.path());
+        pull_test_image(&mut client).await.unwrap();
+        let blob_requests: Vec<String> = registry
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains(layer_digest.as_str()))
+            .collect();
+        assert_eq!(blob_requests.len(), 2);
+        assert!(blob_requests[1].contains("bytes="));
+
+        // A blob fully downloaded before is only replayed from its partial
+        // file, without requesting the blob again.
+        let tempdir = tempfile::tempdir().unwrap();
+        let partial_path = tempdir
+            .path()
+            .join(format!("{}.partial", layer_digest.replace(':', "_")));
+        fs::write(&partial_path, image.layer_blobs()[0]).unwrap();
+        let mut client = test_registry_client(&registry, "faults", tempdir.path());
+        let requests = registry.requests().len();
+        pull_test_image(&mut client).await.unwrap();
+        assert!(!registry.requests()[requests..]
+            .iter()
+            .any(|r| r.contains(layer_digest.as_str())));
+        assert!(!partial_path.exists());
+
+        // A blob not matching its digest fails the pull.
+        registry.inject(Fault::WrongDigest(layer_digest.clone()));
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut client = test_registry_client(&registry, "faults", tempdir.path());
+        assert!(pull_test_image(&mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pull_layer_digest_traversal() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let data_dir = tempdir.path().join("layers");
+        fs::create_dir_all(data_dir.join("sha256_")).unwrap();
+        let outside = tempdir.path().join("outside.partial");
+        fs::write(&outside, b"outside").unwrap();
+
+        // The layer digest is checked before it names any path, the file
+        // it points to outside the data store is left alone.
+        let client = PullClient::new("example.com/traversal", &data_dir, &None).unwrap();
+        let layer = OciDescriptor {
+            media_type: manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE.to_string(),
+            digest: "sha256:/../../outside".to_string(),
+            size: 1,
+            ..Default::default()
+        };
+        let err = client
+            .pull_layer(&layer, &layer.digest, &None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid digest format"));
+        assert_eq!(fs::read(&outside).unwrap(), b"outside");
+    }
+
+    #[tokio::test]
+    async fn test_pull_mirror_fallback() {
+        let mirror = TestRegistry::start().await.unwrap();
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("file.txt", &[7u8; 64 * 1024][..])], Compression::Gzip)
+            .unwrap();
+        mirror.push_image("mirrored", "latest", &image);
+        registry.push_image("mirrored", "latest", &image);
+        let layer_digest = &image.layer_digests()[0];
+
+        // The layer failing part way on the mirror the manifest was pulled
+        // from is resumed from the next source.
+        mirror.inject(Fault::TruncatedBlob(layer_digest.clone()));
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut client = test_registry_client(&registry, "mirrored", tempdir.path());
+        client
+            .client
+            .set_tls(&mirror.host(), &mirror.tls_config())
+            .unwrap();
+        client.client.set_retry(RetryConfig {
+            max_attempts: 1,
+            initial_backoff_ms: 0,
+            max_backoff_ms: 0,
+        });
+        client.sources = vec![
+            Reference::try_from(mirror.reference("mirrored", "latest")).unwrap(),
+            client.reference.clone(),
+        ];
+        let layer_metas = pull_test_image(&mut client).await.unwrap();
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[0].store_path).join("file.txt")).unwrap(),
+            [7u8; 64 * 1024]
+        );
+        assert!(!registry
+            .requests()
+            .iter()
+            .any(|r| r.contains("/manifests/")));
+        let blob_requests: Vec<String> = registry
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains(layer_digest.as_str()))
+            .collect();
+        assert_eq!(blob_requests.len(), 1);
+        assert!(blob_requests[0].contains("bytes="));
+    }
+
+    #[tokio::test]
+    async fn test_pull_spooled_layers() {
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("bin/busybox", b"busybox")], Compression::Gzip)
+            .unwrap()
+            .layer(&[("etc/hostname", b"test")], Compression::Gzip)
+            .unwrap()
+            .layer(&[("etc/motd", b"hello")], Compression::Gzip)
+            .unwrap();
+        registry.push_image("busybox", "latest", &image);
+
+        // With a single unpack slot, the layers waiting for it are
+        // downloaded into their partial files, then unpacked from there.
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut client = test_registry_client(&registry, "busybox", tempdir.path());
+        client.scheduler = Arc::new(PullScheduler::new(&SchedulerConfig {
+            max_downloads: 3,
+            max_downloads_per_registry: 3,
+            max_unpacks: 1,
+        }));
+        let layer_metas = pull_test_image(&mut client).await.unwrap();
+        assert_eq!(layer_metas.len(), 3);
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[2].store_path).join("etc/motd")).unwrap(),
+            b"hello"
+        );
+        for layer_meta in layer_metas.iter() {
+            assert!(!Path::new(&format!("{}.partial", layer_meta.store_path)).exists());
         }
     }
+
+    #[tokio::test]
+    async fn test_pull_foreign_layer() {
+        let registry = TestRegistry::start().await.unwrap();
+        let foreign = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .foreign_layer(
+                &[("base.txt", b"base")],
+                Compression::Gzip,
+                &[
+                    "https://untrusted.example.com/blobs/".to_string(),
+                    foreign.blob_url_base("base"),
+                ],
+            )
+            .unwrap()
+            .layer(&[("file.txt", b"data")], Compression::Gzip)
+            .unwrap();
+        registry.push_image("foreign", "latest", &image);
+        foreign.push_foreign_blobs(&image);
+        let foreign_digest = &image.layer_digests()[0];
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let pull = |foreign_layers: ForeignLayerConfig, name: &'static str| {
+            let registry = &registry;
+            let data_dir = data_dir.path().join(name);
+            async move {
+                let mut client = test_registry_client(registry, "foreign", &data_dir);
+                client.foreign_layers = foreign_layers;
+                pull_test_image(&mut client).await
+            }
+        };
+        let allowed = ForeignLayerConfig {
+            refuse: false,
+            allowed_hosts: vec![foreign.host()],
+        };
+
+        // The URLs of hosts not allowed are not fetched, and the registry
+        // does not hold the foreign layer.
+        assert!(pull(ForeignLayerConfig::default(), "default")
+            .await
+            .is_err());
+        assert!(foreign.requests().is_empty());
+
+        let layer_metas = pull(allowed.clone(), "allowed").await.unwrap();
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[0].store_path).join("base.txt")).unwrap(),
+            b"base"
+        );
+        assert_eq!(
+            foreign.requests(),
+            vec![format!("GET /v2/base/blobs/{}", foreign_digest)]
+        );
+
+        // The foreign layer data is verified like any layer blob.
+        foreign.inject(Fault::WrongDigest(foreign_digest.clone()));
+        assert!(pull(allowed.clone(), "wrong_digest").await.is_err());
+
+        let refuse = ForeignLayerConfig {
+            refuse: true,
+            ..allowed.clone()
+        };
+        let err = pull(refuse, "refuse").await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PullError>(),
+            Some(&PullError::ForeignLayerRefused {
+                layer: foreign_digest.clone()
+            })
+        );
+        assert_eq!(foreign.requests().len(), 2);
+
+        // The foreign layer URLs are not followed when they redirect to
+        // hosts not allowed.
+        let redirected = TestRegistry::start().await.unwrap();
+        redirected.push_foreign_blobs(&image);
+        foreign.inject(Fault::RedirectedBlob(
+            foreign_digest.clone(),
+            format!("{}{}", redirected.blob_url_base("base"), foreign_digest),
+        ));
+        assert!(pull(allowed, "redirected").await.is_err());
+        assert_eq!(foreign.requests().len(), 3);
+        assert!(redirected.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pull_limits() {
+        let registry = TestRegistry::start().await.unwrap();
+        let zeros = vec![0u8; 256 * 1024];
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("zeros1", &zeros)], Compression::Gzip)
+            .unwrap()
+            .layer(&[("zeros2", &zeros)], Compression::Zstd)
+            .unwrap();
+        registry.push_image("limits", "latest", &image);
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let pull = |limits: LimitsConfig, name: &'static str| {
+            let registry = &registry;
+            let data_dir = data_dir.path().join(name);
+            async move {
+                let mut client = test_registry_client(registry, "limits", &data_dir);
+                client.limits = limits;
+                pull_test_image(&mut client).await?;
+                Ok::<_, anyhow::Error>(data_dir)
+            }
+        };
+
+        let tests = [
+            (
+                LimitsConfig {
+                    max_manifest_bytes: 100,
+                    ..Default::default()
+                },
+                Limit::ManifestBytes,
+            ),
+            (
+                LimitsConfig {
+                    max_config_bytes: 10,
+                    ..Default::default()
+                },
+                Limit::ConfigBytes,
+            ),
+            (
+                LimitsConfig {
+                    max_layer_bytes: 64 * 1024,
+                    ..Default::default()
+                },
+                Limit::LayerBytes,
+            ),
+            (
+                LimitsConfig {
+                    max_image_bytes: 300 * 1024,
+                    ..Default::default()
+                },
+                Limit::ImageBytes,
+            ),
+        ];
+
+        for (i, (limits, limit)) in tests.iter().enumerate() {
+            let name = ["manifest", "config", "layer", "image"][i];
+            let err = pull(limits.clone(), name).await.unwrap_err();
+            assert!(
+                err.chain().any(|e| matches!(
+                    e.downcast_ref::<PullError>(),
+                    Some(PullError::LimitExceeded { limit: l, .. }) if l == limit
+                )),
+                "{}: {:#}",
+                name,
+                err
+            );
+
+            // No partial layer is left behind.
+            let layers_dir = data_dir.path().join(name);
+            if layers_dir.exists() {
+                for entry in fs::read_dir(&layers_dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    let metadata = fs::metadata(&path).unwrap();
+                    assert!(metadata.is_dir(), "{}: {}", name, path.display());
+                }
+            }
+        }
+
+        pull(
+            LimitsConfig {
+                max_layer_bytes: 512 * 1024,
+                max_image_bytes: 1024 * 1024,
+                ..Default::default()
+            },
+            "within_limits",
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_compression_detection() {
+        let registry = TestRegistry::start().await.unwrap();
+        let mislabeled = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("gzip.txt", b"gzip")], Compression::Gzip)
+            .unwrap()
+            .media_type(manifest::IMAGE_LAYER_MEDIA_TYPE)
+            .layer(&[("tar.txt", b"tar")], Compression::Uncompressed)
+            .unwrap()
+            .media_type(manifest::IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE);
+        registry.push_image("mislabeled", "latest", &mislabeled);
+        let octet_stream = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("zstd.txt", b"zstd")], Compression::Zstd)
+            .unwrap()
+            .media_type("application/octet-stream");
+        registry.push_image("octet-stream", "latest", &octet_stream);
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let pull = |repository: &'static str, detection: CompressionDetection| {
+            let registry = &registry;
+            let data_dir = data_dir
+                .path()
+                .join(format!("{}-{:?}", repository, detection));
+            async move {
+                let mut client = test_registry_client(registry, repository, &data_dir);
+                client.compression_detection = detection;
+                pull_test_image(&mut client).await
+            }
+        };
+
+        let layer_metas = pull("mislabeled", CompressionDetection::Lenient)
+            .await
+            .unwrap();
+        assert_eq!(layer_metas[0].decoder, Compression::Gzip);
+        assert_eq!(layer_metas[1].decoder, Compression::Uncompressed);
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[0].store_path).join("gzip.txt")).unwrap(),
+            b"gzip"
+        );
+
+        let err = pull("mislabeled", CompressionDetection::Strict)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PullError>(),
+            Some(&PullError::CompressionMismatch {
+                layer: mislabeled.layer_digests()[0].clone(),
+                media_type: manifest::IMAGE_LAYER_MEDIA_TYPE.to_string(),
+                detected: "gzip".to_string(),
+            })
+        );
+
+        let layer_metas = pull("octet-stream", CompressionDetection::Lenient)
+            .await
+            .unwrap();
+        assert_eq!(layer_metas[0].decoder, Compression::Zstd);
+        assert!(pull("octet-stream", CompressionDetection::Strict)
+            .await
+            .is_err());
+    }
+
+    // A pull client of the image `repository:latest` of the test `registry`,
+    // retrying without delay.
+    fn test_registry_client(
+        registry: &TestRegistry,
+        repository: &str,
+        data_dir: &Path,
+    ) -> PullClient {
+        let image = registry.reference(repository, "latest");
+        let mut client = PullClient::new(&image, data_dir, &None).unwrap();
+        client
+            .client
+            .set_tls(&registry.host(), &registry.tls_config())
+            .unwrap();
+        client.client.set_retry(RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 0,
+            max_backoff_ms: 0,
+        });
+        client
+    }
+
+    // Pull the manifest, then the layers of the image of `client`, without
+    // a decrypt config and into a meta store of their own.
+    async fn pull_test_image(client: &mut PullClient) -> Result<Vec<LayerMeta>> {
+        pull_test_image_with(client, &None, Arc::default()).await
+    }
+
+    // Pull the manifest, then the layers of the image of `client` with
+    // `decrypt_config`, into `meta_store`.
+    async fn pull_test_image_with(
+        client: &mut PullClient,
+        decrypt_config: &Option<&str>,
+        meta_store: Arc<Mutex<MetaStore>>,
+    ) -> Result<Vec<LayerMeta>> {
+        let (image_manifest, _image_digest, image_config) = client.pull_manifest().await?;
+        let image_config = ImageConfiguration::from_reader(image_config.as_bytes())?;
+        client
+            .pull_layers(
+                image_manifest.layers,
+                image_config.rootfs().diff_ids(),
+                decrypt_config,
+                meta_store,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_pull_layout() {
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("file.txt", b"data")], Compression::Gzip)
+            .unwrap();
+        let manifest_digest = digest_bytes(&image.manifest(), None).unwrap();
+        let config = String::from_utf8(image.config()).unwrap();
+        let config_digest = digest_bytes(config.as_bytes(), None).unwrap();
+        let layer_digest = image.layer_digests()[0].clone();
+        let diff_id = image.diff_ids()[0].clone();
+        let layout_dir = tempfile::tempdir().unwrap();
+        image.write_layout(layout_dir.path(), "v1").unwrap();
+
+        let reference = format!("oci:{}:v1", layout_dir.path().display());
+        let local = open_local(&reference, &LimitsConfig::default()).unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut client = PullClient::from_local(local, data_dir.path()).unwrap();
+
+        let (_, image_digest, image_config) = client.pull_manifest().await.unwrap();
+        assert_eq!(image_digest, manifest_digest);
+        assert_eq!(image_config, config);
+        let layer_metas = pull_test_image(&mut client).await.unwrap();
+        assert_eq!(layer_metas[0].uncompressed_digest, diff_id);
+        assert_eq!(
+            fs::read(Path::new(&layer_metas[0].store_path).join("file.txt")).unwrap(),
+            b"data"
+        );
+
+        // The same image, in an image directory.
+        let image_dir = tempfile::tempdir().unwrap();
+        image.write_dir(image_dir.path()).unwrap();
+        let local = open_local(
+            &format!("dir:{}", image_dir.path().display()),
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+        let mut dir_client = PullClient::from_local(local, data_dir.path()).unwrap();
+        let (_, image_digest, image_config) = dir_client.pull_manifest().await.unwrap();
+        assert_eq!(image_digest, manifest_digest);
+        assert_eq!(image_config, config);
+
+        // The cached blobs are used once verified, the others are pulled
+        // from the image source.
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cached_layer = cache_dir.path().join(layer_digest.replace(':', "_"));
+        fs::write(
+            cache_dir.path().join(config_digest.replace(':', "_")),
+            &config,
+        )
+        .unwrap();
+        fs::write(&cached_layer, b"corrupted").unwrap();
+        let cache = Arc::new(BlobCache::new(cache_dir.path()));
+        dir_client.blob_cache = Some(cache.clone());
+        let data_dir = tempfile::tempdir().unwrap();
+        dir_client.data_dir = data_dir.path().to_path_buf();
+        pull_test_image(&mut dir_client).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.rejected, stats.cache_bytes),
+            (1, 1, config.len() as u64)
+        );
+
+        // A layer blob not matching its digest is refused.
+        let (_, encoded) = split_digest(&layer_digest).unwrap();
+        fs::write(
+            layout_dir.path().join("blobs/sha256").join(encoded),
+            b"corrupted",
+        )
+        .unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        client.data_dir = data_dir.path().to_path_buf();
+        assert!(pull_test_image(&mut client).await.is_err());
+
+        // Unless the blob cache holds the right one, the config is read
+        // from the cache too.
+        fs::write(&cached_layer, image.layer_blobs()[0]).unwrap();
+        client.blob_cache = Some(cache.clone());
+        let stats = cache.stats();
+        assert!(pull_test_image(&mut client).await.is_ok());
+        assert_eq!(
+            (cache.stats().hits, cache.stats().cache_bytes),
+            (
+                stats.hits + 2,
+                stats.cache_bytes + (config.len() + image.layer_blobs()[0].len()) as u64
+            )
+        );
+    }
+
+    #[test]
+    fn test_unpack_layer_resume() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let data = b"This is some text!".repeat(1024);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "file.txt", data.as_slice())
+            .unwrap();
+        let layer_tar = builder.into_inner().unwrap();
+        let diff_id = digest_bytes(&layer_tar, None).unwrap();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&layer_tar).unwrap();
+        let blob = encoder.finish().unwrap();
+
+        let layer = OciDescriptor {
+            media_type: manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE.to_string(),
+            digest: digest_bytes(&blob, None).unwrap(),
+            size: blob.len() as i64,
+            urls: None,
+            annotations: None,
+        };
+
+        // The first half of the blob was downloaded by an interrupted pull.
+        let tempdir = tempfile::tempdir().unwrap();
+        let partial_path = tempdir.path().join("layer.partial");
+        let destination = tempdir.path().join("layer");
+        let offset = blob.len() / 2;
+        fs::write(&partial_path, &blob[..offset]).unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let layer_unpack = LayerUnpack {
+            layer: layer.clone(),
+            decryptor: Decryptor::from_media_type(&layer.media_type),
+            decrypt_config: None,
+            decoder: Some(Compression::Gzip),
+            detection: CompressionDetection::Strict,
+            diff_id: diff_id.clone(),
+            destination: destination.clone(),
+            partial_path: partial_path.clone(),
+            offset: offset as u64,
+            limits: LimitsConfig::default(),
+            image_bytes: Arc::new(AtomicU64::new(0)),
+            progress: ProgressReporter::new(Some(sender)),
+            cancel: CancellationToken::new(),
+        };
+
+        let (uncompressed_digest, _) = layer_unpack.run(&blob[offset..]).unwrap();
+        assert_eq!(uncompressed_digest, diff_id);
+        assert_eq!(fs::read(destination.join("file.txt")).unwrap(), data);
+        assert_eq!(fs::read(&partial_path).unwrap(), blob);
+
+        let mut stages = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            assert_eq!(event.digest, layer.digest);
+            stages.push(event.stage);
+        }
+        assert_eq!(stages, vec![PullStage::Decompressing, PullStage::Unpacking]);
+
+        // A corrupted partial download fails the blob verification.
+        let mut corrupted = blob[..offset].to_vec();
+        corrupted[offset - 1] ^= 0xff;
+        fs::write(&partial_path, &corrupted).unwrap();
+        fs::remove_dir_all(&destination).unwrap();
+        assert!(layer_unpack.run(&blob[offset..]).is_err());
+
+        // A cancelled unpacking fails without reading anything.
+        fs::write(&partial_path, &blob[..offset]).unwrap();
+        fs::remove_dir_all(&destination).unwrap();
+        layer_unpack.cancel.cancel();
+        let err = layer_unpack.run(&blob[offset..]).unwrap_err();
+        assert!(format!("{:?}", err).contains("pull cancelled"));
+        assert!(!destination.join("file.txt").exists());
+    }
 }
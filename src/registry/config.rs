@@ -0,0 +1,504 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Which images a mirror is used for.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullFromMirror {
+    /// Images pulled by digest or by tag.
+    #[default]
+    All,
+
+    /// Images pulled by digest only.
+    DigestOnly,
+
+    /// Images pulled by tag only.
+    TagOnly,
+}
+
+/// How short image names, without a registry, are resolved.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShortNameMode {
+    /// Short names must resolve to a single image: through an alias or
+    /// a single unqualified search registry. Ambiguous names are rejected.
+    #[default]
+    Enforcing,
+
+    /// Short names resolve through an alias, or else all the unqualified
+    /// search registries are tried in order.
+    Permissive,
+
+    /// Aliases are ignored, and all the unqualified search registries are
+    /// tried in order.
+    Disabled,
+}
+
+/// A mirror of a registry, as in a `[[registry.mirror]]` table.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Mirror {
+    /// The location of the mirror, it replaces the registry prefix
+    /// of the images pulled from the mirror.
+    pub location: String,
+
+    /// Which images the mirror is used for.
+    #[serde(default)]
+    pub pull_from_mirror: PullFromMirror,
+}
+
+/// A registry entry, as in a `[[registry]]` table.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Registry {
+    /// The images the entry applies to, in "host[:port][/namespace...]"
+    /// or "*.domain" format. Defaults to the location.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// The location to pull the matching images from, it replaces the
+    /// prefix of the image references. Defaults to the prefix.
+    #[serde(default)]
+    pub location: String,
+
+    /// Whether pulling the matching images is forbidden.
+    #[serde(default)]
+    pub blocked: bool,
+
+    /// Only use the mirrors for images pulled by digest.
+    #[serde(default)]
+    pub mirror_by_digest_only: bool,
+
+    /// The mirrors to try, in order, before the location.
+    #[serde(default)]
+    pub mirror: Vec<Mirror>,
+}
+
+/// The registries configuration, modelled on containers `registries.conf`
+/// version 2, like:
+///    unqualified-search-registries = ["quay.io", "docker.io"]
+///    short-name-mode = "enforcing"
+///
+///    [aliases]
+///    "busybox" = "docker.io/library/busybox"
+///
+///    [[registry]]
+///    prefix = "example.com/foo"
+///    location = "internal.example.com/bar"
+///
+///    [[registry.mirror]]
+///    location = "mirror.example.com/bar"
+///    pull-from-mirror = "digest-only"
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistriesConfig {
+    /// The registries to search short image names in, in order.
+    #[serde(default)]
+    pub unqualified_search_registries: Vec<String>,
+
+    /// How short image names are resolved.
+    #[serde(default)]
+    pub short_name_mode: ShortNameMode,
+
+    /// Short image names with the fully qualified images they stand for.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+
+    /// The registry entries.
+    #[serde(default)]
+    pub registry: Vec<Registry>,
+}
+
+impl FromStr for RegistriesConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut config: RegistriesConfig =
+            toml::from_str(s).map_err(|e| anyhow!("failed to parse registries config: {}", e))?;
+
+        for registry in config.registry.iter_mut() {
+            if registry.prefix.is_empty() {
+                registry.prefix = registry.location.clone();
+            }
+
+            if registry.prefix.is_empty() {
+                return Err(anyhow!("registry entry without prefix or location"));
+            }
+
+            if registry.prefix.starts_with("*.") && !registry.location.is_empty() {
+                return Err(anyhow!(
+                    "wildcard prefix {} can not have a location",
+                    registry.prefix
+                ));
+            }
+
+            if registry.mirror_by_digest_only
+                && registry
+                    .mirror
+                    .iter()
+                    .any(|m| m.pull_from_mirror != PullFromMirror::All)
+            {
+                return Err(anyhow!(
+                    "registry {} sets both mirror-by-digest-only and pull-from-mirror",
+                    registry.prefix
+                ));
+            }
+        }
+
+        for (alias, image) in config.aliases.iter() {
+            if !is_short_name(alias) || split_name(alias).0 != alias {
+                return Err(anyhow!("alias {} is not a short name", alias));
+            }
+
+            if is_short_name(image) {
+                return Err(anyhow!(
+                    "alias {} is not a fully qualified image: {}",
+                    alias,
+                    image
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl RegistriesConfig {
+    /// Load the registries configuration from a TOML file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read registries config {:?}: {}", path, e))?;
+        Self::from_str(&data)
+    }
+
+    /// resolve returns the fully qualified references an image name may
+    /// stand for, to be tried in order. Short names, without a registry,
+    /// resolve through the aliases or the unqualified search registries,
+    /// as the short name mode allows. Fully qualified names are returned
+    /// as they are.
+    pub fn resolve(&self, image: &str) -> Result<Vec<Reference>> {
+        if !is_short_name(image) {
+            return Ok(vec![Reference::try_from(image)?]);
+        }
+
+        let (name, suffix) = split_name(image);
+        if self.short_name_mode != ShortNameMode::Disabled {
+            if let Some(alias) = self.aliases.get(name) {
+                let qualified = format!("{}{}", alias, suffix);
+                return Ok(vec![Reference::try_from(qualified.as_str())?]);
+            }
+        }
+
+        if self.unqualified_search_registries.is_empty() {
+            return Err(anyhow!(
+                "short image name {} can not be resolved: no alias nor unqualified search registries",
+                image
+            ));
+        }
+
+        if self.short_name_mode == ShortNameMode::Enforcing
+            && self.unqualified_search_registries.len() > 1
+        {
+            return Err(anyhow!(
+                "short image name {} is ambiguous in enforcing mode, it may be in any of {:?}",
+                image,
+                self.unqualified_search_registries
+            ));
+        }
+
+        self.unqualified_search_registries
+            .iter()
+            .map(|registry| {
+                let qualified = format!("{}/{}{}", registry, name, suffix);
+                Reference::try_from(qualified.as_str())
+                    .map_err(|e| anyhow!("invalid image reference {}: {}", qualified, e))
+            })
+            .collect()
+    }
+
+    /// pull_sources returns the references to try, in order, to pull the
+    /// image `reference`: the mirrors of its registry entry, then the
+    /// (possibly rewritten) location of the entry.
+    /// It fails if the registry entry is blocked.
+    pub fn pull_sources(&self, reference: &Reference) -> Result<Vec<Reference>> {
+        let name = format!("{}/{}", reference.registry(), reference.repository());
+        let (registry, matched) = match self.find(reference, &name) {
+            Some(found) => found,
+            None => return Ok(vec![reference.clone()]),
+        };
+
+        if registry.blocked {
+            return Err(anyhow!(
+                "pulling image {} is blocked by registry {}",
+                reference.whole(),
+                registry.prefix
+            ));
+        }
+
+        let by_digest = reference.digest().is_some();
+        let mut sources = Vec::new();
+        for mirror in registry.mirror.iter() {
+            let used = match mirror.pull_from_mirror {
+                _ if registry.mirror_by_digest_only => by_digest,
+                PullFromMirror::All => true,
+                PullFromMirror::DigestOnly => by_digest,
+                PullFromMirror::TagOnly => !by_digest,
+            };
+
+            if used {
+                sources.push(rewrite(reference, &name[matched..], &mirror.location)?);
+            }
+        }
+
+        let location = if registry.location.is_empty() {
+            &name[..matched]
+        } else {
+            &registry.location
+        };
+        sources.push(rewrite(reference, &name[matched..], location)?);
+
+        Ok(sources)
+    }
+
+    // Find the registry entry with the longest prefix matching the image
+    // `name` of `reference`, with the length of the name part it matches.
+    fn find(&self, reference: &Reference, name: &str) -> Option<(&Registry, usize)> {
+        self.registry
+            .iter()
+            .filter_map(|registry| match registry.prefix.strip_prefix('*') {
+                Some(domain) => {
+                    let host = reference.registry().split(':').next().unwrap_or_default();
+                    host.ends_with(domain)
+                        .then(|| (registry, reference.registry().len(), domain.len()))
+                }
+                None => {
+                    let prefix = registry.prefix.as_str();
+                    let matches = name == prefix
+                        || (name.starts_with(prefix) && name[prefix.len()..].starts_with('/'));
+                    matches.then_some((registry, prefix.len(), prefix.len()))
+                }
+            })
+            .max_by_key(|(_, _, len)| *len)
+            .map(|(registry, matched, _)| (registry, matched))
+    }
+}
+
+// Whether an image name has no registry: a registry is the first path
+// component, if it has a "." or ":" or is "localhost".
+fn is_short_name(image: &str) -> bool {
+    match image.split_once('/') {
+        Some((registry, _)) => {
+            !(registry.contains('.') || registry.contains(':') || registry == "localhost")
+        }
+        None => true,
+    }
+}
+
+// Split an image name from its ":tag" and "@digest" suffix.
+fn split_name(image: &str) -> (&str, &str) {
+    let end = image.find('@').unwrap_or(image.len());
+    let last = image[..end].rfind('/').map(|i| i + 1).unwrap_or(0);
+    let end = image[last..end].find(':').map(|i| last + i).unwrap_or(end);
+
+    image.split_at(end)
+}
+
+// Build the reference to the image `path` under `location`, with the tag
+// and digest of `reference`.
+fn rewrite(reference: &Reference, path: &str, location: &str) -> Result<Reference> {
+    let mut image = format!("{}{}", location.trim_end_matches('/'), path);
+    if let Some(tag) = reference.tag() {
+        image = format!("{}:{}", image, tag);
+    }
+    if let Some(digest) = reference.digest() {
+        image = format!("{}@{}", image, digest);
+    }
+
+    Reference::try_from(image.as_str())
+        .map_err(|e| anyhow!("invalid rewritten reference {}: {}", image, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:6d5e1af19c7d4cf2bd6e2a4b1e8e4e3b0e1ea0c8b2a9f1f4a4e5c3c2e1d0f9a8";
+
+    fn sources(config: &RegistriesConfig, image: &str) -> Result<Vec<String>> {
+        let reference = Reference::try_from(image).unwrap();
+        Ok(config
+            .pull_sources(&reference)?
+            .iter()
+            .map(|r| r.whole())
+            .collect())
+    }
+
+    #[test]
+    fn test_registries_config() {
+        let data = r#"
+            [[registry]]
+            prefix = "docker.io"
+
+            [[registry.mirror]]
+            location = "mirror.gcr.io"
+
+            [[registry.mirror]]
+            location = "cache.example.com:5000/hub"
+            pull-from-mirror = "digest-only"
+
+            [[registry]]
+            prefix = "example.com/foo"
+            location = "internal.example.com/bar"
+
+            [[registry]]
+            prefix = "example.com/foo/blocked"
+            blocked = true
+
+            [[registry]]
+            prefix = "*.example.org"
+
+            [[registry.mirror]]
+            location = "mirror.example.org"
+            pull-from-mirror = "tag-only"
+        "#;
+        let config = RegistriesConfig::from_str(data).unwrap();
+        assert_eq!(config.registry.len(), 4);
+        assert_eq!(config.registry[2].location, "");
+
+        assert_eq!(
+            sources(&config, "busybox:latest").unwrap(),
+            vec![
+                "mirror.gcr.io/library/busybox:latest",
+                "docker.io/library/busybox:latest"
+            ]
+        );
+        assert_eq!(
+            sources(&config, &format!("busybox@{}", DIGEST)).unwrap(),
+            vec![
+                format!("mirror.gcr.io/library/busybox@{}", DIGEST),
+                format!("cache.example.com:5000/hub/library/busybox@{}", DIGEST),
+                format!("docker.io/library/busybox@{}", DIGEST),
+            ]
+        );
+        assert_eq!(
+            sources(&config, "example.com/foo/app:v1").unwrap(),
+            vec!["internal.example.com/bar/app:v1"]
+        );
+        assert_eq!(
+            sources(&config, "example.com/foobar/app:v1").unwrap(),
+            vec!["example.com/foobar/app:v1"]
+        );
+        assert!(sources(&config, "example.com/foo/blocked/app:v1").is_err());
+        assert_eq!(
+            sources(&config, "registry.example.org/app:v1").unwrap(),
+            vec!["mirror.example.org/app:v1", "registry.example.org/app:v1"]
+        );
+        assert_eq!(
+            sources(&config, &format!("registry.example.org/app@{}", DIGEST)).unwrap(),
+            vec![format!("registry.example.org/app@{}", DIGEST)]
+        );
+        assert_eq!(
+            sources(&config, "quay.io/prometheus/busybox:latest").unwrap(),
+            vec!["quay.io/prometheus/busybox:latest"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_short_names() {
+        let data = r#"
+            unqualified-search-registries = ["quay.io", "docker.io"]
+
+            [aliases]
+            "busybox" = "docker.io/library/busybox"
+            "prometheus/busybox" = "quay.io/prometheus/busybox"
+        "#;
+        let mut config = RegistriesConfig::from_str(data).unwrap();
+        assert_eq!(config.short_name_mode, ShortNameMode::Enforcing);
+
+        let resolve = |config: &RegistriesConfig, image: &str| -> Result<Vec<String>> {
+            Ok(config.resolve(image)?.iter().map(|r| r.whole()).collect())
+        };
+
+        assert_eq!(
+            resolve(&config, "busybox:1.35").unwrap(),
+            vec!["docker.io/library/busybox:1.35"]
+        );
+        assert_eq!(
+            resolve(&config, &format!("prometheus/busybox@{}", DIGEST)).unwrap(),
+            vec![format!("quay.io/prometheus/busybox@{}", DIGEST)]
+        );
+        assert_eq!(
+            resolve(&config, "example.com/app:v1").unwrap(),
+            vec!["example.com/app:v1"]
+        );
+        assert_eq!(
+            resolve(&config, "localhost/app:v1").unwrap(),
+            vec!["localhost/app:v1"]
+        );
+        assert!(resolve(&config, "alpine:3.16").is_err());
+
+        config.short_name_mode = ShortNameMode::Permissive;
+        assert_eq!(
+            resolve(&config, "alpine:3.16").unwrap(),
+            vec!["quay.io/alpine:3.16", "docker.io/library/alpine:3.16"]
+        );
+
+        config.short_name_mode = ShortNameMode::Disabled;
+        assert_eq!(
+            resolve(&config, "busybox:v1").unwrap(),
+            vec!["quay.io/busybox:v1", "docker.io/library/busybox:v1"]
+        );
+
+        config.short_name_mode = ShortNameMode::Enforcing;
+        config.unqualified_search_registries = vec!["quay.io".to_string()];
+        assert_eq!(
+            resolve(&config, "alpine:3.16").unwrap(),
+            vec!["quay.io/alpine:3.16"]
+        );
+
+        config.unqualified_search_registries.clear();
+        assert!(resolve(&config, "alpine").is_err());
+    }
+
+    #[test]
+    fn test_split_name() {
+        assert_eq!(split_name("busybox"), ("busybox", ""));
+        assert_eq!(split_name("busybox:latest"), ("busybox", ":latest"));
+        assert_eq!(
+            split_name("localhost:5000/busybox:v1"),
+            ("localhost:5000/busybox", ":v1")
+        );
+        assert_eq!(
+            split_name(&format!("busybox:v1@{}", DIGEST)),
+            ("busybox", format!(":v1@{}", DIGEST).as_str())
+        );
+    }
+
+    #[test]
+    fn test_registries_config_invalid() {
+        let tests = &[
+            "[[registry]]\nblocked = true",
+            "[[registry]]\nprefix = \"*.example.com\"\nlocation = \"example.com\"",
+            "[[registry]]\nprefix = \"example.com\"\nmirror-by-digest-only = true\n\
+             [[registry.mirror]]\nlocation = \"mirror.example.com\"\npull-from-mirror = \"tag-only\"",
+            "registry = 1",
+            "short-name-mode = \"prompt\"",
+            "[aliases]\n\"busybox\" = \"busybox\"",
+            "[aliases]\n\"example.com/busybox\" = \"example.com/busybox\"",
+        ];
+
+        for data in tests.iter() {
+            assert!(RegistriesConfig::from_str(data).is_err());
+        }
+    }
+}
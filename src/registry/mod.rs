@@ -0,0 +1,953 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::Reference;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, RANGE, RETRY_AFTER, WWW_AUTHENTICATE};
+use reqwest::redirect::Policy;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::auth::Credential;
+use crate::config::{RegistryTlsConfig, RetryConfig};
+use crate::errors::{Limit, PullError};
+
+pub mod config;
+
+/// The canonical name and the API endpoint of the Docker Hub registry.
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_ENDPOINT: &str = "registry-1.docker.io";
+
+/// Token lifetime when the token server does not tell, as per the docker
+/// token authentication specification.
+const DEFAULT_TOKEN_EXPIRES_IN: u64 = 60;
+
+/// Tokens are refreshed a little before they expire, to not have them
+/// expire during a request.
+const TOKEN_EXPIRATION_MARGIN: Duration = Duration::from_secs(5);
+
+/// The OAuth2 client ID presented when exchanging identity tokens.
+const OAUTH2_CLIENT_ID: &str = "image-rs";
+
+/// The end of a certificate in a PEM bundle.
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// An authentication challenge from the `WWW-Authenticate` header.
+#[derive(Debug, PartialEq, Eq)]
+enum Challenge {
+    Basic,
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+/// How a request is authorized.
+enum Authorization<'a> {
+    None,
+    Basic(&'a str, &'a str),
+    Bearer(String),
+}
+
+/// An error writing the pulled data out. It is never retried, as it comes
+/// from the consumer of the data rather than from the registry, like the
+/// unpacking which stopped reading.
+#[derive(Debug)]
+struct WriteError(io::Error);
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to write pulled data: {}", self.0)
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+struct CachedToken {
+    token: String,
+    expiration: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// The RegistryClient talks the OCI distribution protocol with remote
+/// registries. It authenticates with basic auth, bearer tokens or identity
+/// tokens, and caches the bearer tokens across manifest and blob requests.
+pub struct RegistryClient {
+    http: reqwest::Client,
+
+    // registry_http holds map of registry with its http client, for the
+    // registries with their own TLS settings.
+    registry_http: HashMap<String, reqwest::Client>,
+
+    // The http clients of the foreign layer URLs, which follow no redirect:
+    // the URL hosts are allowed by the foreign layers settings, the hosts
+    // they redirect to are not. foreign_http holds map of host with its
+    // http client, for the hosts with their own TLS settings.
+    foreign: reqwest::Client,
+    foreign_http: HashMap<String, reqwest::Client>,
+
+    // The registries accessed over plain HTTP.
+    plain_http: HashSet<String>,
+
+    retry: RetryConfig,
+
+    // The timeout of each request, and of each read of a response body.
+    request_timeout: Option<Duration>,
+
+    // The maximum size of a manifest response body.
+    max_manifest_bytes: u64,
+
+    // credentials holds map of registry and repository with its credential.
+    credentials: HashMap<String, Credential>,
+
+    // tokens holds map of registry and scope with bearer token.
+    tokens: Mutex<HashMap<String, CachedToken>>,
+}
+
+impl RegistryClient {
+    /// Construct a new RegistryClient, which accesses registries anonymously
+    /// until credentials are set.
+    pub fn new() -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .build()
+            .map_err(|e| anyhow!("failed to build http client: {}", e))?;
+        let foreign = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .map_err(|e| anyhow!("failed to build http client: {}", e))?;
+
+        Ok(RegistryClient {
+            http,
+            registry_http: HashMap::new(),
+            foreign,
+            foreign_http: HashMap::new(),
+            plain_http: HashSet::new(),
+            retry: RetryConfig::default(),
+            request_timeout: None,
+            max_manifest_bytes: u64::MAX,
+            credentials: HashMap::new(),
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Set the credential to pull `reference` with. A credential is only
+    /// ever presented for the registry and repository it is set for, the
+    /// other repositories of the registry are accessed anonymously.
+    pub fn set_credential(&mut self, reference: &Reference, credential: Credential) {
+        self.credentials
+            .insert(credential_key(reference), credential);
+    }
+
+    /// Apply the TLS and protocol settings of `registry`. The settings are
+    /// used for the registry and its token server, and for the foreign
+    /// layer URLs of the same "host[:port]".
+    pub fn set_tls(&mut self, registry: &str, tls: &RegistryTlsConfig) -> Result<()> {
+        let http = build_http(registry, tls, Policy::default())?;
+        self.registry_http.insert(registry.to_string(), http);
+        let http = build_http(registry, tls, Policy::none())?;
+        self.foreign_http.insert(registry.to_string(), http);
+
+        if tls.plain_http {
+            self.plain_http.insert(registry.to_string());
+        } else {
+            self.plain_http.remove(registry);
+        }
+
+        Ok(())
+    }
+
+    /// Set how requests failing with transient errors are retried.
+    pub fn set_retry(&mut self, retry: RetryConfig) {
+        self.retry = retry;
+    }
+
+    /// Set the timeout of each request, a response body is allowed to stall
+    /// for as long before the request fails. Timed out requests are retried
+    /// like the other transient errors.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Set the maximum size of a manifest, larger manifests are not read.
+    pub fn set_max_manifest_bytes(&mut self, max: u64) {
+        self.max_manifest_bytes = max;
+    }
+
+    fn http(&self, registry: &str) -> &reqwest::Client {
+        self.registry_http.get(registry).unwrap_or(&self.http)
+    }
+
+    fn foreign_http(&self, host: &str) -> &reqwest::Client {
+        self.foreign_http.get(host).unwrap_or(&self.foreign)
+    }
+
+    // The base URL of the repository API of `reference`.
+    fn repository_url(&self, reference: &Reference) -> String {
+        let scheme = if self.plain_http.contains(reference.registry()) {
+            "http"
+        } else {
+            "https"
+        };
+
+        repository_url(reference, scheme)
+    }
+
+    fn credential(&self, reference: &Reference) -> &Credential {
+        self.credentials
+            .get(&credential_key(reference))
+            .unwrap_or(&Credential::Anonymous)
+    }
+
+    /// Pull the manifest of `reference`, accepting `accepted_media_types`.
+    /// It returns the raw manifest data and its media type.
+    pub async fn pull_manifest_raw(
+        &self,
+        reference: &Reference,
+        accepted_media_types: &[&str],
+    ) -> Result<(Vec<u8>, String)> {
+        let name = reference
+            .digest()
+            .or_else(|| reference.tag())
+            .unwrap_or("latest");
+        let url = format!("{}/manifests/{}", self.repository_url(reference), name);
+
+        let mut res = self
+            .get(
+                Some(reference),
+                &url,
+                Some(accepted_media_types.join(", ")),
+                0,
+            )
+            .await?;
+        let media_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        // The manifest is read chunk by chunk, to stop as soon as it is
+        // larger than allowed.
+        let mut data = Vec::new();
+        while let Some(chunk) = self.timed(res.chunk()).await?? {
+            if (data.len() + chunk.len()) as u64 > self.max_manifest_bytes {
+                return Err(PullError::LimitExceeded {
+                    limit: Limit::ManifestBytes,
+                    max: self.max_manifest_bytes,
+                }
+                .into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok((data, media_type))
+    }
+
+    /// Pull the blob `digest` of `reference` from `offset`, and write it into
+    /// `out` as the data arrives. Transfers interrupted by transient errors
+    /// are resumed with Range requests from where they stopped.
+    pub async fn pull_blob<T: AsyncWrite + Unpin>(
+        &self,
+        reference: &Reference,
+        digest: &str,
+        offset: u64,
+        out: T,
+    ) -> Result<()> {
+        let url = format!("{}/blobs/{}", self.repository_url(reference), digest);
+        self.pull_data(Some(reference), &url, offset, out)
+            .await
+            .map_err(|e| e.context(format!("pull blob {} failed", digest)))
+    }
+
+    /// Pull the data of `url` from `offset` into `out`, like a blob but
+    /// without registry credentials, as the foreign layer URLs name any host.
+    /// The TLS settings of the URL host apply. Redirects are not followed,
+    /// a redirected request fails.
+    pub async fn pull_url<T: AsyncWrite + Unpin>(
+        &self,
+        url: &str,
+        offset: u64,
+        out: T,
+    ) -> Result<()> {
+        self.pull_data(None, url, offset, out)
+            .await
+            .map_err(|e| e.context(format!("pull {} failed", url)))
+    }
+
+    // Pull the data of `url` from `offset` into `out`, authorized for
+    // `reference` if any.
+    async fn pull_data<T: AsyncWrite + Unpin>(
+        &self,
+        reference: Option<&Reference>,
+        url: &str,
+        offset: u64,
+        mut out: T,
+    ) -> Result<()> {
+        let mut written = offset;
+        let mut attempt = 1;
+
+        loop {
+            let res = self.get(reference, url, None, written).await?;
+
+            // A registry may ignore the Range header and send the whole blob.
+            let skip = if res.status() == StatusCode::PARTIAL_CONTENT {
+                0
+            } else {
+                written
+            };
+
+            match self.copy_body(res, skip, &mut out, &mut written).await {
+                Ok(_) => break,
+                Err(e) if is_transient_error(&e) && attempt < self.retry.max_attempts => {
+                    tokio::time::sleep(self.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        out.flush().await.map_err(WriteError)?;
+
+        Ok(())
+    }
+
+    // Send a GET request for the data from `offset`, and retry it as long
+    // as it fails with transient errors.
+    async fn get(
+        &self,
+        reference: Option<&Reference>,
+        url: &str,
+        accept: Option<String>,
+        offset: u64,
+    ) -> Result<Response> {
+        let mut attempt = 1;
+
+        loop {
+            let res = self
+                .timed(async {
+                    match reference {
+                        Some(reference) => {
+                            self.authorized_get(reference, url, &accept, offset).await
+                        }
+                        None => {
+                            let host = url_host(url)?;
+                            let http = self.foreign_http(&host);
+                            self.send(http, url, &accept, offset, Authorization::None)
+                                .await
+                        }
+                    }
+                })
+                .await
+                .and_then(|res| res);
+            let retry_after = match &res {
+                Ok(res) if is_transient_status(res.status()) => Some(retry_after(res)),
+                Err(e) if is_transient_error(e) => Some(None),
+                _ => None,
+            };
+
+            match retry_after {
+                Some(retry_after) if attempt < self.retry.max_attempts => {
+                    let delay = retry_after
+                        .unwrap_or_else(|| self.retry.backoff(attempt))
+                        .min(self.retry.max_backoff());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return check_response(url, res?).await,
+            }
+        }
+    }
+
+    // Write the response body into `out`, skipping its first `skip` bytes,
+    // and count the bytes written in `written`.
+    async fn copy_body<T: AsyncWrite + Unpin>(
+        &self,
+        mut res: Response,
+        mut skip: u64,
+        out: &mut T,
+        written: &mut u64,
+    ) -> Result<()> {
+        while let Some(mut chunk) = self.timed(res.chunk()).await?? {
+            if skip > 0 {
+                let n = skip.min(chunk.len() as u64);
+                skip -= n;
+                chunk = chunk.slice(n as usize..);
+            }
+
+            out.write_all(&chunk).await.map_err(WriteError)?;
+            *written += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    // Run `f` within the request timeout, if any.
+    async fn timed<T, F: Future<Output = T>>(&self, f: F) -> Result<T> {
+        match self.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, f)
+                .await
+                .map_err(|e| anyhow::Error::new(e).context("registry request timed out")),
+            None => Ok(f.await),
+        }
+    }
+
+    // Send a GET request, and authenticate as the registry challenges.
+    async fn authorized_get(
+        &self,
+        reference: &Reference,
+        url: &str,
+        accept: &Option<String>,
+        offset: u64,
+    ) -> Result<Response> {
+        let token_key = format!(
+            "{}/repository:{}:pull",
+            reference.registry(),
+            reference.repository()
+        );
+
+        let registry = reference.registry();
+        let credential = self.credential(reference);
+        let authorization = match credential {
+            Credential::RegistryToken(token) => Authorization::Bearer(token.clone()),
+            _ => match self.cached_token(&token_key) {
+                Some(token) => Authorization::Bearer(token),
+                None => Authorization::None,
+            },
+        };
+
+        let res = self
+            .send(self.http(registry), url, accept, offset, authorization)
+            .await?;
+        if res.status() != StatusCode::UNAUTHORIZED
+            || matches!(credential, Credential::RegistryToken(_))
+        {
+            return Ok(res);
+        }
+
+        let challenge = res
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .map(parse_challenge)
+            .transpose()?
+            .ok_or_else(|| anyhow!("registry request {} unauthorized without challenge", url))?;
+
+        let authorization = match challenge {
+            Challenge::Basic => match credential {
+                Credential::Basic(username, password) => Authorization::Basic(username, password),
+                _ => return Err(anyhow!("registry {} requires basic auth", registry)),
+            },
+            Challenge::Bearer {
+                realm,
+                service,
+                scope,
+            } => {
+                let scope =
+                    scope.unwrap_or_else(|| format!("repository:{}:pull", reference.repository()));
+                let token = self
+                    .fetch_token(registry, credential, &realm, service.as_deref(), &scope)
+                    .await?;
+                let bearer = token.token.clone();
+                self.cache_token(token_key, token);
+                Authorization::Bearer(bearer)
+            }
+        };
+
+        self.send(self.http(registry), url, accept, offset, authorization)
+            .await
+    }
+
+    async fn send(
+        &self,
+        http: &reqwest::Client,
+        url: &str,
+        accept: &Option<String>,
+        offset: u64,
+        authorization: Authorization<'_>,
+    ) -> Result<Response> {
+        let mut req: RequestBuilder = http.get(url);
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept.as_str());
+        }
+
+        if offset > 0 {
+            req = req.header(RANGE, format!("bytes={}-", offset));
+        }
+
+        req = match authorization {
+            Authorization::None => req,
+            Authorization::Basic(username, password) => req.basic_auth(username, Some(password)),
+            Authorization::Bearer(token) => req.bearer_auth(token),
+        };
+
+        req.send()
+            .await
+            .map_err(|e| anyhow::Error::new(e).context(format!("registry request {} failed", url)))
+    }
+
+    // Get a bearer token from the token server `realm`.
+    async fn fetch_token(
+        &self,
+        registry: &str,
+        credential: &Credential,
+        realm: &str,
+        service: Option<&str>,
+        scope: &str,
+    ) -> Result<CachedToken> {
+        let mut params = vec![("scope", scope)];
+        if let Some(service) = service {
+            params.push(("service", service));
+        }
+
+        let req = match credential {
+            // Identity tokens are OAuth2 refresh tokens.
+            Credential::IdentityToken(refresh_token) => {
+                params.push(("grant_type", "refresh_token"));
+                params.push(("refresh_token", refresh_token));
+                params.push(("client_id", OAUTH2_CLIENT_ID));
+                self.http(registry).post(realm).form(&params)
+            }
+            Credential::Basic(username, password) => self
+                .http(registry)
+                .get(realm)
+                .query(&params)
+                .basic_auth(username, Some(password)),
+            _ => self.http(registry).get(realm).query(&params),
+        };
+
+        let res = req
+            .send()
+            .await
+            .map_err(|e| anyhow!("token request {} failed: {}", realm, e))?;
+        let res: TokenResponse = check_response(realm, res).await?.json().await?;
+
+        let token = res
+            .token
+            .or(res.access_token)
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| anyhow!("no token in token server {} response", realm))?;
+        let expires_in = Duration::from_secs(res.expires_in.unwrap_or(DEFAULT_TOKEN_EXPIRES_IN));
+
+        Ok(CachedToken {
+            token,
+            expiration: Instant::now() + expires_in.saturating_sub(TOKEN_EXPIRATION_MARGIN),
+        })
+    }
+
+    fn cached_token(&self, key: &str) -> Option<String> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens
+            .get(key)
+            .filter(|t| t.expiration > Instant::now())
+            .map(|t| t.token.clone())
+    }
+
+    fn cache_token(&self, key: String, token: CachedToken) {
+        self.tokens.lock().unwrap().insert(key, token);
+    }
+}
+
+// The base URL of the repository API of `reference`.
+fn repository_url(reference: &Reference, scheme: &str) -> String {
+    let registry = match reference.registry() {
+        DOCKER_HUB => DOCKER_HUB_ENDPOINT,
+        registry => registry,
+    };
+
+    format!("{}://{}/v2/{}", scheme, registry, reference.repository())
+}
+
+// Build the http client of `registry` with its TLS settings, following
+// redirects as `redirect` does.
+fn build_http(
+    registry: &str,
+    tls: &RegistryTlsConfig,
+    redirect: Policy,
+) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(redirect);
+
+    if let Some(ca_file) = &tls.ca_file {
+        for cert in read_pem_certificates(ca_file).map_err(|e| {
+            anyhow!(
+                "registry {}: invalid ca_file {:?}: {}",
+                registry,
+                ca_file,
+                e
+            )
+        })? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let identity = read_pem_identity(cert_file, key_file).map_err(|e| {
+                anyhow!(
+                    "registry {}: invalid cert_file {:?} or key_file {:?}: {}",
+                    registry,
+                    cert_file,
+                    key_file,
+                    e
+                )
+            })?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(anyhow!(
+                "registry {}: cert_file and key_file must be set together",
+                registry
+            ))
+        }
+    }
+
+    if tls.insecure_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder
+        .build()
+        .map_err(|e| anyhow!("registry {}: failed to build http client: {}", registry, e))
+}
+
+// The key of the credential to pull `reference` with, its registry and
+// repository, whatever its tag or digest.
+fn credential_key(reference: &Reference) -> String {
+    format!("{}/{}", reference.registry(), reference.repository())
+}
+
+// The "host[:port]" of `url`, to look its TLS settings up with.
+fn url_host(url: &str) -> Result<String> {
+    let url = reqwest::Url::parse(url).map_err(|e| anyhow!("invalid URL {}: {}", url, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("URL {} has no host", url))?;
+
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+// Read all the certificates of a PEM bundle.
+fn read_pem_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+    let pem = fs::read_to_string(path)?;
+    let certs = pem
+        .split_inclusive(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains(PEM_CERTIFICATE_END))
+        .map(|block| reqwest::Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<reqwest::Result<Vec<_>>>()?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found"));
+    }
+
+    Ok(certs)
+}
+
+// Read a client certificate chain and its private key.
+fn read_pem_identity(cert_file: &Path, key_file: &Path) -> Result<reqwest::Identity> {
+    let mut pem = fs::read(key_file)?;
+    pem.push(b'\n');
+    pem.extend(fs::read(cert_file)?);
+
+    Ok(reqwest::Identity::from_pem(&pem)?)
+}
+
+// Whether the registry may succeed if the request is sent again.
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Whether a request failed because of the connection, like a reset or
+// a timeout, rather than the registry refusing it. Requests which cannot
+// be built or sent, like those to invalid URLs, are not retried, and
+// neither are the failures writing the pulled data out.
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    if err.chain().any(|e| e.is::<WriteError>()) {
+        return false;
+    }
+
+    err.chain().any(|e| {
+        if e.is::<tokio::time::error::Elapsed>() {
+            return true;
+        }
+
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(e) => e.is_connect() || e.is_timeout() || e.is_body(),
+            None => false,
+        }
+    })
+}
+
+// The delay asked by the registry in the Retry-After header.
+fn retry_after(res: &Response) -> Option<Duration> {
+    res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+// Parse a Retry-After header value in seconds, HTTP dates are not
+// supported and fall back to the configured backoff.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+async fn check_response(url: &str, res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let body = res.text().await.unwrap_or_default();
+    Err(anyhow!(
+        "registry request {} failed with status {}: {}",
+        url,
+        status,
+        body
+    ))
+}
+
+// Parse a `WWW-Authenticate` header like:
+//    Bearer realm="https://auth.docker.io/token",service="registry.docker.io"
+fn parse_challenge(header: &str) -> Result<Challenge> {
+    let (scheme, params) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        return Ok(Challenge::Basic);
+    }
+
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(anyhow!("unsupported auth challenge: {}", header));
+    }
+
+    let mut params = parse_challenge_params(params);
+    let realm = params
+        .remove("realm")
+        .ok_or_else(|| anyhow!("missing realm in auth challenge: {}", header))?;
+
+    Ok(Challenge::Bearer {
+        realm,
+        service: params.remove("service"),
+        scope: params.remove("scope"),
+    })
+}
+
+// Parse comma separated key=value pairs, where values may be quoted
+// strings containing commas.
+fn parse_challenge_params(params: &str) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    _ => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+
+        res.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_parse_challenge() {
+        let tests = &[
+            (
+                r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/busybox:pull""#,
+                Challenge::Bearer {
+                    realm: "https://auth.docker.io/token".to_string(),
+                    service: Some("registry.docker.io".to_string()),
+                    scope: Some("repository:library/busybox:pull".to_string()),
+                },
+            ),
+            (
+                r#"Bearer realm="https://quay.io/v2/auth", service="quay.io", scope="repository:a/b:pull,push""#,
+                Challenge::Bearer {
+                    realm: "https://quay.io/v2/auth".to_string(),
+                    service: Some("quay.io".to_string()),
+                    scope: Some("repository:a/b:pull,push".to_string()),
+                },
+            ),
+            (
+                r#"bearer realm=https://example.com/token"#,
+                Challenge::Bearer {
+                    realm: "https://example.com/token".to_string(),
+                    service: None,
+                    scope: None,
+                },
+            ),
+            (r#"Basic realm="Registry""#, Challenge::Basic),
+        ];
+
+        for (header, challenge) in tests.iter() {
+            assert_eq!(&parse_challenge(header).unwrap(), challenge);
+        }
+
+        assert!(parse_challenge(r#"Bearer service="registry.docker.io""#).is_err());
+        assert!(parse_challenge(r#"Negotiate"#).is_err());
+    }
+
+    #[test]
+    fn test_repository_url() {
+        let mut client = RegistryClient::new().unwrap();
+        let reference = Reference::try_from("busybox:latest").unwrap();
+        assert_eq!(
+            client.repository_url(&reference),
+            "https://registry-1.docker.io/v2/library/busybox"
+        );
+
+        let reference = Reference::try_from("quay.io/prometheus/busybox:latest").unwrap();
+        assert_eq!(
+            client.repository_url(&reference),
+            "https://quay.io/v2/prometheus/busybox"
+        );
+
+        let tls = RegistryTlsConfig {
+            plain_http: true,
+            ..Default::default()
+        };
+        let reference = Reference::try_from("localhost:5000/busybox:latest").unwrap();
+        client.set_tls("localhost:5000", &tls).unwrap();
+        assert_eq!(
+            client.repository_url(&reference),
+            "http://localhost:5000/v2/busybox"
+        );
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::UNAUTHORIZED));
+
+        assert!(!is_transient_error(&anyhow!("manifest unknown")));
+        assert!(is_transient_error(
+            &anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset))
+                .context("pull failed")
+        ));
+        assert!(!is_transient_error(&anyhow::Error::new(io::Error::from(
+            io::ErrorKind::PermissionDenied
+        ))));
+        assert!(!is_transient_error(&anyhow::Error::new(WriteError(
+            io::Error::from(io::ErrorKind::BrokenPipe)
+        ))));
+
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let mut client = RegistryClient::new().unwrap();
+        assert_eq!(client.timed(async { 1 }).await.unwrap(), 1);
+
+        client.set_request_timeout(Some(Duration::from_millis(10)));
+        let err = client
+            .timed(tokio::time::sleep(Duration::from_secs(10)))
+            .await
+            .unwrap_err();
+        assert!(is_transient_error(&err));
+    }
+
+    #[test]
+    fn test_set_tls_errors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let not_pem = tempdir.path().join("not.pem");
+        fs::write(&not_pem, "not a certificate").unwrap();
+
+        let mut client = RegistryClient::new().unwrap();
+        let tests = &[
+            RegistryTlsConfig {
+                ca_file: Some(tempdir.path().join("missing.pem")),
+                ..Default::default()
+            },
+            RegistryTlsConfig {
+                ca_file: Some(not_pem.clone()),
+                ..Default::default()
+            },
+            RegistryTlsConfig {
+                cert_file: Some(not_pem.clone()),
+                ..Default::default()
+            },
+            RegistryTlsConfig {
+                cert_file: Some(not_pem.clone()),
+                key_file: Some(not_pem),
+                ..Default::default()
+            },
+        ];
+
+        for tls in tests.iter() {
+            let err = client.set_tls("registry.local:5000", tls).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("registry registry.local:5000: "));
+        }
+
+        let tls = RegistryTlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert!(client.set_tls("registry.local:5000", &tls).is_ok());
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::SchedulerConfig;
+
+/// The PullScheduler bounds the layer downloads and unpacking jobs of all
+/// the image pulls sharing it: the concurrent blob downloads in total and
+/// per registry, and the concurrent decrypt/unpack jobs.
+///
+/// Waiting jobs are served in the order they asked, so the layers of an
+/// image, which ask in order, get their downloads and unpacking started
+/// from the base layer up. The download and unpack limits are independent:
+/// a layer downloaded while no unpack slot is free waits for one with its
+/// blob in its partial file, without holding a download slot.
+pub struct PullScheduler {
+    config: SchedulerConfig,
+
+    downloads: Arc<Semaphore>,
+
+    unpacks: Arc<Semaphore>,
+
+    // registry_downloads holds map of registry with its download semaphore.
+    registry_downloads: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// DownloadPermit allows one blob download, until it is dropped.
+pub struct DownloadPermit {
+    _registry: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Default for PullScheduler {
+    fn default() -> PullScheduler {
+        PullScheduler::new(&SchedulerConfig::default())
+    }
+}
+
+impl PullScheduler {
+    /// Construct a new PullScheduler with the limits of `config`, a limit
+    /// of 0 is handled as 1.
+    pub fn new(config: &SchedulerConfig) -> Self {
+        PullScheduler {
+            config: config.clone(),
+            downloads: Arc::new(Semaphore::new(config.max_downloads.max(1))),
+            unpacks: Arc::new(Semaphore::new(config.max_unpacks.max(1))),
+            registry_downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a blob download from `registry` is allowed.
+    pub async fn download(&self, registry: &str) -> Result<DownloadPermit> {
+        let registry_downloads = self
+            .registry_downloads
+            .lock()
+            .unwrap()
+            .entry(registry.to_string())
+            .or_insert_with(|| {
+                Arc::new(Semaphore::new(
+                    self.config.max_downloads_per_registry.max(1),
+                ))
+            })
+            .clone();
+
+        // The registry permit is taken first, so that a job waiting for
+        // its registry does not hold a download slot of other registries.
+        let registry_permit = registry_downloads
+            .acquire_owned()
+            .await
+            .map_err(|e| anyhow!("download scheduler closed: {}", e))?;
+        let global_permit = self
+            .downloads
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| anyhow!("download scheduler closed: {}", e))?;
+
+        Ok(DownloadPermit {
+            _registry: registry_permit,
+            _global: global_permit,
+        })
+    }
+
+    /// Wait until a decrypt/unpack job is allowed.
+    pub async fn unpack(&self) -> Result<OwnedSemaphorePermit> {
+        self.unpacks
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| anyhow!("unpack scheduler closed: {}", e))
+    }
+
+    /// Allow a decrypt/unpack job if one is allowed now, without waiting.
+    pub fn try_unpack(&self) -> Option<OwnedSemaphorePermit> {
+        self.unpacks.clone().try_acquire_owned().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn blocked<F: std::future::Future>(f: F) -> bool {
+        tokio::time::timeout(Duration::from_millis(10), f)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn test_pull_scheduler() {
+        let scheduler = PullScheduler::new(&SchedulerConfig {
+            max_downloads: 3,
+            max_downloads_per_registry: 2,
+            max_unpacks: 1,
+        });
+
+        let quay_1 = scheduler.download("quay.io").await.unwrap();
+        let _quay_2 = scheduler.download("quay.io").await.unwrap();
+        assert!(blocked(scheduler.download("quay.io")).await);
+
+        let _docker = scheduler.download("docker.io").await.unwrap();
+        assert!(blocked(scheduler.download("gcr.io")).await);
+
+        drop(quay_1);
+        let _gcr = scheduler.download("gcr.io").await.unwrap();
+
+        let unpack = scheduler.unpack().await.unwrap();
+        assert!(blocked(scheduler.unpack()).await);
+        assert!(scheduler.try_unpack().is_none());
+        drop(unpack);
+        assert!(scheduler.unpack().await.is_ok());
+        assert!(scheduler.try_unpack().is_some());
+    }
+}
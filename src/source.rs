@@ -0,0 +1,391 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
+use oci_distribution::{manifest, Reference};
+use signature::TransportName;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io::AsyncWrite;
+
+use crate::archive::{
+    DockerArchive, DockerArchiveReference, DOCKER_ARCHIVE_PREFIX, OCI_ARCHIVE_PREFIX,
+};
+use crate::config::LimitsConfig;
+use crate::dir::{DirImage, DIR_PREFIX};
+use crate::layout::{LayoutReference, OciLayout, OCI_LAYOUT_PREFIX};
+use crate::registry::RegistryClient;
+
+/// The prefix of the image references pointing to a registry, references
+/// without a transport prefix point to a registry too.
+pub const DOCKER_PREFIX: &str = "docker://";
+
+/// The accepted manifest media types.
+pub const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    manifest::OCI_IMAGE_MEDIA_TYPE,
+    manifest::IMAGE_MANIFEST_MEDIA_TYPE,
+    manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+    manifest::IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
+
+/// ImageSource reads the manifests and blobs of an image, from a registry
+/// or from a local storage. Nothing read from a source is trusted, the
+/// manifests and blobs are verified against their digests by the puller.
+pub trait ImageSource: fmt::Display + Send + Sync {
+    /// The transport the image is read with, for the signature policy.
+    fn transport(&self) -> TransportName;
+
+    /// The docker reference of the image, if the source names it.
+    fn docker_reference(&self) -> Option<&str>;
+
+    /// The policy scope of a local image: its absolute path, with the image
+    /// the reference names in the path as a suffix, like `:<tag>`. Local
+    /// images are scoped by the path they are read from, never by a name
+    /// read from the image. Registry images have none, they are scoped by
+    /// their docker reference.
+    fn local_scope(&self) -> Result<Option<(String, String)>>;
+
+    /// The digest the image manifest or index is pinned to, if any.
+    fn manifest_digest(&self) -> Option<&str>;
+
+    /// Get the raw manifest or index of the image, or with `digest` one of
+    /// the image manifests of the index, within the manifest size limit.
+    fn get_manifest<'a>(&'a self, digest: Option<&'a str>) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// Stream the blob `digest` from `offset` into `out`, as it is read.
+    fn stream_blob<'a>(
+        &'a self,
+        digest: &'a str,
+        offset: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Parse the transport of `image` from its prefix, one of `docker://`,
+/// `oci:`, `oci-archive:`, `docker-archive:` or `dir:`. References without
+/// a transport prefix point to a registry. It returns the transport, with
+/// the image reference stripped of the prefix.
+pub fn parse_transport(image: &str) -> (TransportName, &str) {
+    let transports = [
+        (DOCKER_PREFIX, TransportName::Docker),
+        (OCI_ARCHIVE_PREFIX, TransportName::OciArchive),
+        (OCI_LAYOUT_PREFIX, TransportName::Oci),
+        (DOCKER_ARCHIVE_PREFIX, TransportName::DockerArchive),
+        (DIR_PREFIX, TransportName::Dir),
+    ];
+
+    transports
+        .iter()
+        .find_map(|(prefix, transport)| image.strip_prefix(prefix).map(|rest| (*transport, rest)))
+        .unwrap_or((TransportName::Docker, image))
+}
+
+/// Open the local image source of `image`, a reference with one of the
+/// `oci:`, `oci-archive:`, `docker-archive:` or `dir:` prefixes. The
+/// manifests and configs are read within `limits`.
+pub fn open_local(image: &str, limits: &LimitsConfig) -> Result<Box<dyn ImageSource>> {
+    match parse_transport(image) {
+        (TransportName::Oci, _) | (TransportName::OciArchive, _) => {
+            let reference = LayoutReference::from_str(image)?;
+            Ok(Box::new(OciLayout::open(reference, limits)?))
+        }
+        (TransportName::DockerArchive, _) => {
+            let reference = DockerArchiveReference::from_str(image)?;
+            Ok(Box::new(DockerArchive::open(reference, limits)?))
+        }
+        (TransportName::Dir, path) => Ok(Box::new(DirImage::open(Path::new(path), limits)?)),
+        (TransportName::Docker, _) => Err(anyhow!("{} is not a local image", image)),
+    }
+}
+
+// The policy scope of the local image at `path`, with `suffix`. Policy
+// scopes name absolute paths, the path is resolved as such.
+fn path_scope(path: &Path, suffix: String) -> Result<Option<(String, String)>> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| anyhow!("failed to resolve image path {}: {}", path.display(), e))?;
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid image path {}", path.display()))?;
+
+    Ok(Some((path.to_string(), suffix)))
+}
+
+/// An image of a registry, or of one of its mirrors.
+pub struct RegistrySource<'a> {
+    client: &'a RegistryClient,
+    reference: Reference,
+    name: String,
+}
+
+impl<'a> RegistrySource<'a> {
+    /// Constructs a source reading the image `reference` with `client`.
+    pub fn new(client: &'a RegistryClient, reference: Reference) -> Self {
+        let name = reference.whole();
+        RegistrySource {
+            client,
+            reference,
+            name,
+        }
+    }
+
+    /// The reference of the image.
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+}
+
+impl fmt::Display for RegistrySource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name.fmt(f)
+    }
+}
+
+impl ImageSource for RegistrySource<'_> {
+    fn transport(&self) -> TransportName {
+        TransportName::Docker
+    }
+
+    fn docker_reference(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn local_scope(&self) -> Result<Option<(String, String)>> {
+        Ok(None)
+    }
+
+    fn manifest_digest(&self) -> Option<&str> {
+        self.reference.digest()
+    }
+
+    fn get_manifest<'a>(&'a self, digest: Option<&'a str>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let reference = match digest {
+                Some(digest) => Reference::with_digest(
+                    self.reference.registry().to_string(),
+                    self.reference.repository().to_string(),
+                    digest.to_string(),
+                ),
+                None => self.reference.clone(),
+            };
+
+            let (manifest, _) = self
+                .client
+                .pull_manifest_raw(&reference, MANIFEST_MEDIA_TYPES)
+                .await?;
+            Ok(manifest)
+        })
+    }
+
+    fn stream_blob<'a>(
+        &'a self,
+        digest: &'a str,
+        offset: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.client.pull_blob(&self.reference, digest, offset, out))
+    }
+}
+
+impl fmt::Display for OciLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.reference().fmt(f)
+    }
+}
+
+impl ImageSource for OciLayout {
+    fn transport(&self) -> TransportName {
+        if self.reference().archive {
+            TransportName::OciArchive
+        } else {
+            TransportName::Oci
+        }
+    }
+
+    fn docker_reference(&self) -> Option<&str> {
+        OciLayout::docker_reference(self)
+    }
+
+    fn local_scope(&self) -> Result<Option<(String, String)>> {
+        let reference = self.reference();
+        let suffix = match (&reference.tag, &reference.digest) {
+            (Some(tag), _) => format!(":{}", tag),
+            (None, Some(digest)) => format!("@{}", digest),
+            (None, None) => String::new(),
+        };
+        path_scope(&reference.path, suffix)
+    }
+
+    fn manifest_digest(&self) -> Option<&str> {
+        Some(OciLayout::manifest_digest(self))
+    }
+
+    fn get_manifest<'a>(&'a self, digest: Option<&'a str>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        let digest = digest.unwrap_or_else(|| OciLayout::manifest_digest(self));
+        Box::pin(async move { self.read_manifest(digest) })
+    }
+
+    fn stream_blob<'a>(
+        &'a self,
+        digest: &'a str,
+        offset: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.copy_blob(digest, offset, out))
+    }
+}
+
+impl fmt::Display for DockerArchive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.reference().fmt(f)
+    }
+}
+
+impl ImageSource for DockerArchive {
+    fn transport(&self) -> TransportName {
+        TransportName::DockerArchive
+    }
+
+    fn docker_reference(&self) -> Option<&str> {
+        DockerArchive::docker_reference(self)
+    }
+
+    fn local_scope(&self) -> Result<Option<(String, String)>> {
+        let reference = self.reference();
+        let suffix = match &reference.image {
+            Some(image) => format!(":{}", image),
+            None => String::new(),
+        };
+        path_scope(&reference.path, suffix)
+    }
+
+    fn manifest_digest(&self) -> Option<&str> {
+        Some(DockerArchive::manifest_digest(self))
+    }
+
+    fn get_manifest<'a>(&'a self, digest: Option<&'a str>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        let digest = digest.unwrap_or_else(|| DockerArchive::manifest_digest(self));
+        Box::pin(async move { self.read_manifest(digest) })
+    }
+
+    fn stream_blob<'a>(
+        &'a self,
+        digest: &'a str,
+        offset: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.copy_blob(digest, offset, out))
+    }
+}
+
+impl fmt::Display for DirImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", DIR_PREFIX, self.path().display())
+    }
+}
+
+impl ImageSource for DirImage {
+    fn transport(&self) -> TransportName {
+        TransportName::Dir
+    }
+
+    // The directory does not name the image.
+    fn docker_reference(&self) -> Option<&str> {
+        None
+    }
+
+    fn local_scope(&self) -> Result<Option<(String, String)>> {
+        path_scope(self.path(), String::new())
+    }
+
+    fn manifest_digest(&self) -> Option<&str> {
+        Some(DirImage::manifest_digest(self))
+    }
+
+    fn get_manifest<'a>(&'a self, digest: Option<&'a str>) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move { self.read_manifest(digest) })
+    }
+
+    fn stream_blob<'a>(
+        &'a self,
+        digest: &'a str,
+        offset: u64,
+        out: &'a mut (dyn AsyncWrite + Send + Unpin),
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.copy_blob(digest, offset, out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_transport() {
+        let tests = [
+            ("busybox", TransportName::Docker, "busybox"),
+            (
+                "localhost:5000/busybox:1.0",
+                TransportName::Docker,
+                "localhost:5000/busybox:1.0",
+            ),
+            (
+                "docker://quay.io/busybox:latest",
+                TransportName::Docker,
+                "quay.io/busybox:latest",
+            ),
+            (
+                "oci:/images/busybox:1.0",
+                TransportName::Oci,
+                "/images/busybox:1.0",
+            ),
+            (
+                "oci-archive:/images/busybox.tar",
+                TransportName::OciArchive,
+                "/images/busybox.tar",
+            ),
+            (
+                "docker-archive:/images/busybox.tar:busybox:1.0",
+                TransportName::DockerArchive,
+                "/images/busybox.tar:busybox:1.0",
+            ),
+            ("dir:/images/busybox", TransportName::Dir, "/images/busybox"),
+        ];
+
+        for (image, transport, rest) in tests.iter() {
+            assert_eq!(parse_transport(image), (*transport, *rest), "{}", image);
+        }
+
+        assert!(open_local("quay.io/busybox:latest", &LimitsConfig::default()).is_err());
+        assert!(open_local("docker://quay.io/busybox:latest", &LimitsConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_local_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_dir = dir.path().join("images").join("busybox");
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(
+            image_dir.join("version"),
+            "Directory Transport Version: 1.1\n",
+        )
+        .unwrap();
+        fs::write(image_dir.join("manifest.json"), br#"{"schemaVersion":2}"#).unwrap();
+
+        // The scope is the resolved path the image is read from.
+        let relative = dir.path().join("images").join(".").join("busybox");
+        let source = open_local(
+            &format!("{}{}", DIR_PREFIX, relative.display()),
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+        let path = fs::canonicalize(&image_dir).unwrap();
+        assert_eq!(
+            source.local_scope().unwrap(),
+            Some((path.to_str().unwrap().to_string(), String::new()))
+        );
+    }
+}
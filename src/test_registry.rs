@@ -0,0 +1,1370 @@
+// Copyright (c) 2022 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use oci_distribution::manifest;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::config::RegistryTlsConfig;
+use crate::decoder::Compression;
+use crate::digest::{digest_bytes, split_digest};
+use crate::platform::Platform;
+use crate::validate::get_resource::get_resource_service_server::{
+    GetResourceService, GetResourceServiceServer,
+};
+use crate::validate::get_resource::{GetResourceRequest, GetResourceResponse};
+use crate::validate::AA_GETRESOURCE_ADDR;
+use keyprovider::key_provider_service_server::{KeyProviderService, KeyProviderServiceServer};
+use keyprovider::{KeyProviderKeyWrapProtocolInput, KeyProviderKeyWrapProtocolOutput};
+
+mod keyprovider {
+    #![allow(unknown_lints)]
+    #![allow(clippy::derive_partial_eq_without_eq)]
+    tonic::include_proto!("keyprovider");
+}
+
+/// The service name of the test registry token server.
+const TOKEN_SERVICE: &str = "test-registry";
+
+/// The maximum size of a request head.
+const MAX_REQUEST_HEAD: usize = 64 * 1024;
+
+/// The encrypted layer fixtures, see `scripts/generate_encrypted_layer.py`.
+const ENCRYPTED_LAYER: &str = "test_data/encrypted_layer.json";
+const ENCRYPTED_LAYER_KBS: &str = "test_data/encrypted_layer_kbs.json";
+
+/// The address the attestation agent serves the ocicrypt key provider
+/// protocol on, as `OCICRYPT_KEYPROVIDER_CONFIG` sets it.
+const AA_KEYPROVIDER_ADDR: &str = "127.0.0.1:48888";
+
+/// Whether a TestKbs is running, on the fixed attestation agent addresses.
+static KBS_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The ocicrypt key provider config, with the attestation agent key
+/// provider of the TestKbs.
+pub const OCICRYPT_KEYPROVIDER_CONFIG: &str = "test_data/ocicrypt_keyprovider.conf";
+
+/// The private key of the signed image fixtures, its public key ring is
+/// `TEST_GPG_PUBKEY`.
+const TEST_GPG_KEY: &str = "test_data/simple-signing-scheme/private_key_for_tests.gpg";
+
+/// The public key ring to verify the signed image fixtures with.
+pub const TEST_GPG_PUBKEY: &str = "test_data/simple-signing-scheme/pubkey_for_tests.gpg";
+
+/// The TestRegistry is an OCI distribution v2 registry served on localhost
+/// over plain HTTP, for tests to pull fixture images from without network.
+///
+/// It serves the manifests and blobs pushed to it, with optional bearer
+/// token auth, blob Range requests, and faults injected in its responses.
+/// The registry is stopped when dropped.
+pub struct TestRegistry {
+    addr: SocketAddr,
+    state: Arc<Mutex<RegistryState>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    // manifests holds map of (repository, tag or digest) with the manifest
+    // media type and data.
+    manifests: HashMap<(String, String), (String, Vec<u8>)>,
+
+    // blobs holds map of blob digest with blob data, for all repositories.
+    blobs: HashMap<String, Vec<u8>>,
+
+    // credentials are the username and password required to get a token,
+    // when auth is enabled.
+    credentials: Option<(String, String)>,
+
+    // token is the bearer token issued to authenticated clients.
+    token: String,
+
+    faults: Vec<Fault>,
+
+    requests: Vec<String>,
+}
+
+/// A fault injected in the responses of the test registry. Each fault
+/// applies once, to the first request it matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer a request with 429 Too Many Requests.
+    TooManyRequests,
+
+    /// Send half of the blob `digest`, then close the connection.
+    TruncatedBlob(String),
+
+    /// Send the blob `digest` with its data altered.
+    WrongDigest(String),
+
+    /// Send half of the blob `digest`, then stall without closing the
+    /// connection.
+    StalledBlob(String),
+
+    /// Redirect the request of the blob `digest` to the URL `location`.
+    RedirectedBlob(String, String),
+}
+
+impl TestRegistry {
+    /// Start a test registry on a free localhost port.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(RegistryState::default()));
+
+        let task = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, addr, state.clone()));
+                }
+            })
+        };
+
+        Ok(TestRegistry { addr, state, task })
+    }
+
+    /// The "host:port" of the registry, to use in image references.
+    pub fn host(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// The reference of the image `repository:tag` in the registry.
+    pub fn reference(&self, repository: &str, tag: &str) -> String {
+        format!("{}/{}:{}", self.host(), repository, tag)
+    }
+
+    /// The TLS settings to access the registry with, over plain HTTP.
+    pub fn tls_config(&self) -> RegistryTlsConfig {
+        RegistryTlsConfig {
+            plain_http: true,
+            ..Default::default()
+        }
+    }
+
+    /// Require a bearer token for all the registry requests, issued by the
+    /// registry token server for `username` and `password`.
+    pub fn set_auth(&self, username: &str, password: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.credentials = Some((username.to_string(), password.to_string()));
+        state.token = format!("token-{}", self.addr.port());
+    }
+
+    /// Inject `fault` in the registry responses.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// The requests served so far, as "METHOD target" with their Range
+    /// header if any.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The base of the URLs of the blobs of `repository`, for the foreign
+    /// layers of test images to be fetched from the registry.
+    pub fn blob_url_base(&self, repository: &str) -> String {
+        format!("http://{}/v2/{}/blobs/", self.addr, repository)
+    }
+
+    /// Push the blobs of the foreign layers of `image`, they are not pushed
+    /// with the image.
+    pub fn push_foreign_blobs(&self, image: &TestImage) {
+        let mut state = self.state.lock().unwrap();
+        for layer in image.layers.iter().filter(|l| !l.urls.is_empty()) {
+            state
+                .blobs
+                .insert(digest_bytes(&layer.blob, None).unwrap(), layer.blob.clone());
+        }
+    }
+
+    /// Push `image` as `repository:tag`, it returns the digest of the image
+    /// manifest.
+    pub fn push_image(&self, repository: &str, tag: &str, image: &TestImage) -> String {
+        let manifest = self.push_blobs(image);
+        self.push_manifest(repository, tag, manifest::OCI_IMAGE_MEDIA_TYPE, manifest)
+    }
+
+    /// Push `images` as the multi-platform image `repository:tag`, in an OCI
+    /// image index. It returns the digest of the image index.
+    pub fn push_index(&self, repository: &str, tag: &str, images: &[TestImage]) -> String {
+        let manifests: Vec<Value> = images
+            .iter()
+            .map(|image| {
+                let manifest = self.push_blobs(image);
+                let entry = json!({
+                    "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                    "digest": digest_bytes(&manifest, None).unwrap(),
+                    "size": manifest.len(),
+                    "platform": image.platform_json(),
+                });
+                self.push_manifest(repository, "", manifest::OCI_IMAGE_MEDIA_TYPE, manifest);
+                entry
+            })
+            .collect();
+
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": manifests,
+        });
+        self.push_manifest(
+            repository,
+            tag,
+            manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            index.to_string().into_bytes(),
+        )
+    }
+
+    // Store the blobs of `image`, and return its manifest.
+    fn push_blobs(&self, image: &TestImage) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        for layer in image.layers.iter().filter(|l| l.urls.is_empty()) {
+            state
+                .blobs
+                .insert(digest_bytes(&layer.blob, None).unwrap(), layer.blob.clone());
+        }
+        let config = image.config();
+        state
+            .blobs
+            .insert(digest_bytes(&config, None).unwrap(), config);
+
+        image.manifest()
+    }
+
+    // Store `manifest` by digest, and by `tag` unless empty.
+    fn push_manifest(
+        &self,
+        repository: &str,
+        tag: &str,
+        media_type: &str,
+        manifest: Vec<u8>,
+    ) -> String {
+        let digest = digest_bytes(&manifest, None).unwrap();
+        let mut state = self.state.lock().unwrap();
+        let mut references = vec![digest.clone()];
+        if !tag.is_empty() {
+            references.push(tag.to_string());
+        }
+        for reference in references {
+            state.manifests.insert(
+                (repository.to_string(), reference),
+                (media_type.to_string(), manifest.clone()),
+            );
+        }
+
+        digest
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The TestKbs serves KBS resources in place of the attestation agent, on
+/// its getresource address, for tests to get resources from the KBS without
+/// an attestation agent nor network. Resources are looked up by name, and
+/// the requests record the KBC name and KBS URI they were sent for.
+///
+/// It serves the attestation agent key provider too, for ocicrypt to unwrap
+/// the layer keys with the keys of the KBS, by their key ID, as configured
+/// by `OCICRYPT_KEYPROVIDER_CONFIG`.
+///
+/// Only one TestKbs can run at a time, the next one waits for the previous
+/// one to be dropped, which stops it.
+pub struct TestKbs {
+    state: Arc<Mutex<KbsState>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct KbsState {
+    resources: HashMap<String, Vec<u8>>,
+    requests: Vec<(String, String, String)>,
+}
+
+impl TestKbs {
+    /// Start serving the KBS `resources`, by resource name.
+    pub async fn start(resources: HashMap<String, Vec<u8>>) -> Result<Self> {
+        while KBS_RUNNING.swap(true, Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let listeners = async {
+            let getresource = bind(AA_GETRESOURCE_ADDR.trim_start_matches("http://")).await?;
+            Ok::<_, anyhow::Error>((getresource, bind(AA_KEYPROVIDER_ADDR).await?))
+        };
+        let (getresource, keyprovider) = match listeners.await {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                KBS_RUNNING.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        let state = Arc::new(Mutex::new(KbsState {
+            resources,
+            ..Default::default()
+        }));
+
+        let router = tonic::transport::Server::builder().add_service(
+            GetResourceServiceServer::new(KbsService {
+                state: state.clone(),
+            }),
+        );
+        let getresource = tokio::spawn(async move {
+            let _ = router.serve_with_incoming(incoming(getresource)).await;
+        });
+        let router = tonic::transport::Server::builder().add_service(
+            KeyProviderServiceServer::new(KbsService {
+                state: state.clone(),
+            }),
+        );
+        let keyprovider = tokio::spawn(async move {
+            let _ = router.serve_with_incoming(incoming(keyprovider)).await;
+        });
+
+        Ok(TestKbs {
+            state,
+            tasks: vec![getresource, keyprovider],
+        })
+    }
+
+    /// The requests served, as KBC name, KBS URI and resource name, or key
+    /// ID for the keys unwrapped.
+    pub fn requests(&self) -> Vec<(String, String, String)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for TestKbs {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+        KBS_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+// Bind `addr`, once the listener of the previous TestKbs is closed: its
+// server task may still be stopping.
+async fn bind(addr: &str) -> Result<TcpListener> {
+    let mut attempts = 0;
+    loop {
+        match TcpListener::bind(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempts < 100 => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            res => return res.map_err(Into::into),
+        }
+    }
+}
+
+// The connections accepted by `listener`, for a gRPC server to serve.
+fn incoming(listener: TcpListener) -> impl futures_util::Stream<Item = std::io::Result<TcpStream>> {
+    futures_util::stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await.map(|(stream, _)| stream), listener))
+    })
+}
+
+struct KbsService {
+    state: Arc<Mutex<KbsState>>,
+}
+
+#[tonic::async_trait]
+impl GetResourceService for KbsService {
+    async fn get_resource(
+        &self,
+        request: tonic::Request<GetResourceRequest>,
+    ) -> Result<tonic::Response<GetResourceResponse>, tonic::Status> {
+        let request = request.into_inner();
+        let description: Value = serde_json::from_str(&request.resource_description)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        let name = description["name"].as_str().unwrap_or_default().to_string();
+
+        let mut state = self.state.lock().unwrap();
+        state
+            .requests
+            .push((request.kbc_name, request.kbs_uri, name.clone()));
+        match state.resources.get(&name) {
+            Some(resource) => Ok(tonic::Response::new(GetResourceResponse {
+                resource: resource.clone(),
+            })),
+            None => Err(tonic::Status::not_found(name)),
+        }
+    }
+}
+
+// The annotation of the layers encrypted with the attestation agent key
+// provider, the layer key is wrapped with the KBS key `kid`.
+#[derive(Deserialize)]
+struct AnnotationPacket {
+    kid: String,
+    wrapped_data: String,
+    iv: String,
+    wrap_type: String,
+}
+
+#[tonic::async_trait]
+impl KeyProviderService for KbsService {
+    async fn wrap_key(
+        &self,
+        _request: tonic::Request<KeyProviderKeyWrapProtocolInput>,
+    ) -> Result<tonic::Response<KeyProviderKeyWrapProtocolOutput>, tonic::Status> {
+        Err(tonic::Status::unimplemented(
+            "the test KBS does not wrap keys",
+        ))
+    }
+
+    // Unwrap the layer key of the annotation, like the attestation agent
+    // does with the KBC named by the decrypt config parameters.
+    async fn un_wrap_key(
+        &self,
+        request: tonic::Request<KeyProviderKeyWrapProtocolInput>,
+    ) -> Result<tonic::Response<KeyProviderKeyWrapProtocolOutput>, tonic::Status> {
+        let invalid = |e: anyhow::Error| tonic::Status::invalid_argument(e.to_string());
+        let input: Value =
+            serde_json::from_slice(&request.into_inner().key_provider_key_wrap_protocol_input)
+                .map_err(|e| invalid(e.into()))?;
+        let params = &input["keyunwrapparams"];
+
+        let aa_kbc_params = params["dc"]["Parameters"]["attestation-agent"][0]
+            .as_str()
+            .ok_or_else(|| invalid(anyhow!("no attestation-agent decrypt config")))?;
+        let aa_kbc_params =
+            String::from_utf8(base64::decode(aa_kbc_params).map_err(|e| invalid(e.into()))?)
+                .map_err(|e| invalid(e.into()))?;
+        let (kbc_name, kbs_uri) = aa_kbc_params
+            .split_once("::")
+            .ok_or_else(|| invalid(anyhow!("invalid aa_kbc_params {}", aa_kbc_params)))?;
+
+        let annotation = params["annotation"]
+            .as_str()
+            .ok_or_else(|| invalid(anyhow!("no annotation")))?;
+        let annotation: AnnotationPacket =
+            serde_json::from_slice(&base64::decode(annotation).map_err(|e| invalid(e.into()))?)
+                .map_err(|e| invalid(e.into()))?;
+
+        let key = {
+            let mut state = self.state.lock().unwrap();
+            state.requests.push((
+                kbc_name.to_string(),
+                kbs_uri.to_string(),
+                annotation.kid.clone(),
+            ));
+            state.resources.get(&annotation.kid).cloned()
+        }
+        .ok_or_else(|| tonic::Status::not_found(annotation.kid.clone()))?;
+
+        let opts_data = unwrap_key(&key, &annotation).map_err(invalid)?;
+        let output = json!({ "keyunwrapresults": { "optsdata": opts_data } });
+        Ok(tonic::Response::new(KeyProviderKeyWrapProtocolOutput {
+            key_provider_key_wrap_protocol_output: output.to_string().into_bytes(),
+        }))
+    }
+}
+
+// Unwrap the layer key of `annotation` with the KBS `key`.
+fn unwrap_key(key: &[u8], annotation: &AnnotationPacket) -> Result<Vec<u8>> {
+    if annotation.wrap_type != "A256GCM" {
+        return Err(anyhow!("unsupported wrap type {}", annotation.wrap_type));
+    }
+
+    let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow!("invalid KBS key"))?;
+    let iv = base64::decode(&annotation.iv)?;
+    let nonce = Nonce::try_assume_unique_for_key(&iv).map_err(|_| anyhow!("invalid iv"))?;
+    let mut data = base64::decode(&annotation.wrapped_data)?;
+    let opts_data = LessSafeKey::new(key)
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("failed to unwrap the key of {}", annotation.kid))?;
+
+    Ok(opts_data.to_vec())
+}
+
+/// Write `signature` of the image `repository@digest` in the `sigstore`
+/// directory, in the layout the simple signing lookaside uses, for the
+/// signature policy to find it with a `file://` sigstore.
+pub fn write_signature(
+    sigstore: &Path,
+    repository: &str,
+    digest: &str,
+    signature: &[u8],
+) -> Result<()> {
+    let (algorithm, encoded) = split_digest(digest)?;
+    let dir = sigstore.join(format!("{}@{}={}", repository, algorithm, encoded));
+    fs::create_dir_all(&dir)?;
+
+    let count = fs::read_dir(&dir)?.count();
+    fs::write(dir.join(format!("signature-{}", count + 1)), signature)?;
+
+    Ok(())
+}
+
+/// A TestImage is a fixture image built in memory, to push to a test
+/// registry.
+#[derive(Clone, Debug)]
+pub struct TestImage {
+    platform: Platform,
+    layers: Vec<TestLayer>,
+    labels: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+struct TestLayer {
+    media_type: String,
+    blob: Vec<u8>,
+    diff_id: String,
+    annotations: HashMap<String, String>,
+    urls: Vec<String>,
+}
+
+impl TestImage {
+    /// Constructs an image without layers for `platform`, in
+    /// "os/architecture[/variant]" format.
+    pub fn new(platform: &str) -> Result<Self> {
+        Ok(TestImage {
+            platform: Platform::from_str(platform)?,
+            layers: Vec::new(),
+            labels: HashMap::new(),
+        })
+    }
+
+    /// Add a layer holding the regular `files`, a list of path and data,
+    /// compressed with `compression`.
+    pub fn layer(mut self, files: &[(&str, &[u8])], compression: Compression) -> Result<Self> {
+        self.layers.push(tar_layer(files, compression)?);
+        Ok(self)
+    }
+
+    /// Add a non-distributable layer holding the regular `files`, fetched
+    /// from each of `url_bases` followed by the layer digest, like the
+    /// [`TestRegistry::blob_url_base`] of a registry. The layer blob is not
+    /// pushed with the image.
+    pub fn foreign_layer(
+        mut self,
+        files: &[(&str, &[u8])],
+        compression: Compression,
+        url_bases: &[String],
+    ) -> Result<Self> {
+        let mut layer = tar_layer(files, compression)?;
+        let digest = digest_bytes(&layer.blob, None)?;
+        layer.media_type = layer.media_type.replace(
+            "application/vnd.oci.image.layer.",
+            "application/vnd.oci.image.layer.nondistributable.",
+        );
+        layer.urls = url_bases
+            .iter()
+            .map(|base| format!("{}{}", base, digest))
+            .collect();
+
+        self.layers.push(layer);
+        Ok(self)
+    }
+
+    /// Add a layer blob as is, with its media type, the digest of the
+    /// uncompressed layer and the descriptor annotations. It is meant for
+    /// layers prepared out of the test, like encrypted layers with their
+    /// wrapped keys in the annotations.
+    pub fn raw_layer(
+        mut self,
+        media_type: &str,
+        blob: Vec<u8>,
+        diff_id: &str,
+        annotations: HashMap<String, String>,
+    ) -> Self {
+        self.layers.push(TestLayer {
+            media_type: media_type.to_string(),
+            blob,
+            diff_id: diff_id.to_string(),
+            annotations,
+            urls: Vec::new(),
+        });
+        self
+    }
+
+    /// Add the encrypted layer fixture, holding the "bin/busybox" file with
+    /// "busybox" in it. It is encrypted for the JWE recipient of the test
+    /// key pair, `test_data/private_key_for_tests.pem` decrypts it.
+    pub fn encrypted_layer(self) -> Result<Self> {
+        self.layer_fixture(ENCRYPTED_LAYER).map(|(image, _)| image)
+    }
+
+    /// Add the encrypted layer fixture of the attestation agent key provider,
+    /// holding the "bin/busybox" file with "busybox" in it. Its key is
+    /// wrapped with a key of the KBS, it returns the KBS resources for a
+    /// TestKbs to unwrap the key with.
+    pub fn kbs_encrypted_layer(self) -> Result<(Self, HashMap<String, Vec<u8>>)> {
+        self.layer_fixture(ENCRYPTED_LAYER_KBS)
+    }
+
+    // Add the layer fixture `name`, it returns the KBS resources of the
+    // fixture too, if any.
+    fn layer_fixture(self, name: &str) -> Result<(Self, HashMap<String, Vec<u8>>)> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct LayerFixture {
+            media_type: String,
+            diff_id: String,
+            annotations: HashMap<String, String>,
+            blob: String,
+            #[serde(default)]
+            kbs_resources: HashMap<String, String>,
+        }
+
+        let fixture: LayerFixture = serde_json::from_slice(&fs::read(test_data(name))?)?;
+        let blob = base64::decode(&fixture.blob)?;
+        let kbs_resources = fixture
+            .kbs_resources
+            .iter()
+            .map(|(name, resource)| Ok((name.clone(), base64::decode(resource)?)))
+            .collect::<Result<_>>()?;
+        let image = self.raw_layer(
+            &fixture.media_type,
+            blob,
+            &fixture.diff_id,
+            fixture.annotations,
+        );
+
+        Ok((image, kbs_resources))
+    }
+
+    /// Set the media type of the last layer, like the media types of
+    /// mislabeled layers.
+    pub fn media_type(mut self, media_type: &str) -> Self {
+        if let Some(layer) = self.layers.last_mut() {
+            layer.media_type = media_type.to_string();
+        }
+        self
+    }
+
+    /// Set the label `key` of the image configuration.
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// The image configuration.
+    pub fn config(&self) -> Vec<u8> {
+        let mut config = self.platform_json();
+        config["rootfs"] = json!({
+            "type": "layers",
+            "diff_ids": self.layers.iter().map(|l| l.diff_id.clone()).collect::<Vec<_>>(),
+        });
+        config["config"] = json!({ "Labels": self.labels });
+        config["history"] = json!([]);
+
+        config.to_string().into_bytes()
+    }
+
+    /// The image manifest.
+    pub fn manifest(&self) -> Vec<u8> {
+        let config = self.config();
+        let layers: Vec<Value> = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let mut descriptor = json!({
+                    "mediaType": layer.media_type,
+                    "digest": digest_bytes(&layer.blob, None).unwrap(),
+                    "size": layer.blob.len(),
+                });
+                if !layer.annotations.is_empty() {
+                    descriptor["annotations"] = json!(layer.annotations);
+                }
+                if !layer.urls.is_empty() {
+                    descriptor["urls"] = json!(layer.urls);
+                }
+                descriptor
+            })
+            .collect();
+
+        json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": manifest::IMAGE_CONFIG_MEDIA_TYPE,
+                "digest": digest_bytes(&config, None).unwrap(),
+                "size": config.len(),
+            },
+            "layers": layers,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    /// The simple signing signature of the image manifest as `reference`,
+    /// signed with the test GPG key by the `gpg` command. The signature
+    /// verifies with the `TEST_GPG_PUBKEY` key ring.
+    pub fn signature(&self, reference: &str) -> Result<Vec<u8>> {
+        let payload = json!({
+            "critical": {
+                "identity": { "docker-reference": reference },
+                "image": { "docker-manifest-digest": digest_bytes(&self.manifest(), None)? },
+                "type": "atomic container signature",
+            },
+            "optional": {},
+        });
+
+        let home = GpgHome::new()?;
+        home.gpg(
+            &["--import", &test_data(TEST_GPG_KEY).display().to_string()],
+            &[],
+        )?;
+        home.gpg(&["--sign", "--output", "-"], payload.to_string().as_bytes())
+    }
+
+    /// The digests of the image layer blobs.
+    pub fn layer_digests(&self) -> Vec<String> {
+        self.layers
+            .iter()
+            .map(|layer| digest_bytes(&layer.blob, None).unwrap())
+            .collect()
+    }
+
+    /// The diff IDs of the image layers.
+    pub fn diff_ids(&self) -> Vec<String> {
+        self.layers
+            .iter()
+            .map(|layer| layer.diff_id.clone())
+            .collect()
+    }
+
+    /// The image layer blobs.
+    pub fn layer_blobs(&self) -> Vec<&[u8]> {
+        self.layers
+            .iter()
+            .map(|layer| layer.blob.as_slice())
+            .collect()
+    }
+
+    /// Write the image to the image directory `path`, in the format of the
+    /// `dir:` transport.
+    pub fn write_dir(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)?;
+        fs::write(path.join("version"), "Directory Transport Version: 1.1\n")?;
+        fs::write(path.join("manifest.json"), self.manifest())?;
+        let config = self.config();
+        let blobs = self.layer_blobs().into_iter().chain([config.as_slice()]);
+        for blob in blobs {
+            let digest = digest_bytes(blob, None)?;
+            fs::write(path.join(split_digest(&digest)?.1), blob)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the image to the OCI image layout `path`, where the image is
+    /// tagged `tag`.
+    pub fn write_layout(&self, path: &Path, tag: &str) -> Result<()> {
+        let blobs_dir = path.join("blobs").join("sha256");
+        fs::create_dir_all(&blobs_dir)?;
+        let manifest = self.manifest();
+        let config = self.config();
+        let blobs = self
+            .layer_blobs()
+            .into_iter()
+            .chain([config.as_slice(), manifest.as_slice()]);
+        for blob in blobs {
+            let digest = digest_bytes(blob, None)?;
+            fs::write(blobs_dir.join(split_digest(&digest)?.1), blob)?;
+        }
+
+        let index = json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                "digest": digest_bytes(&manifest, None)?,
+                "size": manifest.len(),
+                "annotations": { "org.opencontainers.image.ref.name": tag },
+            }],
+        });
+        fs::write(path.join("index.json"), index.to_string())?;
+        fs::write(
+            path.join("oci-layout"),
+            r#"{"imageLayoutVersion": "1.0.0"}"#,
+        )?;
+
+        Ok(())
+    }
+
+    fn platform_json(&self) -> Value {
+        let mut platform = json!({
+            "os": self.platform.os,
+            "architecture": self.platform.architecture,
+        });
+        if let Some(variant) = &self.platform.variant {
+            platform["variant"] = json!(variant);
+        }
+        platform
+    }
+}
+
+// The path of the test data `name` in the source tree.
+fn test_data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+}
+
+// A GpgHome is a GnuPG home directory of its own, removed with its agent
+// when dropped.
+struct GpgHome {
+    path: PathBuf,
+}
+
+impl GpgHome {
+    fn new() -> Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "image-rs-gpg-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(GpgHome { path })
+    }
+
+    // Run gpg with `args` in batch mode, with `input` as its standard input,
+    // and return its standard output.
+    fn gpg(&self, args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+        let mut child = Command::new("gpg")
+            .arg("--homedir")
+            .arg(&self.path)
+            .args(["--batch", "--quiet"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "gpg {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl Drop for GpgHome {
+    fn drop(&mut self) {
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(&self.path)
+            .args(["--kill", "gpg-agent"])
+            .output();
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Build a layer holding the regular `files`, a list of path and data,
+// compressed with `compression`.
+fn tar_layer(files: &[(&str, &[u8])], compression: Compression) -> Result<TestLayer> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, data) in files.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *data)?;
+    }
+    let layer_tar = builder.into_inner()?;
+
+    let (media_type, blob) = match compression {
+        Compression::Uncompressed => (manifest::IMAGE_LAYER_MEDIA_TYPE, layer_tar.clone()),
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&layer_tar)?;
+            (manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE, encoder.finish()?)
+        }
+        Compression::Zstd => (
+            "application/vnd.oci.image.layer.v1.tar+zstd",
+            zstd::encode_all(&layer_tar[..], 0)?,
+        ),
+        Compression::Bzip2 => {
+            let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+            encoder.write_all(&layer_tar)?;
+            (
+                "application/vnd.oci.image.layer.v1.tar+bzip2",
+                encoder.finish()?,
+            )
+        }
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+            encoder.write_all(&layer_tar)?;
+            (
+                "application/vnd.oci.image.layer.v1.tar+xz",
+                encoder.finish()?,
+            )
+        }
+    };
+
+    Ok(TestLayer {
+        media_type: media_type.to_string(),
+        blob,
+        diff_id: digest_bytes(&layer_tar, None)?,
+        annotations: HashMap::new(),
+        urls: Vec::new(),
+    })
+}
+
+/// A response of the test registry.
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    // Send half of the body only, and close the connection.
+    truncated: bool,
+    // Send half of the body only, and keep the connection open.
+    stalled: bool,
+}
+
+impl Response {
+    fn new(status: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body,
+            truncated: false,
+            stalled: false,
+        }
+    }
+
+    fn error(status: &'static str, code: &str) -> Self {
+        let body = json!({ "errors": [{ "code": code, "message": status }] });
+        Response::new(status, body.to_string().into_bytes())
+            .header("Content-Type", "application/json")
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+// Serve one request of `stream`, the connection is closed after it.
+async fn serve(mut stream: TcpStream, addr: SocketAddr, state: Arc<Mutex<RegistryState>>) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(n) if n > 0 && head.len() < MAX_REQUEST_HEAD => head.extend_from_slice(&buf[..n]),
+            _ => return,
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let response = handle(&state, addr, &method, &target, &headers);
+    let mut out = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in response.headers.iter() {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+
+    let body = match (method.as_str(), response.truncated || response.stalled) {
+        ("HEAD", _) => &[][..],
+        (_, true) => &response.body[..response.body.len() / 2],
+        (_, false) => &response.body[..],
+    };
+    let _ = stream.write_all(out.as_bytes()).await;
+    let _ = stream.write_all(body).await;
+    if response.stalled {
+        std::future::pending::<()>().await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+fn handle(
+    state: &Mutex<RegistryState>,
+    addr: SocketAddr,
+    method: &str,
+    target: &str,
+    headers: &HashMap<String, String>,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    let (path, _query) = target.split_once('?').unwrap_or((target, ""));
+    state.requests.push(match headers.get("range") {
+        Some(range) => format!("{} {} {}", method, path, range),
+        None => format!("{} {}", method, path),
+    });
+
+    if method != "GET" && method != "HEAD" {
+        return Response::error("405 Method Not Allowed", "UNSUPPORTED");
+    }
+
+    if path == "/token" {
+        return token(&state, headers);
+    }
+
+    let rest = match path.strip_prefix("/v2/") {
+        Some(rest) => rest,
+        None => return Response::error("404 Not Found", "NAME_UNKNOWN"),
+    };
+
+    if let Some(i) = state
+        .faults
+        .iter()
+        .position(|f| *f == Fault::TooManyRequests)
+    {
+        state.faults.remove(i);
+        return Response::error("429 Too Many Requests", "TOOMANYREQUESTS")
+            .header("Retry-After", "0");
+    }
+
+    if let Some((_, password)) = &state.credentials {
+        let authorized = headers.get("authorization") == Some(&format!("Bearer {}", state.token));
+        if !authorized && !password.is_empty() {
+            let mut challenge = format!(
+                "Bearer realm=\"http://{}/token\",service=\"{}\"",
+                addr, TOKEN_SERVICE
+            );
+            if let Some((name, _)) = split_route(rest) {
+                challenge.push_str(&format!(",scope=\"repository:{}:pull\"", name));
+            }
+            return Response::error("401 Unauthorized", "UNAUTHORIZED")
+                .header("WWW-Authenticate", &challenge);
+        }
+    }
+
+    if rest.is_empty() {
+        return Response::new("200 OK", b"{}".to_vec());
+    }
+
+    match split_route(rest) {
+        Some((name, Route::Manifest(reference))) => {
+            match state
+                .manifests
+                .get(&(name.to_string(), reference.to_string()))
+            {
+                Some((media_type, data)) => Response::new("200 OK", data.clone())
+                    .header("Content-Type", media_type)
+                    .header("Docker-Content-Digest", &digest_bytes(data, None).unwrap()),
+                None => Response::error("404 Not Found", "MANIFEST_UNKNOWN"),
+            }
+        }
+        Some((_, Route::Blob(digest))) => {
+            let mut data = match state.blobs.get(digest) {
+                Some(data) => data.clone(),
+                None => return Response::error("404 Not Found", "BLOB_UNKNOWN"),
+            };
+
+            let mut truncated = false;
+            let mut stalled = false;
+            let mut redirect = None;
+            let faults = std::mem::take(&mut state.faults);
+            for fault in faults {
+                match fault {
+                    Fault::WrongDigest(d) if d == digest && !data.is_empty() => data[0] ^= 0xff,
+                    Fault::TruncatedBlob(d) if d == digest && !truncated => truncated = true,
+                    Fault::StalledBlob(d) if d == digest && !stalled => stalled = true,
+                    Fault::RedirectedBlob(d, location) if d == digest && redirect.is_none() => {
+                        redirect = Some(location)
+                    }
+                    fault => state.faults.push(fault),
+                }
+            }
+            if let Some(location) = redirect {
+                return Response::new("307 Temporary Redirect", Vec::new())
+                    .header("Location", &location);
+            }
+
+            // Only "bytes=<start>-" ranges are served, as the puller sends.
+            let start = headers
+                .get("range")
+                .and_then(|r| r.strip_prefix("bytes="))
+                .and_then(|r| r.strip_suffix('-'))
+                .and_then(|r| r.parse::<usize>().ok());
+            let mut response = match start {
+                Some(start) if start < data.len() => {
+                    let content_range =
+                        format!("bytes {}-{}/{}", start, data.len() - 1, data.len());
+                    Response::new("206 Partial Content", data[start..].to_vec())
+                        .header("Content-Range", &content_range)
+                }
+                Some(_) => return Response::error("416 Range Not Satisfiable", "BLOB_UNKNOWN"),
+                None => Response::new("200 OK", data),
+            };
+            response.truncated = truncated;
+            response.stalled = stalled;
+            response
+                .header("Content-Type", "application/octet-stream")
+                .header("Docker-Content-Digest", digest)
+        }
+        None => Response::error("404 Not Found", "NAME_UNKNOWN"),
+    }
+}
+
+enum Route<'a> {
+    Manifest(&'a str),
+    Blob(&'a str),
+}
+
+// Split a registry API path after "/v2/" into the repository name and the
+// manifest or blob it refers to.
+fn split_route(rest: &str) -> Option<(&str, Route<'_>)> {
+    if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        return Some((name, Route::Manifest(reference)));
+    }
+    rest.rsplit_once("/blobs/")
+        .map(|(name, digest)| (name, Route::Blob(digest)))
+}
+
+// Issue a token to the clients authenticated with the registry credentials.
+fn token(state: &RegistryState, headers: &HashMap<String, String>) -> Response {
+    let expected = state.credentials.as_ref().map(|(username, password)| {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        )
+    });
+
+    if expected.is_none() || headers.get("authorization") != expected.as_ref() {
+        return Response::error("401 Unauthorized", "UNAUTHORIZED");
+    }
+
+    let body = json!({ "token": state.token, "expires_in": 300 });
+    Response::new("200 OK", body.to_string().into_bytes())
+        .header("Content-Type", "application/json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Credential;
+    use crate::config::RetryConfig;
+    use crate::registry::RegistryClient;
+    use keyprovider::key_provider_service_client::KeyProviderServiceClient;
+    use oci_distribution::Reference;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn test_test_registry() {
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(&[("file.txt", b"data")], Compression::Gzip)
+            .unwrap();
+        let digest = registry.push_image("test/image", "v1", &image);
+        registry.set_auth("user", "password");
+
+        let reference = Reference::try_from(registry.reference("test/image", "v1")).unwrap();
+        let mut client = RegistryClient::new().unwrap();
+        client
+            .set_tls(&registry.host(), &registry.tls_config())
+            .unwrap();
+        assert!(client
+            .pull_manifest_raw(&reference, &[manifest::OCI_IMAGE_MEDIA_TYPE])
+            .await
+            .is_err());
+
+        client.set_credential(
+            &reference,
+            Credential::Basic("user".to_string(), "password".to_string()),
+        );
+        let (manifest, media_type) = client
+            .pull_manifest_raw(&reference, &[manifest::OCI_IMAGE_MEDIA_TYPE])
+            .await
+            .unwrap();
+        assert_eq!(digest_bytes(&manifest, None).unwrap(), digest);
+        assert_eq!(media_type, manifest::OCI_IMAGE_MEDIA_TYPE);
+
+        // The credential is not presented for the other repositories.
+        registry.push_image("test/other", "v1", &image);
+        let other = Reference::try_from(registry.reference("test/other", "v1")).unwrap();
+        assert!(client
+            .pull_manifest_raw(&other, &[manifest::OCI_IMAGE_MEDIA_TYPE])
+            .await
+            .is_err());
+
+        // Blobs are served from an offset with Range requests.
+        let layer_digest = &image.layer_digests()[0];
+        let mut blob = Vec::new();
+        client
+            .pull_blob(&reference, layer_digest, 4, &mut blob)
+            .await
+            .unwrap();
+        assert_eq!(blob, image.layers[0].blob[4..]);
+        assert!(registry
+            .requests()
+            .iter()
+            .any(|r| r.ends_with(&format!("/blobs/{} bytes=4-", layer_digest))));
+
+        // An altered blob is served once.
+        registry.inject(Fault::WrongDigest(layer_digest.clone()));
+        let mut blob = Vec::new();
+        client
+            .pull_blob(&reference, layer_digest, 0, &mut blob)
+            .await
+            .unwrap();
+        assert_ne!(blob, image.layers[0].blob);
+        let mut blob = Vec::new();
+        client
+            .pull_blob(&reference, layer_digest, 0, &mut blob)
+            .await
+            .unwrap();
+        assert_eq!(blob, image.layers[0].blob);
+
+        let sigstore = tempfile::tempdir().unwrap();
+        write_signature(sigstore.path(), "test/image", &digest, b"signature").unwrap();
+        let (algorithm, encoded) = split_digest(&digest).unwrap();
+        assert_eq!(
+            fs::read(
+                sigstore
+                    .path()
+                    .join(format!("test/image@{}={}", algorithm, encoded))
+                    .join("signature-1")
+            )
+            .unwrap(),
+            b"signature"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stopped_consumer() {
+        let registry = TestRegistry::start().await.unwrap();
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .layer(
+                &[("file.txt", &[7u8; 256 * 1024][..])],
+                Compression::Uncompressed,
+            )
+            .unwrap();
+        registry.push_image("test/image", "v1", &image);
+        let layer_digest = &image.layer_digests()[0];
+
+        let reference = Reference::try_from(registry.reference("test/image", "v1")).unwrap();
+        let mut client = RegistryClient::new().unwrap();
+        client
+            .set_tls(&registry.host(), &registry.tls_config())
+            .unwrap();
+        client.set_retry(RetryConfig {
+            max_attempts: 5,
+            initial_backoff_ms: 0,
+            max_backoff_ms: 0,
+        });
+
+        // The consumer stops reading part way, the blob is not pulled again.
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let (res, _) = tokio::join!(
+            client.pull_blob(&reference, layer_digest, 0, writer),
+            async move {
+                let mut buf = [0u8; 1024];
+                reader.read_exact(&mut buf).await.unwrap();
+            }
+        );
+        assert!(res.is_err());
+        let blob_requests = registry
+            .requests()
+            .iter()
+            .filter(|r| r.contains(layer_digest.as_str()))
+            .count();
+        assert_eq!(blob_requests, 1);
+    }
+
+    #[test]
+    fn test_test_image_fixtures() {
+        let image = TestImage::new("linux/amd64")
+            .unwrap()
+            .encrypted_layer()
+            .unwrap();
+        let layer = &image.layers[0];
+        assert!(layer.media_type.ends_with("+encrypted"));
+        assert!(layer
+            .annotations
+            .contains_key("org.opencontainers.image.enc.keys.jwe"));
+        assert!(layer
+            .annotations
+            .contains_key("org.opencontainers.image.enc.pubopts"));
+
+        assert!(!image
+            .signature("localhost/test/image:v1")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_test_kbs_keyprovider() {
+        let (image, resources) = TestImage::new("linux/amd64")
+            .unwrap()
+            .kbs_encrypted_layer()
+            .unwrap();
+        let annotation = &image.layers[0].annotations
+            ["org.opencontainers.image.enc.keys.provider.attestation-agent"];
+        let input = json!({
+            "op": "keyunwrap",
+            "keyunwrapparams": {
+                "dc": { "Parameters": { "attestation-agent": [base64::encode("null_kbc::null")] } },
+                "annotation": annotation,
+            },
+        });
+        let unwrap_key = || async {
+            let mut client =
+                KeyProviderServiceClient::connect(format!("http://{}", AA_KEYPROVIDER_ADDR))
+                    .await
+                    .unwrap();
+            client
+                .un_wrap_key(KeyProviderKeyWrapProtocolInput {
+                    key_provider_key_wrap_protocol_input: input.to_string().into_bytes(),
+                })
+                .await
+        };
+
+        // The layer key is unwrapped into the private options of the layer
+        // cipher, like the attestation agent does.
+        let kbs = TestKbs::start(resources.clone()).await.unwrap();
+        let output = unwrap_key().await.unwrap().into_inner();
+        let output: Value =
+            serde_json::from_slice(&output.key_provider_key_wrap_protocol_output).unwrap();
+        let opts_data: Vec<u8> =
+            serde_json::from_value(output["keyunwrapresults"]["optsdata"].clone()).unwrap();
+        let opts: Value = serde_json::from_slice(&opts_data).unwrap();
+        let symkey = base64::decode(opts["symkey"].as_str().unwrap()).unwrap();
+        assert_eq!(symkey.len(), 32);
+
+        let kid = resources.keys().next().unwrap();
+        assert_eq!(
+            kbs.requests(),
+            vec![("null_kbc".to_string(), "null".to_string(), kid.clone())]
+        );
+
+        // Keys the KBS does not hold are not unwrapped.
+        drop(kbs);
+        let _kbs = TestKbs::start(HashMap::new()).await.unwrap();
+        assert!(unwrap_key().await.is_err());
+    }
+}
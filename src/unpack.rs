@@ -11,9 +11,14 @@ use std::io;
 use std::path::Path;
 use tar::Archive;
 
-/// Unpack the contents of tarball to the destination path
-pub fn unpack(input: Vec<u8>, destination: &Path) -> Result<()> {
-    let mut archive = Archive::new(input.as_slice());
+use crate::config::LimitsConfig;
+use crate::errors::{Limit, PullError};
+
+/// Unpack the contents of tarball to the destination path.
+/// Entries are extracted as they are read from the input stream.
+/// It fails once the number of entries or an entry path exceeds `limits`.
+pub fn unpack<R: io::Read>(input: R, destination: &Path, limits: &LimitsConfig) -> Result<()> {
+    let mut archive = Archive::new(input);
 
     if destination.exists() {
         return Err(anyhow!(
@@ -25,8 +30,17 @@ pub fn unpack(input: Vec<u8>, destination: &Path) -> Result<()> {
     fs::create_dir_all(destination)?;
 
     let mut dirs: HashMap<CString, [timeval; 2]> = HashMap::default();
-    for file in archive.entries()? {
+    for (i, file) in archive.entries()?.enumerate() {
+        if i as u64 >= limits.max_layer_entries {
+            return Err(PullError::LimitExceeded {
+                limit: Limit::LayerEntries,
+                max: limits.max_layer_entries,
+            }
+            .into());
+        }
+
         let mut file = file?;
+        check_path_limits(&file, limits)?;
         file.unpack_in(destination)?;
 
         // tar-rs crate only preserve timestamps of files,
@@ -77,6 +91,29 @@ pub fn unpack(input: Vec<u8>, destination: &Path) -> Result<()> {
     Ok(())
 }
 
+// Check the path and link target of `file` against the path limits.
+fn check_path_limits<R: io::Read>(file: &tar::Entry<R>, limits: &LimitsConfig) -> Result<()> {
+    let path = file.path()?;
+    if path.components().count() > limits.max_path_depth {
+        return Err(anyhow::Error::new(PullError::LimitExceeded {
+            limit: Limit::PathDepth,
+            max: limits.max_path_depth as u64,
+        })
+        .context(format!("layer entry {}", path.display())));
+    }
+
+    let link_len = file.link_name_bytes().map_or(0, |link| link.len());
+    if path.as_os_str().len().max(link_len) > limits.max_path_length {
+        return Err(anyhow::Error::new(PullError::LimitExceeded {
+            limit: Limit::PathLength,
+            max: limits.max_path_length as u64,
+        })
+        .context(format!("layer entry {}", path.display())));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,7 +153,8 @@ mod tests {
             fs::remove_dir_all(destination).unwrap();
         }
 
-        assert!(unpack(data.clone(), destination).is_ok());
+        let limits = LimitsConfig::default();
+        assert!(unpack(data.as_slice(), destination, &limits).is_ok());
 
         let path = destination.join("file.txt");
         let metadata = fs::metadata(&path).unwrap();
@@ -129,6 +167,66 @@ mod tests {
         assert_eq!(mtime, new_mtime);
 
         // destination already exists
-        assert!(unpack(data, destination).is_err());
+        assert!(unpack(data.as_slice(), destination, &limits).is_err());
+    }
+
+    #[test]
+    fn test_unpack_limits() {
+        let mut ar = tar::Builder::new(Vec::new());
+        for path in ["a/b/c/file1", "a/b/c/file2", "a/b/c/file3"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            ar.append_data(&mut header, path, &b"data"[..]).unwrap();
+        }
+        let data = ar.into_inner().unwrap();
+
+        let tests = [
+            (
+                LimitsConfig {
+                    max_layer_entries: 2,
+                    ..Default::default()
+                },
+                Limit::LayerEntries,
+            ),
+            (
+                LimitsConfig {
+                    max_path_depth: 3,
+                    ..Default::default()
+                },
+                Limit::PathDepth,
+            ),
+            (
+                LimitsConfig {
+                    max_path_length: 10,
+                    ..Default::default()
+                },
+                Limit::PathLength,
+            ),
+        ];
+
+        for (limits, limit) in tests.iter() {
+            let tempdir = tempfile::tempdir().unwrap();
+            let destination = tempdir.path().join("layer");
+            let err = unpack(data.as_slice(), &destination, limits).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<PullError>(),
+                    Some(PullError::LimitExceeded { limit: l, .. }) if l == limit
+                ),
+                "{:#}",
+                err
+            );
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let limits = LimitsConfig {
+            max_layer_entries: 3,
+            max_path_depth: 4,
+            max_path_length: 11,
+            ..Default::default()
+        };
+        unpack(data.as_slice(), &tempdir.path().join("layer"), &limits).unwrap();
     }
 }
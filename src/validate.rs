@@ -6,7 +6,7 @@
 use anyhow::{anyhow, Result};
 use oci_distribution::Reference;
 use serde::{Deserialize, Serialize};
-use signature::{Image, Policy, SignatureScheme};
+use signature::{Image, Policy, SignatureScheme, TransportName};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs;
@@ -16,7 +16,7 @@ use std::str::FromStr;
 use get_resource::get_resource_service_client::GetResourceServiceClient;
 use get_resource::GetResourceRequest;
 
-const AA_GETRESOURCE_ADDR: &str = "http://127.0.0.1:50001";
+pub(crate) const AA_GETRESOURCE_ADDR: &str = "http://127.0.0.1:50001";
 
 // Image security config dir contains important information such as
 // security policy configuration file and signature verification configuration file.
@@ -24,10 +24,10 @@ const AA_GETRESOURCE_ADDR: &str = "http://127.0.0.1:50001";
 //
 // The reason for using the `/run` directory here is that in general HW-TEE,
 // the `/run` directory is mounted in `tmpfs`, which is located in the encrypted memory protected by HW-TEE.
-const IMAGE_SECURITY_CONFIG_DIR: &str = "/run/image-security";
-const POLICY_FILE_PATH: &str = "/run/image-security/security_policy.json";
+pub(crate) const IMAGE_SECURITY_CONFIG_DIR: &str = "/run/image-security";
+pub(crate) const POLICY_FILE_PATH: &str = "/run/image-security/security_policy.json";
 
-mod get_resource {
+pub(crate) mod get_resource {
     #![allow(unknown_lints)]
     #![allow(clippy::derive_partial_eq_without_eq)]
     tonic::include_proto!("getresource");
@@ -65,8 +65,19 @@ impl ResourceDescription {
 /// According to the configuration of the policy file, if the signature
 /// of the container image needs to be verified, the specified signature
 /// scheme is used for signature verification.
+///
+/// The `image_digest` must be computed locally from the pulled manifest data,
+/// rather than be the digest reported by the registry.
+///
+/// The policy requirements are looked up for the `transport` the image is
+/// read with, in the scope of its docker `image_reference` for registry
+/// images, and of their path and image suffix `local_scope` for local ones.
+/// Local images may have no docker reference, in which case only requirements
+/// that need no signature to be matched against it can accept them.
 pub async fn security_validate(
-    image_reference: &str,
+    transport: TransportName,
+    image_reference: Option<&str>,
+    local_scope: Option<(String, String)>,
     image_digest: &str,
     aa_kbc_params: &str,
 ) -> Result<()> {
@@ -83,8 +94,14 @@ pub async fn security_validate(
 
     let policy = Policy::from_file(POLICY_FILE_PATH)?;
 
-    let reference = Reference::try_from(image_reference)?;
-    let mut image = Image::default_with_reference(reference);
+    let mut image = match image_reference {
+        Some(reference) => Image::default_with_reference(Reference::try_from(reference)?),
+        None => Image::default_with_transport(transport),
+    };
+    image.set_transport(transport);
+    if let Some((path, suffix)) = local_scope {
+        image.set_local_scope(&path, &suffix);
+    }
     image.set_manifest_digest(image_digest)?;
 
     // Read the set of signature schemes that need to be verified
@@ -159,7 +176,11 @@ async fn update_scheme_resources(scheme: &str, aa_kbc_params: &str) -> Result<()
     }
 }
 
-async fn get_resource_from_kbs(resource_name: &str, aa_kbc_params: &str) -> Result<Vec<u8>> {
+/// Get a resource from the KBS through the attestation agent.
+pub(crate) async fn get_resource_from_kbs(
+    resource_name: &str,
+    aa_kbc_params: &str,
+) -> Result<Vec<u8>> {
     if let Some((kbc_name, kbs_uri)) = aa_kbc_params.split_once("::") {
         if kbc_name.is_empty() {
             return Err(anyhow!("aa_kbc_params: missing KBC name"));
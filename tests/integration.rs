@@ -4,50 +4,62 @@
 
 #[cfg(test)]
 mod tests {
-    use anyhow::Result;
+    use image_rs::bundle::BUNDLE_ROOTFS;
     use image_rs::image::ImageClient;
-    use std::path::Path;
-    use std::process::{Child, Command};
-
-    fn start_attestation_agent() -> Result<Child> {
-        let script_dir = format!("{}/{}", std::env!("CARGO_MANIFEST_DIR"), "scripts");
-        let aa_path = format!("{}/{}", script_dir, "attestation-agent");
-
-        if !Path::new(&aa_path).exists() {
-            let script_path = format!("{}/{}", script_dir, "build_attestation_agent.sh");
-            Command::new(script_path)
-                .output()
-                .expect("Failed to build attestation-agent");
-        }
-
-        Ok(Command::new(aa_path)
-            .args(&["--keyprovider_sock"])
-            .args(&["127.0.0.1:48888"])
-            .spawn()?)
-    }
+    use image_rs::test_registry::{TestImage, TestKbs, TestRegistry, OCICRYPT_KEYPROVIDER_CONFIG};
+    use std::process::Command;
 
     #[tokio::test]
     async fn test_image_rs() {
-        let mut aa = start_attestation_agent().expect("Failed to start attestation agent!");
+        let registry = TestRegistry::start().await.unwrap();
+        let (image, kbs_resources) = TestImage::new("linux/amd64")
+            .unwrap()
+            .kbs_encrypted_layer()
+            .unwrap();
+        registry.push_image("busybox_kbs_encrypted", "latest", &image);
 
+        // The layer key is unwrapped by the attestation agent key provider,
+        // with the key the KBS holds for it.
+        let kbs = TestKbs::start(kbs_resources.clone()).await.unwrap();
         let manifest_dir = std::env!("CARGO_MANIFEST_DIR");
-        let image = "docker.io/arronwang/busybox_kbs_encrypted";
-
-        let keyprovider_config =
-            format!("{}/{}", manifest_dir, "test_data/ocicrypt_keyprovider.conf");
+        let keyprovider_config = format!("{}/{}", manifest_dir, OCICRYPT_KEYPROVIDER_CONFIG);
         std::env::set_var("OCICRYPT_KEYPROVIDER_CONFIG", keyprovider_config);
-        let decrypt_config = "provider:attestation-agent:sample_kbc::null";
+        let decrypt_config = "provider:attestation-agent:null_kbc::null";
 
         let work_dir = tempfile::tempdir().unwrap();
-        std::env::set_var("CC_IMAGE_WORK_DIR", &work_dir.path());
+        std::env::set_var("CC_IMAGE_WORK_DIR", work_dir.path());
         let bundle_dir = tempfile::tempdir().unwrap();
 
         let mut image_client = ImageClient::default();
-        assert!(image_client
-            .pull_image(image, bundle_dir.path(), &None, &Some(decrypt_config))
+        image_client
+            .config
+            .registry_tls
+            .insert(registry.host(), registry.tls_config());
+        image_client
+            .pull_image(
+                &registry.reference("busybox_kbs_encrypted", "latest"),
+                bundle_dir.path(),
+                &None,
+                &Some(decrypt_config),
+            )
             .await
-            .is_ok());
+            .unwrap();
+
+        let rootfs = bundle_dir.path().join(BUNDLE_ROOTFS);
+        assert_eq!(
+            std::fs::read(rootfs.join("bin/busybox")).unwrap(),
+            b"busybox"
+        );
+        let _ = Command::new("umount").arg(&rootfs).output();
 
-        aa.kill().expect("Failed to stop attestation agent!");
+        let key_ids: Vec<&String> = kbs_resources.keys().collect();
+        assert_eq!(
+            kbs.requests(),
+            vec![(
+                "null_kbc".to_string(),
+                "null".to_string(),
+                key_ids[0].clone()
+            )]
+        );
     }
 }